@@ -0,0 +1,264 @@
+// Feeds a buddy any sequence of messages, ticks, proposals and restarts, and
+// checks after every step that nothing panicked and that what it enforces on
+// its own still holds, however nonsensical what it's been sent.
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+
+use raft::raft_buddy::{RaftBuddy, Role};
+use raft::raft_compression::Compression;
+use raft::raft_id::{ClusterId, RaftId};
+use raft::raft_log::LogEntry;
+use raft::raft_message::{
+    AppendEntriesBody, AppendEntriesResponseBody, Envelope, InstallSnapshotBody,
+    InstallSnapshotResponseBody, RaftMessage, RequestVoteBody,
+};
+use raft::raft_storage::MemoryStorage;
+use raft::raft_temporal::Temporal;
+use raft::raft_topology::Topology;
+
+/// Buddies `0..BUDDIES` are in the cluster. Ids are picked from one more
+/// than that, so some messages come from or go to a stranger.
+const BUDDIES: usize = 3;
+
+/// Terms and indexes are kept small so messages land near the buddy's own
+/// log and term rather than somewhere it'll never get to.
+#[derive(Arbitrary, Debug)]
+enum Message {
+    RequestVote {
+        last_log_index: u8,
+        last_log_term: u8,
+    },
+    VoteForCandidate,
+    RejectCandidateVote,
+    AppendEntries {
+        prev_log_index: u8,
+        prev_log_term: u8,
+        entries: Vec<Entry>,
+        leader_commit: u8,
+    },
+    AppendEntriesResponse {
+        success: bool,
+        last_log_index: u8,
+    },
+    InstallSnapshot {
+        last_included_index: u8,
+        last_included_term: u8,
+        offset: u8,
+        data: Vec<u8>,
+        deflated: bool,
+        done: bool,
+    },
+    InstallSnapshotResponse {
+        last_included_index: u8,
+        next_offset: u8,
+        done: bool,
+        deflated: bool,
+    },
+}
+
+#[derive(Arbitrary, Debug)]
+struct Entry {
+    term: u8,
+    /// Follows on from the entry before it unless given.
+    index: Option<u8>,
+    contents: String,
+}
+
+#[derive(Arbitrary, Debug)]
+enum Step {
+    Deliver {
+        from: u8,
+        to: u8,
+        term: u8,
+        other_cluster: bool,
+        message: Message,
+    },
+    Tick(u8),
+    Propose(String),
+    Snapshot,
+    Restart,
+}
+
+impl Message {
+    fn into_raft(self) -> RaftMessage {
+        match self {
+            Message::RequestVote {
+                last_log_index,
+                last_log_term,
+            } => RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: last_log_index.into(),
+                last_log_term: last_log_term.into(),
+            }),
+            Message::VoteForCandidate => RaftMessage::VoteForCandidate,
+            Message::RejectCandidateVote => RaftMessage::RejectCandidateVote,
+            Message::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                let mut next = usize::from(prev_log_index) + 1;
+                let entries = entries
+                    .into_iter()
+                    .map(|entry| {
+                        let index = entry.index.map_or(next, usize::from);
+                        next = index + 1;
+
+                        LogEntry {
+                            term: entry.term.into(),
+                            index,
+                            contents: entry.contents,
+                        }
+                    })
+                    .collect();
+
+                RaftMessage::AppendEntries(AppendEntriesBody {
+                    prev_log_index: prev_log_index.into(),
+                    prev_log_term: prev_log_term.into(),
+                    entries,
+                    leader_commit: leader_commit.into(),
+                })
+            }
+            Message::AppendEntriesResponse {
+                success,
+                last_log_index,
+            } => RaftMessage::AppendEntriesResponse(AppendEntriesResponseBody {
+                success,
+                last_log_index: last_log_index.into(),
+            }),
+            Message::InstallSnapshot {
+                last_included_index,
+                last_included_term,
+                offset,
+                data,
+                deflated,
+                done,
+            } => RaftMessage::InstallSnapshot(InstallSnapshotBody {
+                last_included_index: last_included_index.into(),
+                last_included_term: last_included_term.into(),
+                offset: offset.into(),
+                data,
+                compression: if deflated {
+                    Compression::Deflate
+                } else {
+                    Compression::None
+                },
+                done,
+            }),
+            Message::InstallSnapshotResponse {
+                last_included_index,
+                next_offset,
+                done,
+                deflated,
+            } => RaftMessage::InstallSnapshotResponse(InstallSnapshotResponseBody {
+                last_included_index: last_included_index.into(),
+                next_offset: next_offset.into(),
+                done,
+                compression: if deflated {
+                    Compression::Deflate
+                } else {
+                    Compression::None
+                },
+            }),
+        }
+    }
+}
+
+/// What has to hold from one step to the next, whatever the buddy was sent.
+#[derive(Clone, Copy, Debug)]
+struct Seen {
+    term: usize,
+    voted_for: Option<RaftId>,
+    commit_index: usize,
+}
+
+fn check(buddy: &RaftBuddy, before: Seen) -> Seen {
+    assert!(buddy.current_term >= before.term, "term went backwards");
+    if buddy.current_term == before.term && before.voted_for.is_some() {
+        assert_eq!(buddy.voted_for, before.voted_for, "voted twice in a term");
+    }
+    if buddy.role != Role::Follower {
+        assert_eq!(buddy.voted_for, Some(buddy.id), "didn't vote for itself");
+    }
+    assert!(
+        buddy.commit_index >= before.commit_index,
+        "commit index went backwards"
+    );
+    assert!(
+        buddy.last_applied <= buddy.commit_index,
+        "applied past the commit index"
+    );
+    assert!(buddy.log.is_well_formed(), "log isn't well formed");
+
+    Seen {
+        term: buddy.current_term,
+        voted_for: buddy.voted_for,
+        commit_index: buddy.commit_index,
+    }
+}
+
+fuzz_target!(|steps: Vec<Step>| {
+    let topology = Topology::in_memory(BUDDIES);
+    let storage = MemoryStorage::default();
+    let mut buddy = RaftBuddy {
+        id: RaftId(0),
+        topology: topology.clone(),
+        storage: Box::new(storage.clone()),
+        snapshot_chunk_size: 8,
+        ..Default::default()
+    };
+    let mut seen = check(
+        &buddy,
+        Seen {
+            term: 0,
+            voted_for: None,
+            commit_index: 0,
+        },
+    );
+
+    for step in steps {
+        match step {
+            Step::Deliver {
+                from,
+                to,
+                term,
+                other_cluster,
+                message,
+            } => {
+                let envelope = Envelope {
+                    from: RaftId(usize::from(from) % (BUDDIES + 1)),
+                    to: RaftId(usize::from(to) % (BUDDIES + 1)),
+                    term: term.into(),
+                    cluster_id: ClusterId(other_cluster.into()),
+                    message: message.into_raft(),
+                };
+
+                buddy.channel().lock().unwrap().push(envelope);
+                buddy.tick();
+            }
+            Step::Tick(ticks) => {
+                for _ in 0..ticks {
+                    buddy.tick();
+                }
+            }
+            Step::Propose(contents) => {
+                buddy.propose(contents);
+            }
+            Step::Snapshot => buddy.take_snapshot(),
+            Step::Restart => {
+                buddy = RaftBuddy::restore(RaftId(0), topology.clone(), Box::new(storage.clone()))
+                    .expect("memory storage always loads");
+                seen.commit_index = buddy.commit_index;
+            }
+        }
+
+        seen = check(&buddy, seen);
+
+        // Nobody's listening, so don't let what the buddy sends pile up.
+        for channel in topology.values() {
+            channel.lock().unwrap().clear();
+        }
+    }
+});
@@ -0,0 +1,34 @@
+// Reads arbitrary bytes as a frame and as an envelope in every codec. None of
+// it may panic, and anything that does decode has to survive a round trip.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use raft::raft_auth::{ClusterKey, ClusterKeys};
+use raft::raft_codec::{BinaryCodec, Codec, JsonCodec};
+use raft::raft_frame::{read_envelope, write_envelope};
+
+fuzz_target!(|bytes: &[u8]| {
+    let codecs: [&dyn Codec; 2] = [&BinaryCodec, &JsonCodec];
+
+    for codec in codecs {
+        if let Ok(envelope) = codec.decode_envelope(bytes) {
+            let encoded = codec.encode_envelope(&envelope);
+            assert_eq!(codec.decode_envelope(&encoded).unwrap(), envelope);
+        }
+    }
+
+    let keys = ClusterKeys::new(ClusterKey::new("fuzz"));
+
+    for keys in [None, Some(&keys)] {
+        let mut stream = bytes;
+
+        while let Ok(Some(envelope)) = read_envelope(&mut stream, keys) {
+            let mut written = vec![];
+            write_envelope(&mut written, &BinaryCodec, keys, &envelope).unwrap();
+
+            let read = read_envelope(&mut written.as_slice(), keys).unwrap();
+            assert_eq!(read, Some(envelope));
+        }
+    }
+});
@@ -0,0 +1,22 @@
+// Reads arbitrary bytes as a persisted log in every codec. None of it may
+// panic, and any log that does decode has to be well formed and survive a
+// round trip.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use raft::raft_codec::{BinaryCodec, Codec, JsonCodec};
+
+fuzz_target!(|bytes: &[u8]| {
+    let codecs: [&dyn Codec; 2] = [&BinaryCodec, &JsonCodec];
+
+    for codec in codecs {
+        if let Ok(log) = codec.decode_log(bytes) {
+            assert!(log.is_well_formed());
+
+            let decoded = codec.decode_log(&codec.encode_log(&log)).unwrap();
+            assert_eq!(decoded.base(), log.base());
+            assert!(decoded.iter().eq(log.iter()));
+        }
+    }
+});
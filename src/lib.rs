@@ -1,71 +1,86 @@
 #![allow(unused)]
-use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::{collections::BTreeMap, rc::Rc};
-
-mod raft_log;
-mod raft_buddy;
-mod raft_channel;
-mod raft_id;
-mod raft_message;
-mod raft_temporal;
-mod raft_topology;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "tokio")]
+pub mod raft_async;
+pub mod raft_auth;
+pub mod raft_buddy;
+pub mod raft_channel;
+mod raft_checksum;
+pub mod raft_cluster;
+pub mod raft_codec;
+pub mod raft_compression;
+pub mod raft_connection;
+mod raft_encoding;
+pub mod raft_frame;
+pub mod raft_id;
+pub mod raft_invariants;
+pub mod raft_linearizability;
+pub mod raft_log;
+pub mod raft_message;
+pub mod raft_model_checker;
+pub mod raft_network;
+mod raft_rng;
+#[cfg(test)]
+mod raft_scratch;
+mod raft_snapshot;
+mod raft_snapshot_store;
+mod raft_state_machine;
+pub mod raft_storage;
+pub mod raft_tcp;
+pub mod raft_temporal;
+mod raft_threaded;
+pub mod raft_topology;
+pub mod raft_trace;
 mod raft_type_aliases;
+#[cfg(unix)]
+pub mod raft_unix;
 
-use raft_log::RaftLog;
 use raft_buddy::{RaftBuddy, Role};
-use raft_channel::{Channel, RaftChannel};
-use raft_id::RaftId;
-use raft_message::{RaftMessage, RaftMessageBody};
+use raft_channel::{Channel, OverflowPolicy, RaftChannel};
+use raft_compression::Compression;
+use raft_id::{ClusterId, RaftId};
+use raft_log::{LogEntry, RaftLog, SnapshotBase};
+use raft_message::{
+    AppendEntriesBody, AppendEntriesResponseBody, Envelope, InstallSnapshotBody,
+    InstallSnapshotResponseBody, RaftMessage, RequestVoteBody,
+};
+use raft_snapshot::{Snapshot, SnapshotPolicy};
+use raft_state_machine::{KeyValueStore, StateMachine};
+use raft_storage::{DiskStorage, MemoryStorage};
 use raft_temporal::RaftTimer;
 use raft_temporal::Temporal;
 use raft_topology::Topology;
-use raft_type_aliases::RcMutChannel;
+use raft_type_aliases::ArcMutChannel;
 
 #[cfg(test)]
 mod tests {
     use super::*;
-
-    #[derive(PartialEq, Eq, Clone, Default)]
-    struct TestRaftChannel {
-        queue: Vec<RaftMessage>,
-    }
-
-    impl Channel for TestRaftChannel {
-        fn push(&mut self, message: RaftMessage) {
-            self.queue.push(message);
-        }
-
-        fn pop(&mut self) -> Option<RaftMessage> {
-            self.queue.pop()
-        }
-
-        fn all_messages(&mut self) -> Vec<RaftMessage> {
-            self.queue.clone()
-        }
-    }
+    use crate::raft_scratch::ScratchDir;
 
     fn default_topology() -> Topology {
         Topology::from_iter([
             (
                 RaftId(0),
-                Rc::new(RefCell::new(TestRaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             ),
             (
                 RaftId(1),
-                Rc::new(RefCell::new(TestRaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             ),
             (
                 RaftId(2),
-                Rc::new(RefCell::new(TestRaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             ),
             (
                 RaftId(3),
-                Rc::new(RefCell::new(TestRaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             ),
             (
                 RaftId(4),
-                Rc::new(RefCell::new(TestRaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             ),
         ])
     }
@@ -77,6 +92,19 @@ mod tests {
         assert!(buddies.len() == 5)
     }
 
+    #[test]
+    fn test_default_buddy_ticks() {
+        let mut buddy = RaftBuddy::default();
+
+        for _tick in 0..buddy.timer.default_timeout {
+            buddy.tick();
+        }
+
+        // On its own it's the whole cluster, so its own vote is a majority.
+        assert_eq!(buddy.role, Role::Leader);
+        assert_eq!(buddy.current_term, 1);
+    }
+
     #[test]
     fn test_buddies_run_out_of_patience() {
         let mut buddy = RaftBuddy {
@@ -121,6 +149,7 @@ mod tests {
         let buddy = buddies.first().unwrap();
 
         assert_eq!(buddy.role, Role::Candidate);
+        assert_eq!(buddy.current_term, 1);
         assert!(buddies[1..].iter().all(|b| b.role == Role::Follower));
     }
 
@@ -134,35 +163,30 @@ mod tests {
             candidate.tick()
         }
 
-        let messages: Vec<Option<RaftMessage>> = topology
+        let messages: Vec<Option<Envelope>> = topology
             .values()
-            .map(|channel| channel.borrow_mut().pop())
+            .map(|channel| channel.lock().unwrap().pop())
             .collect();
 
+        let request_vote = |to: usize| Envelope {
+            from: RaftId(0),
+            to: RaftId(to),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: 0,
+                last_log_term: 0,
+            }),
+        };
+
         assert_eq!(
             messages,
             [
                 None,
-                Some(RaftMessage::RequestVote(RaftMessageBody {
-                    id: RaftId(0),
-                    current_term: 0,
-                    log_length: 1
-                })),
-                Some(RaftMessage::RequestVote(RaftMessageBody {
-                    id: RaftId(0),
-                    current_term: 0,
-                    log_length: 1
-                })),
-                Some(RaftMessage::RequestVote(RaftMessageBody {
-                    id: RaftId(0),
-                    current_term: 0,
-                    log_length: 1
-                })),
-                Some(RaftMessage::RequestVote(RaftMessageBody {
-                    id: RaftId(0),
-                    current_term: 0,
-                    log_length: 1
-                })),
+                Some(request_vote(1)),
+                Some(request_vote(2)),
+                Some(request_vote(3)),
+                Some(request_vote(4)),
             ]
         )
     }
@@ -174,54 +198,46 @@ mod tests {
         let (candidate, followers) = buddies.split_first_mut().unwrap();
 
         for follower in followers.iter_mut() {
-            follower
-                .channel()
-                .borrow_mut()
-                .push(RaftMessage::RequestVote(RaftMessageBody {
-                    id: RaftId(0),
-                    current_term: 0,
-                    log_length: 1,
-                }));
+            follower.channel().lock().unwrap().push(Envelope {
+                from: RaftId(0),
+                to: follower.id,
+                term: 1,
+                cluster_id: ClusterId::default(),
+                message: RaftMessage::RequestVote(RequestVoteBody {
+                    last_log_index: 0,
+                    last_log_term: 0,
+                }),
+            });
 
             follower.tick()
         }
 
         let messages: Vec<_> = topology
             .values()
-            .map(|channel| channel.borrow_mut().all_messages())
+            .map(|channel| channel.lock().unwrap().all_messages())
             .collect();
 
+        let vote = |from: usize| Envelope {
+            from: RaftId(from),
+            to: RaftId(0),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        };
+
         assert_eq!(
             messages,
             [
-                vec![
-                    RaftMessage::VoteForCandidate(RaftMessageBody {
-                        id: 1.into(),
-                        current_term: 0,
-                        log_length: 1
-                    }),
-                    RaftMessage::VoteForCandidate(RaftMessageBody {
-                        id: 2.into(),
-                        current_term: 0,
-                        log_length: 1
-                    }),
-                    RaftMessage::VoteForCandidate(RaftMessageBody {
-                        id: 3.into(),
-                        current_term: 0,
-                        log_length: 1
-                    }),
-                    RaftMessage::VoteForCandidate(RaftMessageBody {
-                        id: 4.into(),
-                        current_term: 0,
-                        log_length: 1
-                    })
-                ],
+                vec![vote(1), vote(2), vote(3), vote(4)],
                 vec![],
                 vec![],
                 vec![],
                 vec![]
             ]
-        )
+        );
+        assert!(followers
+            .iter()
+            .all(|follower| follower.voted_for == Some(RaftId(0))));
     }
 
     #[test]
@@ -251,7 +267,8 @@ mod tests {
         candidate.timer.ticks_left = 1;
         candidate.tick();
 
-        for follower in followers[0..2].iter_mut() {
+        // Its own vote plus one more isn't enough out of five.
+        for follower in followers[0..1].iter_mut() {
             follower.tick()
         }
 
@@ -269,7 +286,8 @@ mod tests {
         candidate.timer.ticks_left = 1;
         candidate.tick();
 
-        for follower in followers[0..3].iter_mut() {
+        // Its own vote plus two more is.
+        for follower in followers[0..2].iter_mut() {
             follower.tick()
         }
 
@@ -277,4 +295,655 @@ mod tests {
 
         assert_eq!(candidate.role, Role::Leader);
     }
+
+    /// Elect `buddies[0]` with votes from everyone else and return it along
+    /// with its followers.
+    fn elect_first(buddies: &mut [RaftBuddy]) -> (&mut RaftBuddy, &mut [RaftBuddy]) {
+        let (leader, followers) = buddies.split_first_mut().unwrap();
+
+        leader.timer.ticks_left = 1;
+        leader.tick();
+
+        for follower in followers.iter_mut() {
+            follower.tick()
+        }
+
+        leader.tick();
+
+        (leader, followers)
+    }
+
+    /// One heartbeat from the leader, answered by `followers`, with the
+    /// answers handled by the leader.
+    fn replication_round(leader: &mut RaftBuddy, followers: &mut [RaftBuddy]) {
+        leader.heartbeat.ticks_left = 1;
+        leader.tick();
+
+        for follower in followers.iter_mut() {
+            follower.tick()
+        }
+
+        leader.tick();
+    }
+
+    #[test]
+    fn test_leader_replicates_and_commits_proposals() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+
+        assert_eq!(leader.propose("set x 42"), Some(1));
+
+        replication_round(leader, followers);
+
+        assert_eq!(leader.commit_index, 1);
+        assert_eq!(leader.state_machine.get("x"), Some(&"42".to_owned()));
+
+        replication_round(leader, followers);
+
+        assert!(followers
+            .iter()
+            .all(|follower| follower.commit_index == 1
+                && follower.state_machine == leader.state_machine));
+    }
+
+    #[test]
+    fn test_followers_do_not_accept_proposals() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (_, followers) = elect_first(&mut buddies);
+
+        assert_eq!(followers[0].propose("set x 42"), None);
+    }
+
+    #[test]
+    fn test_lagging_follower_is_sent_a_snapshot() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+        let (lagging, followers) = followers.split_last_mut().unwrap();
+
+        for value in 1..=3 {
+            leader.propose(format!("set x{value} {value}"));
+        }
+
+        replication_round(leader, followers);
+        leader.take_snapshot();
+
+        assert_eq!(leader.commit_index, 3);
+        assert_eq!(leader.log.first_index(), 4);
+
+        // Whatever was sent to the lagging follower before the snapshot was
+        // taken got lost along the way.
+        while lagging.channel().lock().unwrap().pop().is_some() {}
+
+        leader.snapshot_chunk_size = 4;
+
+        for _round in 0..50 {
+            leader.heartbeat.ticks_left = 1;
+            leader.tick();
+            lagging.tick();
+            leader.tick();
+        }
+
+        assert_eq!(lagging.last_applied, 3);
+        assert_eq!(lagging.log.base(), leader.log.base());
+        assert_eq!(lagging.state_machine, leader.state_machine);
+        assert_eq!(leader.progress[&lagging.id].match_index, 3);
+    }
+
+    #[test]
+    fn test_leader_only_compresses_snapshots_the_follower_can_read() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+        let (lagging, followers) = followers.split_last_mut().unwrap();
+
+        leader.propose("set x 1");
+        replication_round(leader, followers);
+        leader.take_snapshot();
+        while lagging.channel().lock().unwrap().pop().is_some() {}
+        leader.snapshot_chunk_size = 4;
+
+        let next_chunk = |lagging: &RaftBuddy| match lagging.channel().lock().unwrap().pop() {
+            Some(Envelope {
+                message: RaftMessage::InstallSnapshot(body),
+                ..
+            }) => body,
+            other => panic!("Expected a snapshot chunk, got {other:?}"),
+        };
+        let answer = |leader: &mut RaftBuddy, next_offset: usize, can_read: Compression| {
+            leader.channel().lock().unwrap().push(envelope(
+                4,
+                0,
+                leader.current_term,
+                RaftMessage::InstallSnapshotResponse(InstallSnapshotResponseBody {
+                    last_included_index: 1,
+                    next_offset,
+                    done: false,
+                    compression: can_read,
+                }),
+            ));
+            leader.tick();
+        };
+
+        // Nothing's known about what the follower can read to begin with.
+        leader.heartbeat.ticks_left = 1;
+        leader.tick();
+        assert_eq!(next_chunk(lagging).compression, Compression::None);
+
+        answer(leader, 4, Compression::Deflate);
+        assert_eq!(next_chunk(lagging).compression, Compression::preferred());
+
+        // Say it couldn't make sense of that chunk, and can't inflate.
+        answer(leader, 4, Compression::None);
+        let chunk = next_chunk(lagging);
+        assert_eq!(chunk.offset, 4);
+        assert_eq!(chunk.compression, Compression::None);
+    }
+
+    #[test]
+    fn test_follower_resumes_interrupted_snapshot_transfer() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let follower = &mut buddies[1];
+
+        let mut state_machine = KeyValueStore::default();
+        state_machine.apply(&LogEntry {
+            term: 1,
+            index: 1,
+            contents: "set x 42".to_owned(),
+        });
+        let snapshot = Snapshot {
+            base: SnapshotBase {
+                last_included_index: 3,
+                last_included_term: 1,
+            },
+            data: state_machine.snapshot(),
+        };
+
+        let send_chunk = |follower: &mut RaftBuddy, offset: usize| {
+            let (data, done) = snapshot.chunk(offset, 4);
+
+            follower.channel().lock().unwrap().push(Envelope {
+                from: RaftId(0),
+                to: RaftId(1),
+                term: 1,
+                cluster_id: ClusterId::default(),
+                message: RaftMessage::InstallSnapshot(InstallSnapshotBody {
+                    last_included_index: 3,
+                    last_included_term: 1,
+                    offset,
+                    data: data.to_vec(),
+                    compression: Compression::None,
+                    done,
+                }),
+            });
+            follower.tick();
+
+            match topology[&RaftId(0)]
+                .lock()
+                .unwrap()
+                .pop()
+                .map(|envelope| envelope.message)
+            {
+                Some(RaftMessage::InstallSnapshotResponse(response)) => response,
+                other => panic!("Expected a snapshot response, got {other:?}"),
+            }
+        };
+
+        assert_eq!(send_chunk(follower, 0).next_offset, 4);
+
+        // The leader lost track and starts over, but we pick up where we were.
+        assert_eq!(send_chunk(follower, 0).next_offset, 4);
+
+        let mut offset = 4;
+        let response = loop {
+            let response = send_chunk(follower, offset);
+
+            if response.done {
+                break response;
+            }
+
+            offset = response.next_offset;
+        };
+
+        assert_eq!(response.next_offset, snapshot.data.len());
+        assert_eq!(follower.state_machine, state_machine);
+        assert_eq!(follower.log.base(), snapshot.base);
+        assert_eq!(follower.commit_index, 3);
+        assert_eq!(follower.snapshot, Some(snapshot.clone()));
+    }
+
+    #[test]
+    fn test_policy_snapshots_after_enough_entries() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+
+        for buddy in buddies.iter_mut() {
+            buddy.snapshot_policy = Some(SnapshotPolicy {
+                max_entries: 3,
+                max_bytes: usize::MAX,
+                trailing_entries: 1,
+            });
+        }
+
+        let (leader, followers) = elect_first(&mut buddies);
+
+        leader.propose("set x 1");
+        leader.propose("set x 2");
+        replication_round(leader, followers);
+
+        assert_eq!(leader.commit_index, 2);
+        assert_eq!(leader.snapshot, None);
+        assert_eq!(leader.entries_since_snapshot, 2);
+
+        leader.propose("set x 3");
+        replication_round(leader, followers);
+        leader.tick();
+
+        let snapshot = leader.snapshot.as_ref().unwrap();
+        assert_eq!(snapshot.base.last_included_index, 3);
+        assert_eq!(leader.log.base().last_included_index, 2);
+        assert_eq!(leader.log.first_index(), 3);
+        assert_eq!(leader.entries_since_snapshot, 0);
+        assert_eq!(leader.bytes_since_snapshot, 0);
+    }
+
+    #[test]
+    fn test_policy_snapshots_after_enough_bytes() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+
+        leader.snapshot_policy = Some(SnapshotPolicy {
+            max_entries: usize::MAX,
+            max_bytes: 16,
+            trailing_entries: 0,
+        });
+
+        leader.propose("set x 1");
+        replication_round(leader, followers);
+        leader.tick();
+
+        assert_eq!(leader.snapshot, None);
+
+        leader.propose("set greeting hello there");
+        replication_round(leader, followers);
+        leader.tick();
+
+        assert_eq!(leader.log.base().last_included_index, 2);
+    }
+
+    #[test]
+    fn test_slightly_lagging_follower_catches_up_without_a_snapshot() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+        let (lagging, followers) = followers.split_last_mut().unwrap();
+
+        leader.snapshot_policy = Some(SnapshotPolicy {
+            max_entries: 4,
+            max_bytes: usize::MAX,
+            trailing_entries: 3,
+        });
+
+        leader.propose("set x 1");
+        replication_round(leader, followers);
+        lagging.tick();
+        leader.tick();
+
+        assert_eq!(leader.progress[&lagging.id].match_index, 1);
+
+        for value in 2..=4 {
+            leader.propose(format!("set x {value}"));
+        }
+        replication_round(leader, followers);
+        leader.tick();
+
+        assert_eq!(leader.log.first_index(), 2);
+
+        for _round in 0..3 {
+            replication_round(leader, std::slice::from_mut(lagging));
+        }
+
+        assert_eq!(lagging.last_applied, 4);
+        assert_eq!(lagging.snapshot, None);
+        assert_eq!(lagging.log.first_index(), 1);
+    }
+
+    #[test]
+    fn test_buddy_restores_from_memory_storage() {
+        let topology = default_topology();
+        let storage = MemoryStorage::default();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        buddies[0].storage = Box::new(storage.clone());
+        let (leader, followers) = elect_first(&mut buddies);
+
+        leader.propose("set x 1");
+        leader.propose("set y 2");
+        replication_round(leader, followers);
+        leader.take_snapshot();
+        leader.propose("set x 3");
+
+        let restored = RaftBuddy::restore(RaftId(0), topology, Box::new(storage)).unwrap();
+
+        assert_eq!(restored.current_term, leader.current_term);
+        assert_eq!(restored.voted_for, Some(RaftId(0)));
+        assert_eq!(restored.snapshot, leader.snapshot);
+        assert_eq!(restored.log.base(), leader.log.base());
+        assert_eq!(restored.log.last_index(), 3);
+        assert_eq!(restored.last_applied, 2);
+        assert_eq!(restored.state_machine.get("y"), Some(&"2".to_owned()));
+    }
+
+    #[test]
+    fn test_buddy_restores_from_disk_storage() {
+        let dir = ScratchDir::new("buddy-disk");
+
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        buddies[0].storage = Box::new(DiskStorage::open(&dir).unwrap());
+        let (leader, followers) = elect_first(&mut buddies);
+
+        leader.propose("set x 1");
+        replication_round(leader, followers);
+        leader.take_snapshot();
+
+        let storage = DiskStorage::open(&dir).unwrap();
+        let restored = RaftBuddy::restore(RaftId(0), topology, Box::new(storage)).unwrap();
+
+        assert_eq!(restored.snapshot, leader.snapshot);
+        assert_eq!(restored.state_machine, leader.state_machine);
+    }
+
+    #[test]
+    fn test_buddy_refuses_to_restore_from_a_snapshot_behind_its_log() {
+        let dir = ScratchDir::new("buddy-stale-snapshot");
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        buddies[0].storage = Box::new(DiskStorage::open(&dir).unwrap());
+        let (leader, followers) = elect_first(&mut buddies);
+
+        for value in 1..=2 {
+            leader.propose(format!("set x {value}"));
+            replication_round(leader, followers);
+            leader.take_snapshot();
+        }
+
+        // The newest snapshot is unreadable, and the log's already been
+        // compacted past the one before it.
+        let storage = DiskStorage::open(&dir).unwrap();
+        let newest = storage.snapshots().list().unwrap().pop().unwrap();
+        let mut bytes = std::fs::read(&newest).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        std::fs::write(&newest, bytes).unwrap();
+
+        let error = RaftBuddy::restore(RaftId(0), topology, Box::new(storage)).unwrap_err();
+
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    fn envelope(from: usize, to: usize, term: usize, message: RaftMessage) -> Envelope {
+        Envelope {
+            from: RaftId(from),
+            to: RaftId(to),
+            term,
+            cluster_id: ClusterId::default(),
+            message,
+        }
+    }
+
+    fn heartbeat(from: usize, to: usize, term: usize) -> Envelope {
+        envelope(
+            from,
+            to,
+            term,
+            RaftMessage::AppendEntries(AppendEntriesBody {
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![],
+                leader_commit: 0,
+            }),
+        )
+    }
+
+    #[test]
+    fn test_buddies_only_vote_once_per_term() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let voter = &mut buddies[4];
+        let request_vote = RaftMessage::RequestVote(RequestVoteBody {
+            last_log_index: 0,
+            last_log_term: 0,
+        });
+
+        voter
+            .channel()
+            .lock()
+            .unwrap()
+            .push(envelope(1, 4, 1, request_vote.clone()));
+        voter
+            .channel()
+            .lock()
+            .unwrap()
+            .push(envelope(2, 4, 1, request_vote.clone()));
+        voter.tick();
+
+        assert_eq!(
+            topology[&RaftId(1)].lock().unwrap().pop().unwrap().message,
+            RaftMessage::VoteForCandidate
+        );
+        assert_eq!(
+            topology[&RaftId(2)].lock().unwrap().pop().unwrap().message,
+            RaftMessage::RejectCandidateVote
+        );
+
+        // A new term is a new vote.
+        voter
+            .channel()
+            .lock()
+            .unwrap()
+            .push(envelope(2, 4, 2, request_vote));
+        voter.tick();
+
+        assert_eq!(
+            topology[&RaftId(2)].lock().unwrap().pop().unwrap().message,
+            RaftMessage::VoteForCandidate
+        );
+        assert_eq!(voter.voted_for, Some(RaftId(2)));
+    }
+
+    #[test]
+    fn test_buddies_do_not_vote_for_candidates_behind_them() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let voter = &mut buddies[4];
+        voter.log.push(LogEntry {
+            term: 2,
+            index: 1,
+            contents: "set x 1".to_owned(),
+        });
+
+        voter.channel().lock().unwrap().push(envelope(
+            1,
+            4,
+            3,
+            RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: 5,
+                last_log_term: 1,
+            }),
+        ));
+        voter.tick();
+
+        assert_eq!(
+            topology[&RaftId(1)].lock().unwrap().pop().unwrap().message,
+            RaftMessage::RejectCandidateVote
+        );
+        assert_eq!(voter.current_term, 3);
+        assert_eq!(voter.voted_for, None);
+    }
+
+    #[test]
+    fn test_leader_steps_down_on_seeing_a_newer_term() {
+        let mut buddies: Vec<RaftBuddy> = default_topology().into();
+        let (leader, followers) = elect_first(&mut buddies);
+        let term = leader.current_term;
+
+        leader
+            .channel()
+            .lock()
+            .unwrap()
+            .push(heartbeat(1, 0, term + 1));
+        leader.tick();
+
+        assert_eq!(leader.role, Role::Follower);
+        assert_eq!(leader.current_term, term + 1);
+    }
+
+    #[test]
+    fn test_stale_requests_are_turned_away() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let (leader, followers) = elect_first(&mut buddies);
+        let term = leader.current_term;
+        while topology[&RaftId(1)].lock().unwrap().pop().is_some() {}
+
+        leader
+            .channel()
+            .lock()
+            .unwrap()
+            .push(heartbeat(1, 0, term - 1));
+        leader.tick();
+
+        assert_eq!(leader.role, Role::Leader);
+        assert_eq!(
+            topology[&RaftId(1)].lock().unwrap().pop(),
+            Some(envelope(
+                0,
+                1,
+                term,
+                RaftMessage::AppendEntriesResponse(AppendEntriesResponseBody {
+                    success: false,
+                    last_log_index: 0,
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_messages_from_other_clusters_are_dropped() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let follower = &mut buddies[1];
+
+        follower.channel().lock().unwrap().push(Envelope {
+            cluster_id: ClusterId(7),
+            ..heartbeat(0, 1, 5)
+        });
+        follower.tick();
+
+        assert_eq!(follower.current_term, 0);
+        assert_eq!(topology[&RaftId(0)].lock().unwrap().pop(), None);
+    }
+
+    #[test]
+    fn test_messages_for_someone_else_are_dropped() {
+        let topology = default_topology();
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let follower = &mut buddies[1];
+
+        follower.channel().lock().unwrap().push(heartbeat(0, 2, 5));
+        follower.channel().lock().unwrap().push(heartbeat(9, 1, 5));
+        follower.tick();
+
+        assert_eq!(follower.current_term, 0);
+        assert_eq!(topology[&RaftId(0)].lock().unwrap().pop(), None);
+    }
+
+    /// The default topology, but with buddy 1's inbox bounded.
+    fn topology_with_bounded_follower(capacity: usize, overflow: OverflowPolicy) -> Topology {
+        let mut topology = default_topology();
+        topology.insert(
+            RaftId(1),
+            Arc::new(Mutex::new(RaftChannel::bounded(capacity, overflow))) as ArcMutChannel,
+        );
+
+        topology
+    }
+
+    #[test]
+    fn test_leader_pauses_replication_to_a_full_follower() {
+        let topology = topology_with_bounded_follower(1, OverflowPolicy::Reject);
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let (leader, followers) = elect_first(&mut buddies);
+
+        // Buddy 1 hasn't looked at the heartbeat that came with the election
+        // yet, so there's no room for what comes next.
+        leader.propose("set x 1");
+        replication_round(leader, &mut followers[1..]);
+
+        assert!(leader.progress[&RaftId(1)].paused);
+        assert_eq!(leader.commit_index, 1);
+        assert_eq!(topology[&RaftId(1)].lock().unwrap().all_messages().len(), 1);
+
+        // Answering the old heartbeat makes room, so the entry follows.
+        replication_round(leader, followers);
+        followers[0].tick();
+
+        assert!(!leader.progress[&RaftId(1)].paused);
+        assert_eq!(followers[0].log.last_index(), 1);
+    }
+
+    #[test]
+    fn test_leader_holds_on_to_what_a_full_follower_blocked() {
+        let topology = topology_with_bounded_follower(2, OverflowPolicy::Block);
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let (leader, followers) = elect_first(&mut buddies);
+
+        leader.propose("set x 1");
+        replication_round(leader, &mut followers[1..]);
+        leader.propose("set x 2");
+        replication_round(leader, &mut followers[1..]);
+        replication_round(leader, &mut followers[1..]);
+
+        assert!(leader.progress[&RaftId(1)].paused);
+        assert!(leader.blocked.contains_key(&RaftId(1)));
+        assert_eq!(leader.commit_index, 2);
+        assert_eq!(topology[&RaftId(1)].lock().unwrap().all_messages().len(), 2);
+
+        replication_round(leader, followers);
+        replication_round(leader, followers);
+
+        assert!(!leader.progress[&RaftId(1)].paused);
+        assert!(leader.blocked.is_empty());
+        assert_eq!(leader.progress[&RaftId(1)].match_index, 2);
+        assert_eq!(followers[0].state_machine.get("x"), Some(&"2".to_owned()));
+    }
+
+    #[test]
+    fn test_votes_a_full_candidate_blocked_are_held_until_there_is_room() {
+        let topology = topology_with_bounded_follower(1, OverflowPolicy::Block);
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let voter = &mut buddies[2];
+
+        topology[&RaftId(1)]
+            .lock()
+            .unwrap()
+            .push(heartbeat(0, 1, 0));
+        voter.channel().lock().unwrap().push(envelope(
+            1,
+            2,
+            1,
+            RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: 0,
+                last_log_term: 0,
+            }),
+        ));
+        voter.tick();
+
+        assert_eq!(voter.blocked[&RaftId(1)].len(), 1);
+
+        topology[&RaftId(1)].lock().unwrap().pop();
+        voter.tick();
+
+        assert!(voter.blocked.is_empty());
+        assert_eq!(
+            topology[&RaftId(1)].lock().unwrap().pop().unwrap().message,
+            RaftMessage::VoteForCandidate
+        );
+    }
 }
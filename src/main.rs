@@ -1,4 +1,94 @@
-// This bin should start a server and then copy logs from instance to instance.
+// This bin starts a server and then copies logs from instance to instance.
+// Run one per buddy, each with its own id and everyone's address in the same
+// order, then type commands like `set x 42` into whichever is the leader:
+//
+//     raft 0 127.0.0.1:7000 127.0.0.1:7001 127.0.0.1:7002
+//
+// On Unix, addresses can also be Unix domain sockets, as
+// `unix:/tmp/raft-0.sock`.
+//
+// Set RAFT_CLUSTER_KEY to sign everything and refuse anything unsigned, and
+// RAFT_ACCEPTED_KEY as well to accept a second key while rotating.
+use std::collections::BTreeMap;
+use std::io::BufRead;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use raft::raft_auth::{ClusterKey, ClusterKeys};
+use raft::raft_buddy::RaftBuddy;
+use raft::raft_codec::BinaryCodec;
+use raft::raft_id::RaftId;
+use raft::raft_temporal::{RaftTimer, Temporal};
+use raft::raft_topology::{PeerAddress, Topology};
+
+const USAGE: &str = "usage: raft <id> <address of buddy 0> <address of buddy 1> ...";
+const TICK: Duration = Duration::from_millis(10);
+
 fn main() {
-    println!("Hello, world!");
+    let mut args = std::env::args().skip(1);
+
+    let id = RaftId(args.next().and_then(|id| id.parse().ok()).expect(USAGE));
+    let addresses: BTreeMap<RaftId, PeerAddress> = args
+        .enumerate()
+        .map(|(peer_id, addr)| (RaftId(peer_id), addr.parse().expect(USAGE)))
+        .collect();
+
+    assert!(addresses.contains_key(&id), "{USAGE}");
+
+    let keys = std::env::var("RAFT_CLUSTER_KEY").ok().map(|current| {
+        let keys = ClusterKeys::new(ClusterKey::new(current));
+
+        match std::env::var("RAFT_ACCEPTED_KEY") {
+            Ok(accepted) => Arc::new(keys.also_accepting(ClusterKey::new(accepted))),
+            Err(_) => Arc::new(keys),
+        }
+    });
+
+    let topology = Topology::connect_with_keys(id, &addresses, &BinaryCodec, keys)
+        .expect("Could not listen on our address");
+
+    let mut buddy = RaftBuddy {
+        id,
+        topology,
+        timer: RaftTimer::from(100 + *id * 10),
+        ..Default::default()
+    };
+
+    let (commands, typed) = mpsc::channel();
+    thread::spawn(move || {
+        for line in std::io::stdin().lock().lines().map_while(Result::ok) {
+            if commands.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut role = None;
+    let mut last_applied = buddy.last_applied;
+
+    loop {
+        buddy.tick();
+
+        for command in typed.try_iter() {
+            match buddy.propose(command) {
+                Some(index) => println!("Proposed at index {index}"),
+                None => println!("Not the leader, try another buddy"),
+            }
+        }
+
+        if role.as_ref() != Some(&buddy.role) {
+            println!("Buddy {} is now {:?}", *id, buddy.role);
+            role = Some(buddy.role.clone());
+        }
+
+        for index in last_applied + 1..=buddy.last_applied {
+            if let Some(entry) = buddy.log.get(index) {
+                println!("Applied {index}: {}", entry.contents);
+            }
+        }
+        last_applied = buddy.last_applied;
+
+        thread::sleep(TICK);
+    }
 }
@@ -0,0 +1,344 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::raft_buddy::{Applied, RaftBuddy, Role};
+use crate::raft_channel::Channel;
+use crate::raft_message::Envelope;
+use crate::raft_temporal::Temporal;
+
+/// Why a proposal didn't get an answer from the state machine.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ProposeError {
+    /// We weren't the leader, so nothing was proposed. Try another buddy.
+    NotLeader,
+    /// A later leader committed a different entry at the proposal's index.
+    Overwritten,
+    /// There's no telling whether the proposal made it: either we stopped
+    /// leading the term it was proposed in, or a snapshot covering its index
+    /// was installed, before we saw it applied.
+    Indeterminate,
+    /// The driver stopped before the proposal was applied.
+    Stopped,
+}
+
+impl fmt::Display for ProposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            Self::NotLeader => "not the leader",
+            Self::Overwritten => "overwritten by another leader's entry",
+            Self::Indeterminate => "leadership or a snapshot moved on before it was seen applied",
+            Self::Stopped => "the buddy stopped",
+        };
+
+        f.write_str(reason)
+    }
+}
+
+impl std::error::Error for ProposeError {}
+
+type Reply = oneshot::Sender<Result<Option<String>, ProposeError>>;
+
+enum Request {
+    Propose(String, Reply),
+    Role(oneshot::Sender<Role>),
+    Stop,
+}
+
+/// A peer reached through an async transport. Pushing never blocks; the
+/// envelope is handed to whatever task is on the other end of `sender`.
+#[derive(Debug)]
+pub struct AsyncPeer(pub mpsc::UnboundedSender<Envelope>);
+
+impl Channel for AsyncPeer {
+    fn push(&mut self, envelope: Envelope) {
+        // The other end going away looks the same as a lost message.
+        let _ = self.0.send(envelope);
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        None
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        vec![]
+    }
+}
+
+/// Runs a buddy as a tokio task, ticking it every `tick` and moving anything
+/// that arrives on `inbound` into its inbox as it comes in.
+///
+/// The buddy pushes to its peers from inside the task, so their channels
+/// mustn't block. `AsyncPeer`s don't, and neither do `TcpPeer`s and
+/// `UnixPeer`s, which only queue for a writer thread of their own.
+pub struct AsyncBuddy {
+    requests: mpsc::UnboundedSender<Request>,
+    task: JoinHandle<RaftBuddy>,
+}
+
+impl AsyncBuddy {
+    /// Must be called from within a tokio runtime.
+    pub fn spawn(
+        buddy: RaftBuddy,
+        tick: Duration,
+        inbound: mpsc::UnboundedReceiver<Envelope>,
+    ) -> Self {
+        let (requests, incoming_requests) = mpsc::unbounded_channel();
+        let task = tokio::spawn(drive(buddy, tick, inbound, incoming_requests));
+
+        Self { requests, task }
+    }
+
+    /// Resolves once the command has been committed and applied, with what
+    /// the state machine gave back for it.
+    pub async fn propose(
+        &self,
+        command: impl Into<String>,
+    ) -> Result<Option<String>, ProposeError> {
+        let (reply, answer) = oneshot::channel();
+
+        self.requests
+            .send(Request::Propose(command.into(), reply))
+            .map_err(|_| ProposeError::Stopped)?;
+
+        answer.await.unwrap_or(Err(ProposeError::Stopped))
+    }
+
+    /// Reads go through the log like everything else, so they see every
+    /// write that was committed before they were proposed.
+    pub async fn read(&self, key: &str) -> Result<Option<String>, ProposeError> {
+        self.propose(format!("get {key}")).await
+    }
+
+    pub async fn role(&self) -> Option<Role> {
+        let (reply, answer) = oneshot::channel();
+        self.requests.send(Request::Role(reply)).ok()?;
+
+        answer.await.ok()
+    }
+
+    /// Stop ticking and hand the buddy back. Anything still waiting on a
+    /// proposal gets `ProposeError::Stopped`.
+    pub async fn stop(self) -> RaftBuddy {
+        let _ = self.requests.send(Request::Stop);
+
+        self.task.await.expect("The driver task panicked")
+    }
+}
+
+async fn drive(
+    mut buddy: RaftBuddy,
+    tick: Duration,
+    mut inbound: mpsc::UnboundedReceiver<Envelope>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) -> RaftBuddy {
+    let mut interval = time::interval(tick);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    // BTreeMap<index, (term it was proposed in, reply)>
+    let mut pending: BTreeMap<usize, (usize, Reply)> = BTreeMap::new();
+    buddy.applied.get_or_insert_with(Vec::new);
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => buddy.tick(),
+            Some(envelope) = inbound.recv() => buddy.channel().lock().unwrap().push(envelope),
+            request = requests.recv() => match request {
+                Some(Request::Propose(command, reply)) => match buddy.propose(command) {
+                    Some(index) => {
+                        pending.insert(index, (buddy.current_term, reply));
+                    }
+                    None => {
+                        let _ = reply.send(Err(ProposeError::NotLeader));
+                    }
+                },
+                Some(Request::Role(reply)) => {
+                    let _ = reply.send(buddy.role.clone());
+                }
+                Some(Request::Stop) | None => break,
+            },
+        }
+
+        let applied = buddy.applied.as_mut().map(mem::take).unwrap_or_default();
+
+        for Applied {
+            index,
+            term,
+            output,
+        } in applied
+        {
+            if let Some((proposed_term, reply)) = pending.remove(&index) {
+                let answer = if term == proposed_term {
+                    Ok(output)
+                } else {
+                    Err(ProposeError::Overwritten)
+                };
+                let _ = reply.send(answer);
+            }
+        }
+
+        // Whatever's left at or below last_applied went in with a snapshot.
+        let still_pending = pending.split_off(&(buddy.last_applied + 1));
+        for (_, reply) in mem::replace(&mut pending, still_pending).into_values() {
+            let _ = reply.send(Err(ProposeError::Indeterminate));
+        }
+
+        // Once we're not leading the term something was proposed in, whether
+        // it's committed is up to whoever is, and we may never hear.
+        let leading = (buddy.role == Role::Leader).then_some(buddy.current_term);
+        let (still_pending, lost) = mem::take(&mut pending)
+            .into_iter()
+            .partition(|(_, (proposed_term, _))| leading == Some(*proposed_term));
+        pending = still_pending;
+        for (_, (_, reply)) in lost {
+            let _ = reply.send(Err(ProposeError::Indeterminate));
+        }
+    }
+
+    for (_, reply) in pending.into_values() {
+        let _ = reply.send(Err(ProposeError::Stopped));
+    }
+
+    buddy.applied = None;
+    buddy
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_channel::RaftChannel;
+    use crate::raft_id::RaftId;
+    use crate::raft_message::{AppendEntriesBody, RaftMessage};
+    use crate::raft_temporal::RaftTimer;
+    use crate::raft_topology::Topology;
+    use crate::raft_type_aliases::ArcMutChannel;
+    use std::sync::{Arc, Mutex};
+
+    const TICK: Duration = Duration::from_millis(1);
+
+    /// Three buddies wired to each other's drivers through `AsyncPeer`s.
+    fn spawn_cluster() -> Vec<AsyncBuddy> {
+        let (senders, receivers): (Vec<_>, Vec<_>) =
+            (0..3).map(|_| mpsc::unbounded_channel()).unzip();
+
+        receivers
+            .into_iter()
+            .enumerate()
+            .map(|(index, inbound)| {
+                let topology =
+                    Topology::from_iter(senders.iter().enumerate().map(|(peer, sender)| {
+                        let channel: ArcMutChannel = if peer == index {
+                            Arc::new(Mutex::new(RaftChannel::default()))
+                        } else {
+                            Arc::new(Mutex::new(AsyncPeer(sender.clone())))
+                        };
+
+                        (RaftId(peer), channel)
+                    }));
+
+                let buddy = RaftBuddy {
+                    id: RaftId(index),
+                    topology,
+                    timer: RaftTimer::from(20 + index * 10),
+                    ..Default::default()
+                };
+
+                AsyncBuddy::spawn(buddy, TICK, inbound)
+            })
+            .collect()
+    }
+
+    async fn wait_for_leader(buddies: &[AsyncBuddy]) -> &AsyncBuddy {
+        for _attempt in 0..500 {
+            for buddy in buddies {
+                if buddy.role().await == Some(Role::Leader) {
+                    return buddy;
+                }
+            }
+
+            time::sleep(TICK * 5).await;
+        }
+
+        panic!("No leader was elected");
+    }
+
+    #[tokio::test]
+    async fn proposals_resolve_once_applied() {
+        let buddies = spawn_cluster();
+        let leader = wait_for_leader(&buddies).await;
+
+        assert_eq!(leader.propose("set x 42").await, Ok(None));
+        assert_eq!(leader.read("x").await, Ok(Some("42".to_owned())));
+        assert_eq!(leader.read("y").await, Ok(None));
+
+        for buddy in buddies {
+            let buddy = buddy.stop().await;
+            assert_eq!(buddy.applied, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn followers_turn_proposals_away() {
+        let buddies = spawn_cluster();
+        wait_for_leader(&buddies).await;
+
+        for buddy in &buddies {
+            if buddy.role().await == Some(Role::Follower) {
+                assert_eq!(buddy.propose("set x 1").await, Err(ProposeError::NotLeader));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn proposals_are_given_up_on_when_leadership_is_lost() {
+        let (sender, inbound) = mpsc::unbounded_channel();
+        let leader = RaftBuddy {
+            role: Role::Leader,
+            current_term: 1,
+            topology: Topology::in_memory(3),
+            ..Default::default()
+        };
+        let driver = AsyncBuddy::spawn(leader, TICK, inbound);
+
+        // Nobody's listening, so it can't commit, until buddy 1 turns up
+        // leading a later term.
+        let newer_leader = async {
+            time::sleep(TICK * 20).await;
+            sender
+                .send(Envelope {
+                    from: RaftId(1),
+                    to: RaftId(0),
+                    term: 2,
+                    cluster_id: Default::default(),
+                    message: RaftMessage::AppendEntries(AppendEntriesBody {
+                        prev_log_index: 0,
+                        prev_log_term: 0,
+                        entries: vec![],
+                        leader_commit: 0,
+                    }),
+                })
+                .unwrap();
+        };
+
+        let (answer, ()) = tokio::join!(driver.propose("set x 1"), newer_leader);
+
+        assert_eq!(answer, Err(ProposeError::Indeterminate));
+        assert_eq!(driver.role().await, Some(Role::Follower));
+    }
+
+    #[tokio::test]
+    async fn stopping_hands_the_buddy_back() {
+        let (_sender, inbound) = mpsc::unbounded_channel();
+        let driver = AsyncBuddy::spawn(RaftBuddy::default(), TICK, inbound);
+
+        let buddy = driver.stop().await;
+
+        assert_eq!(buddy.id, RaftId(0));
+        assert_eq!(buddy.role, Role::Follower);
+    }
+}
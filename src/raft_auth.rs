@@ -0,0 +1,126 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How many bytes an HMAC-SHA256 tag takes up on the wire.
+pub const TAG_LENGTH: usize = 32;
+
+/// A secret shared by every buddy in the cluster.
+#[derive(PartialEq, Eq, Clone)]
+pub struct ClusterKey(Vec<u8>);
+
+impl ClusterKey {
+    pub fn new(secret: impl Into<Vec<u8>>) -> Self {
+        Self(secret.into())
+    }
+
+    fn mac(&self, version: u8, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.0).expect("HMAC takes keys of any length");
+        mac.update(&[version]);
+        mac.update(payload);
+        mac
+    }
+}
+
+/// Keeps the secret out of logs.
+impl fmt::Debug for ClusterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ClusterKey(..)")
+    }
+}
+
+/// What a buddy signs its frames with, and what it'll accept them signed
+/// with.
+///
+/// Rotating to a new key without a moment where buddies can't understand
+/// each other takes three rounds of restarts: first every buddy accepts the
+/// new key while still signing with the old one, then every buddy signs with
+/// the new one while still accepting the old, then the old one is dropped.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ClusterKeys {
+    /// Signs everything we send, and is always accepted.
+    pub current: ClusterKey,
+    /// Also accepted, for the length of a rotation.
+    pub accepted: Option<ClusterKey>,
+}
+
+impl ClusterKeys {
+    pub fn new(current: ClusterKey) -> Self {
+        Self {
+            current,
+            accepted: None,
+        }
+    }
+
+    pub fn also_accepting(self, key: ClusterKey) -> Self {
+        Self {
+            accepted: Some(key),
+            ..self
+        }
+    }
+
+    /// The tag covers the frame version as well as the payload, so a frame
+    /// can't be passed off as another version's.
+    pub fn sign(&self, version: u8, payload: &[u8]) -> [u8; TAG_LENGTH] {
+        self.current
+            .mac(version, payload)
+            .finalize()
+            .into_bytes()
+            .into()
+    }
+
+    /// Compared in constant time, against each key we accept.
+    pub fn verify(&self, version: u8, payload: &[u8], tag: &[u8]) -> bool {
+        [Some(&self.current), self.accepted.as_ref()]
+            .into_iter()
+            .flatten()
+            .any(|key| key.mac(version, payload).verify_slice(tag).is_ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(secret: &str) -> ClusterKeys {
+        ClusterKeys::new(ClusterKey::new(secret))
+    }
+
+    #[test]
+    fn it_verifies_what_it_signs() {
+        let keys = keys("hunter2");
+        let tag = keys.sign(1, b"payload");
+
+        assert!(keys.verify(1, b"payload", &tag));
+    }
+
+    #[test]
+    fn it_refuses_tampering_and_other_keys() {
+        let keys = keys("hunter2");
+        let tag = keys.sign(1, b"payload");
+
+        assert!(!keys.verify(1, b"payloaD", &tag));
+        assert!(!keys.verify(2, b"payload", &tag));
+        assert!(!keys.verify(1, b"payload", &tag[1..]));
+        assert!(!self::keys("swordfish").verify(1, b"payload", &tag));
+    }
+
+    #[test]
+    fn it_accepts_either_key_during_a_rotation() {
+        let old = keys("hunter2");
+        let new = keys("swordfish");
+        let rotating = keys("swordfish").also_accepting(ClusterKey::new("hunter2"));
+
+        assert!(rotating.verify(1, b"payload", &old.sign(1, b"payload")));
+        assert!(rotating.verify(1, b"payload", &new.sign(1, b"payload")));
+        assert_eq!(rotating.sign(1, b"payload"), new.sign(1, b"payload"));
+    }
+
+    #[test]
+    fn it_keeps_keys_out_of_debug_output() {
+        assert!(!format!("{:?}", keys("hunter2")).contains("hunter2"));
+    }
+}
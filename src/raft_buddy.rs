@@ -1,33 +1,104 @@
-use std::cell::RefCell;
-use std::cmp::Ordering;
-use std::{collections::BTreeMap, rc::Rc};
-
-use crate::raft_channel::RaftChannel;
-use crate::raft_id::RaftId;
-use crate::raft_log::RaftLog;
-use crate::raft_message::{RaftMessage as Message, RaftMessageBody as Body};
+use std::collections::{BTreeMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use crate::raft_channel::{Overflow, PeerHealth, RaftChannel};
+use crate::raft_compression::Compression;
+use crate::raft_encoding::invalid_data;
+use crate::raft_frame::MAX_FRAME_LENGTH;
+use crate::raft_id::{ClusterId, RaftId};
+use crate::raft_log::{LogEntry, RaftAppendable, RaftLog, SnapshotBase};
+use crate::raft_message::{
+    AppendEntriesBody, AppendEntriesResponseBody, Envelope, InstallSnapshotBody,
+    InstallSnapshotResponseBody, RaftMessage as Message, RequestVoteBody,
+};
+use crate::raft_snapshot::{Snapshot, SnapshotPolicy};
+use crate::raft_state_machine::{KeyValueStore, StateMachine};
+use crate::raft_storage::{HardState, MemoryStorage, PersistedState, Storage};
 use crate::raft_temporal::{RaftTimer, Temporal};
 use crate::raft_topology::Topology;
-use crate::raft_type_aliases::RcMutChannel;
+use crate::raft_type_aliases::ArcMutChannel;
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
 pub enum Role {
     Follower,
     Candidate,
     Leader,
 }
 
+/// What the leader knows about how far along each follower is.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub struct Progress {
+    pub next_index: usize,
+    pub match_index: usize,
+    /// Set while the follower is too far behind for AppendEntries and is being
+    /// sent our snapshot instead, to the offset we'll send from next.
+    pub snapshot_offset: Option<usize>,
+    /// What to compress snapshot chunks to the follower with, which is
+    /// nothing until it's told us what it can read.
+    pub snapshot_compression: Compression,
+    /// The follower's channel is full, so we're holding off until it's
+    /// caught up on what's there.
+    pub paused: bool,
+}
+
+/// An entry that's been applied to the state machine, and what applying it
+/// gave back.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Applied {
+    pub index: usize,
+    pub term: usize,
+    pub output: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct RaftBuddy {
     pub role: Role,
     pub id: RaftId,
-    /// BTreeMap<RaftId, RcMutChannel>
+    /// BTreeMap<RaftId, ArcMutChannel>
     pub topology: Topology,
     pub timer: RaftTimer,
     pub current_term: usize,
+    /// Who we voted for in `current_term`, if anyone. Candidates vote for
+    /// themselves.
+    pub voted_for: Option<RaftId>,
+    /// Messages from any other cluster are dropped.
+    pub cluster_id: ClusterId,
     pub log: RaftLog,
-    /// BTreeMap<RaftId, Message>
+    /// BTreeMap<RaftId, Message>, the votes and rejections we've had as a
+    /// candidate this term
     pub votes_received: BTreeMap<RaftId, Message>,
+    pub commit_index: usize,
+    pub last_applied: usize,
+    pub state_machine: KeyValueStore,
+    /// Set to `Some` to keep every entry we apply here until someone takes
+    /// it, which is how whoever proposed it finds out what it did. Entries
+    /// covered by an installed snapshot never show up.
+    pub applied: Option<Vec<Applied>>,
+    /// The latest snapshot we've taken or installed, which is what we send to
+    /// followers that need entries we've already compacted.
+    pub snapshot: Option<Snapshot>,
+    /// A snapshot the leader is partway through sending us.
+    pub incoming_snapshot: Option<Snapshot>,
+    /// BTreeMap<RaftId, Progress>, only kept up to date while we're leader
+    pub progress: BTreeMap<RaftId, Progress>,
+    /// BTreeMap<RaftId, VecDeque<Envelope>>, whatever a full channel handed
+    /// back under `OverflowPolicy::Block`, oldest first. It goes out before
+    /// anything else once there's room.
+    pub blocked: BTreeMap<RaftId, VecDeque<Envelope>>,
+    /// BTreeMap<RaftId, PeerHealth>, what each peer's channel said about
+    /// getting through to it as of our last tick.
+    pub peer_health: BTreeMap<RaftId, PeerHealth>,
+    pub heartbeat: RaftTimer,
+    pub snapshot_chunk_size: usize,
+    /// When to snapshot and compact on our own. `None` leaves it to whoever
+    /// calls `take_snapshot`.
+    pub snapshot_policy: Option<SnapshotPolicy>,
+    /// Entries applied since our latest snapshot, and the bytes of their
+    /// contents, kept as we go so checking the policy doesn't walk the log.
+    pub entries_since_snapshot: usize,
+    pub bytes_since_snapshot: usize,
+    pub storage: Box<dyn Storage>,
 }
 
 impl Default for RaftBuddy {
@@ -37,15 +108,32 @@ impl Default for RaftBuddy {
             id: RaftId(0),
             topology: Topology::from_iter([(
                 RaftId(0),
-                Rc::new(RefCell::new(RaftChannel::default())) as RcMutChannel,
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
             )]),
             timer: RaftTimer {
                 default_timeout: 100,
                 ticks_left: 100,
             },
             current_term: 0,
+            voted_for: None,
+            cluster_id: ClusterId::default(),
             log: RaftLog::default(),
             votes_received: BTreeMap::default(),
+            commit_index: 0,
+            last_applied: 0,
+            state_machine: KeyValueStore::default(),
+            applied: None,
+            snapshot: None,
+            incoming_snapshot: None,
+            progress: BTreeMap::default(),
+            blocked: BTreeMap::default(),
+            peer_health: BTreeMap::default(),
+            heartbeat: 10.into(),
+            snapshot_chunk_size: 1024,
+            snapshot_policy: Some(SnapshotPolicy::default()),
+            entries_since_snapshot: 0,
+            bytes_since_snapshot: 0,
+            storage: Box::new(MemoryStorage::default()),
         }
     }
 }
@@ -69,89 +157,768 @@ impl From<Topology> for Vec<RaftBuddy> {
 }
 
 impl RaftBuddy {
+    /// Bring a buddy back from whatever it persisted before it went away.
+    pub fn restore(id: RaftId, topology: Topology, storage: Box<dyn Storage>) -> io::Result<Self> {
+        let PersistedState {
+            hard_state,
+            log,
+            snapshot,
+        } = storage.load()?;
+
+        let mut state_machine = KeyValueStore::default();
+
+        let applied = match &snapshot {
+            Some(snapshot) => {
+                state_machine.restore(&snapshot.data)?;
+                snapshot.base.last_included_index
+            }
+            None => 0,
+        };
+
+        // The log was compacted behind a snapshot we no longer have, so
+        // there's no way to get from what we do have to where the log starts.
+        if applied < log.base().last_included_index {
+            return Err(invalid_data(&format!(
+                "log starts after index {} but the latest readable snapshot ends at {applied}",
+                log.base().last_included_index
+            )));
+        }
+
+        Ok(Self {
+            id,
+            topology,
+            current_term: hard_state.current_term,
+            voted_for: hard_state.voted_for,
+            log,
+            commit_index: applied,
+            last_applied: applied,
+            state_machine,
+            snapshot,
+            storage,
+            ..Default::default()
+        })
+    }
+
+    /// Only hold our inbox's lock long enough to take one message out, so
+    /// we're never sitting on it while pushing into someone else's.
+    fn next_message(&self) -> Option<Envelope> {
+        self.channel().lock().unwrap().pop()
+    }
+
     fn process_inbox(&mut self) {
-        let inbox = self.channel().clone();
-        let mut inbox = inbox.borrow_mut();
-
-        while let Some(message) = inbox.pop() {
-            match message {
-                Message::RequestVote(Body {
-                    id,
-                    current_term,
-                    log_length,
-                }) => match self
-                    .current_term
-                    .cmp(&current_term)
-                    .then_with(|| self.log.len().cmp(&log_length))
-                {
-                    Ordering::Less => self.reject_candidate(id),
-                    Ordering::Equal | Ordering::Greater => self.accept_candidate(id),
-                },
-                // Received a vote
-                vote @ Message::VoteForCandidate(Body {
-                    id,
-                    current_term,
-                    log_length,
-                }) => {
-                    if self.is_candidate() {
-                        self.votes_received.entry(id).or_insert(vote);
-
-                        if self.received_majority_votes() {
-                            self.become_leader();
-                        }
-                    }
-                }
-                rejection @ Message::RejectCandidateVote(Body {
-                    id,
-                    current_term,
-                    log_length,
-                }) => {
-                    match self
-                        .current_term
-                        .cmp(&current_term)
-                        .then_with(|| self.log.len().cmp(&log_length))
-                    {
-                        Ordering::Less => todo!(),
-                        Ordering::Equal => todo!(),
-                        Ordering::Greater => todo!(),
-                    }
-                }
+        while let Some(envelope) = self.next_message() {
+            self.handle(envelope);
+        }
+    }
+
+    /// Everything that's the same for every kind of message happens here,
+    /// before the payload is looked at: anything not meant for us is dropped,
+    /// a newer term makes us a follower in it, and an older one gets turned
+    /// away.
+    pub fn handle(&mut self, envelope: Envelope) {
+        let Envelope {
+            from,
+            to,
+            term,
+            cluster_id,
+            message,
+        } = envelope;
+
+        if cluster_id != self.cluster_id {
+            eprintln!(
+                "Dropping message from buddy {} of cluster {}",
+                *from, cluster_id.0
+            );
+            return;
+        }
+
+        if to != self.id || !self.topology.contains_key(&from) {
+            eprintln!("Dropping message from buddy {} to buddy {}", *from, *to);
+            return;
+        }
+
+        if term > self.current_term {
+            self.step_down(term);
+        }
+
+        if term < self.current_term {
+            // Answering is how the sender finds out it's behind. Stale
+            // responses are only of interest to a term that's over.
+            if message.is_request() {
+                self.refuse(from, &message);
+            }
+            return;
+        }
+
+        match message {
+            Message::RequestVote(body) => self.handle_request_vote(from, body),
+            vote @ (Message::VoteForCandidate | Message::RejectCandidateVote) => {
+                self.handle_vote(from, vote)
+            }
+            Message::AppendEntries(body) => self.handle_append_entries(from, body),
+            Message::AppendEntriesResponse(body) => self.handle_append_entries_response(from, body),
+            Message::InstallSnapshot(body) => self.handle_install_snapshot(from, body),
+            Message::InstallSnapshotResponse(body) => {
+                self.handle_install_snapshot_response(from, body)
             }
         }
     }
 
-    fn become_candidate(&mut self) {
-        self.role = Role::Candidate;
+    /// The negative answer to a request from a stale term.
+    fn refuse(&mut self, to: RaftId, request: &Message) {
+        let response = match request {
+            Message::RequestVote(..) => Message::RejectCandidateVote,
+            Message::AppendEntries(..) => {
+                Message::AppendEntriesResponse(AppendEntriesResponseBody {
+                    success: false,
+                    last_log_index: self.log.last_index(),
+                })
+            }
+            Message::InstallSnapshot(body) => {
+                Message::InstallSnapshotResponse(InstallSnapshotResponseBody {
+                    last_included_index: body.last_included_index,
+                    next_offset: 0,
+                    done: false,
+                    compression: Compression::preferred(),
+                })
+            }
+            _ => return,
+        };
+
+        self.send(to, response);
+    }
+
+    /// Vote for the candidate if we haven't voted for anyone else this term
+    /// and its log is at least as up to date as ours.
+    fn handle_request_vote(&mut self, candidate_id: RaftId, body: RequestVoteBody) {
+        let RequestVoteBody {
+            last_log_index,
+            last_log_term,
+        } = body;
+
+        let up_to_date =
+            (last_log_term, last_log_index) >= (self.log.last_term(), self.log.last_index());
+        let free_to_vote = self.voted_for.is_none_or(|id| id == candidate_id);
+
+        if up_to_date && free_to_vote {
+            self.voted_for = Some(candidate_id);
+            self.persist_hard_state();
+            self.timer.ticks_left = self.timer.default_timeout;
+            self.send(candidate_id, Message::VoteForCandidate);
+        } else {
+            self.send(candidate_id, Message::RejectCandidateVote);
+        }
+    }
+
+    fn handle_vote(&mut self, voter_id: RaftId, vote: Message) {
+        if !self.is_candidate() {
+            return;
+        }
+
+        self.votes_received.entry(voter_id).or_insert(vote);
+
+        if self.received_majority_votes() {
+            self.become_leader();
+        }
     }
 
-    fn follower_channels(&mut self) -> Vec<&RcMutChannel> {
+    fn handle_append_entries(&mut self, leader_id: RaftId, body: AppendEntriesBody) {
+        let AppendEntriesBody {
+            prev_log_index,
+            prev_log_term,
+            entries,
+            leader_commit,
+        } = body;
+
+        self.follow();
+
+        let success = self
+            .log
+            .append_entries(prev_log_index, prev_log_term, &entries);
+
+        let last_log_index = if success {
+            prev_log_index + entries.len()
+        } else {
+            self.log.last_index()
+        };
+
+        if success {
+            if !entries.is_empty() {
+                self.persist_log();
+            }
+
+            self.commit_index = self.commit_index.max(leader_commit.min(last_log_index));
+            self.apply_committed();
+        }
+
+        self.send(
+            leader_id,
+            Message::AppendEntriesResponse(AppendEntriesResponseBody {
+                success,
+                last_log_index,
+            }),
+        );
+    }
+
+    fn handle_append_entries_response(
+        &mut self,
+        follower_id: RaftId,
+        body: AppendEntriesResponseBody,
+    ) {
+        let AppendEntriesResponseBody {
+            success,
+            last_log_index,
+        } = body;
+
+        if !self.is_leader() {
+            return;
+        }
+
+        let Some(progress) = self.progress.get_mut(&follower_id) else {
+            return;
+        };
+
+        if success {
+            progress.match_index = progress.match_index.max(last_log_index);
+            progress.next_index = progress.match_index + 1;
+            let paused = progress.paused;
+            self.advance_commit_index();
+
+            // Hearing back means it's working through its channel, so see
+            // whether there's room again.
+            if paused {
+                self.replicate_to(follower_id);
+            }
+        } else {
+            // Back up and try again straight away rather than waiting for the
+            // next heartbeat.
+            progress.next_index = progress
+                .next_index
+                .saturating_sub(1)
+                .min(last_log_index + 1)
+                .max(1);
+            self.replicate_to(follower_id);
+        }
+    }
+
+    fn handle_install_snapshot(&mut self, leader_id: RaftId, body: InstallSnapshotBody) {
+        let InstallSnapshotBody {
+            last_included_index,
+            last_included_term,
+            offset,
+            data,
+            compression,
+            done,
+        } = body;
+
+        let respond = |buddy: &mut Self, next_offset: usize, done: bool| {
+            buddy.send(
+                leader_id,
+                Message::InstallSnapshotResponse(InstallSnapshotResponseBody {
+                    last_included_index,
+                    next_offset,
+                    done,
+                    compression: Compression::preferred(),
+                }),
+            )
+        };
+
+        self.follow();
+
+        // Everything the snapshot covers is already committed here, so
+        // there's nothing to gain from installing it.
+        if last_included_index <= self.commit_index {
+            respond(self, offset + data.len(), true);
+            return;
+        }
+
+        // A chunk came in a single frame, so it can't have been any bigger
+        // than one before it was compressed.
+        let data = match compression.decompress(&data, MAX_FRAME_LENGTH) {
+            Ok(data) => data,
+            Err(error) => {
+                eprintln!("Could not decompress snapshot chunk at offset {offset}: {error}");
+                let next_offset = self
+                    .incoming_snapshot
+                    .as_ref()
+                    .filter(|incoming| incoming.base.last_included_index == last_included_index)
+                    .map_or(0, |incoming| incoming.data.len());
+                respond(self, next_offset, false);
+                return;
+            }
+        };
+
+        let base = SnapshotBase {
+            last_included_index,
+            last_included_term,
+        };
+
+        let incoming = match &mut self.incoming_snapshot {
+            Some(incoming) if incoming.base == base => incoming,
+            incoming => incoming.insert(Snapshot { base, data: vec![] }),
+        };
+
+        let next_offset = incoming.receive(offset, &data);
+
+        if !(done && next_offset == offset + data.len()) {
+            respond(self, next_offset, false);
+            return;
+        }
+
+        let snapshot = self.incoming_snapshot.take().unwrap();
+
+        match self.install_snapshot(snapshot) {
+            Ok(()) => respond(self, next_offset, true),
+            Err(error) => {
+                eprintln!("Could not install snapshot at index {last_included_index}: {error}");
+                respond(self, 0, false);
+            }
+        }
+    }
+
+    /// Swap in the snapshot's state machine and log base together, or not at
+    /// all if the snapshot can't be restored.
+    fn install_snapshot(&mut self, snapshot: Snapshot) -> std::io::Result<()> {
+        let mut state_machine = KeyValueStore::default();
+        state_machine.restore(&snapshot.data)?;
+        self.storage
+            .save_snapshot(&snapshot, &self.configuration())?;
+
+        let SnapshotBase {
+            last_included_index,
+            last_included_term,
+        } = snapshot.base;
+
+        // If we already have the entry the snapshot ends on, whatever follows
+        // it is still good.
+        if self.log.term_at(last_included_index) == Some(last_included_term) {
+            self.log.compact(last_included_index);
+        } else {
+            self.log = RaftLog::with_base(snapshot.base);
+        }
+
+        self.state_machine = state_machine;
+        self.commit_index = self.commit_index.max(last_included_index);
+        self.last_applied = last_included_index;
+        self.snapshot = Some(snapshot);
+        self.entries_since_snapshot = 0;
+        self.bytes_since_snapshot = 0;
+        self.persist_log();
+
+        Ok(())
+    }
+
+    fn handle_install_snapshot_response(
+        &mut self,
+        follower_id: RaftId,
+        body: InstallSnapshotResponseBody,
+    ) {
+        let InstallSnapshotResponseBody {
+            last_included_index,
+            next_offset,
+            done,
+            compression,
+        } = body;
+
+        if !self.is_leader() {
+            return;
+        }
+
+        let snapshot_index = self
+            .snapshot
+            .as_ref()
+            .map(|snapshot| snapshot.base.last_included_index);
+
+        let Some(progress) = self.progress.get_mut(&follower_id) else {
+            return;
+        };
+
+        if done {
+            progress.match_index = progress.match_index.max(last_included_index);
+            progress.next_index = progress.match_index + 1;
+            progress.snapshot_offset = None;
+            self.advance_commit_index();
+            self.replicate_to(follower_id);
+        } else if snapshot_index == Some(last_included_index) {
+            progress.snapshot_offset = Some(next_offset);
+            progress.snapshot_compression = Compression::preferred().common(compression);
+
+            if self.has_room_for(follower_id) {
+                self.send_snapshot_chunk(follower_id);
+            }
+        }
+    }
+
+    /// Someone's in a newer term than us, so whatever we were doing in ours
+    /// is over, and we haven't voted in theirs yet.
+    fn step_down(&mut self, term: usize) {
+        self.current_term = term;
+        self.voted_for = None;
+        self.role = Role::Follower;
+        self.blocked.clear();
+        self.persist_hard_state();
+    }
+
+    /// Heard from the leader of our term, so there's no need for an election.
+    fn follow(&mut self) {
+        self.role = Role::Follower;
+        self.timer.ticks_left = self.timer.default_timeout;
+    }
+
+    fn configuration(&self) -> Vec<RaftId> {
+        self.topology.keys().copied().collect()
+    }
+
+    fn persist_hard_state(&mut self) {
+        let hard_state = HardState {
+            current_term: self.current_term,
+            voted_for: self.voted_for,
+        };
+
+        if let Err(error) = self.storage.save_hard_state(hard_state) {
+            eprintln!("Could not save hard state: {error}");
+        }
+    }
+
+    fn persist_log(&mut self) {
+        if let Err(error) = self.storage.save_log(&self.log) {
+            eprintln!("Could not save log: {error}");
+        }
+    }
+
+    fn peer_ids(&self) -> Vec<RaftId> {
         self.topology
-            .iter()
-            .filter_map(|(&peer_id, channel)| {
-                if (peer_id == self.id) {
-                    None
-                } else {
-                    Some(channel)
-                }
-            })
+            .keys()
+            .copied()
+            .filter(|&peer_id| peer_id != self.id)
             .collect()
     }
 
+    fn replicate(&mut self) {
+        for peer_id in self.peer_ids() {
+            self.replicate_to(peer_id);
+        }
+    }
+
+    /// Send the peer everything after the last entry we think it has, or our
+    /// snapshot if we've compacted that entry away.
+    fn replicate_to(&mut self, peer_id: RaftId) {
+        if !self.has_room_for(peer_id) {
+            return;
+        }
+
+        let Some(progress) = self.progress.get(&peer_id) else {
+            return;
+        };
+
+        let prev_log_index = progress.next_index - 1;
+
+        let Some(prev_log_term) = self.log.term_at(prev_log_index) else {
+            self.send_snapshot_chunk(peer_id);
+            return;
+        };
+
+        self.send_replication(
+            peer_id,
+            Message::AppendEntries(AppendEntriesBody {
+                prev_log_index,
+                prev_log_term,
+                entries: self.log.entries_from(prev_log_index + 1).to_vec(),
+                leader_commit: self.commit_index,
+            }),
+        );
+    }
+
+    /// Whether there's any point sending the peer more replication. Whatever
+    /// its channel blocked last time goes first, and a peer whose channel
+    /// turned us away stays paused until the channel has room again.
+    fn has_room_for(&mut self, peer_id: RaftId) -> bool {
+        if !self.unblock(peer_id) {
+            return false;
+        }
+
+        let Some(progress) = self.progress.get_mut(&peer_id) else {
+            return true;
+        };
+
+        progress.paused = progress.paused && self.topology[&peer_id].lock().unwrap().is_full();
+
+        !progress.paused
+    }
+
+    /// Replication a full channel won't take pauses replication to that peer,
+    /// rather than anything piling up for it. A rejected message is as good
+    /// as lost, and the next heartbeat makes up for it.
+    fn send_replication(&mut self, peer_id: RaftId, message: Message) {
+        let envelope = self.envelope(peer_id, message);
+        let paused = !self.push(peer_id, envelope);
+
+        if let Some(progress) = self.progress.get_mut(&peer_id) {
+            progress.paused = paused;
+        }
+    }
+
+    /// Hand the envelope to the peer's channel, after anything held for it,
+    /// and say whether the channel took it. What the channel blocks is held
+    /// for next time, so the peer still gets everything in the order it was
+    /// sent. What it rejects is lost.
+    fn push(&mut self, peer_id: RaftId, envelope: Envelope) -> bool {
+        if !self.unblock(peer_id) {
+            self.blocked.entry(peer_id).or_default().push_back(envelope);
+            return false;
+        }
+
+        let pushed = self.get_channel(peer_id).lock().unwrap().try_push(envelope);
+
+        match pushed {
+            Ok(()) => true,
+            Err(Overflow::Rejected) => false,
+            Err(Overflow::Blocked(envelope)) => {
+                self.blocked
+                    .entry(peer_id)
+                    .or_default()
+                    .push_back(*envelope);
+                false
+            }
+        }
+    }
+
+    /// Push whatever's held for the peer, oldest first, and say whether it
+    /// all went.
+    fn unblock(&mut self, peer_id: RaftId) -> bool {
+        let Some(held) = self.blocked.get_mut(&peer_id) else {
+            return true;
+        };
+
+        let channel = self.topology[&peer_id].clone();
+        let mut channel = channel.lock().unwrap();
+
+        while let Some(envelope) = held.pop_front() {
+            if let Err(Overflow::Blocked(envelope)) = channel.try_push(envelope) {
+                held.push_front(*envelope);
+                return false;
+            }
+        }
+
+        self.blocked.remove(&peer_id);
+        true
+    }
+
+    fn send_snapshot_chunk(&mut self, peer_id: RaftId) {
+        let Some(snapshot) = &self.snapshot else {
+            eprintln!(
+                "Buddy {} needs compacted entries but there's no snapshot",
+                *peer_id
+            );
+            return;
+        };
+
+        let Some(progress) = self.progress.get_mut(&peer_id) else {
+            return;
+        };
+
+        let offset = *progress.snapshot_offset.get_or_insert(0);
+        let (data, done) = snapshot.chunk(offset, self.snapshot_chunk_size);
+
+        let (compression, data) = match progress.snapshot_compression.compress(data) {
+            Ok(compressed) => (progress.snapshot_compression, compressed),
+            Err(error) => {
+                eprintln!("Could not compress snapshot chunk at offset {offset}: {error}");
+                (Compression::None, data.to_vec())
+            }
+        };
+
+        let message = Message::InstallSnapshot(InstallSnapshotBody {
+            last_included_index: snapshot.base.last_included_index,
+            last_included_term: snapshot.base.last_included_term,
+            offset,
+            data,
+            compression,
+            done,
+        });
+
+        self.send_replication(peer_id, message);
+    }
+
+    /// Commit the highest entry from this term that a majority has, which
+    /// implicitly commits everything before it too.
+    fn advance_commit_index(&mut self) {
+        let majority = (self.topology.len() / 2) + 1;
+
+        for index in (self.commit_index + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != Some(self.current_term) {
+                continue;
+            }
+
+            let replicated = 1 + self
+                .progress
+                .values()
+                .filter(|progress| progress.match_index >= index)
+                .count();
+
+            if replicated >= majority {
+                self.commit_index = index;
+                break;
+            }
+        }
+
+        self.apply_committed();
+    }
+
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            let Some(entry) = self.log.get(self.last_applied + 1) else {
+                break;
+            };
+
+            let output = self.state_machine.apply(entry);
+
+            if let Some(applied) = &mut self.applied {
+                applied.push(Applied {
+                    index: entry.index,
+                    term: entry.term,
+                    output,
+                });
+            }
+
+            self.last_applied += 1;
+            self.entries_since_snapshot += 1;
+            self.bytes_since_snapshot += entry.contents.len();
+        }
+    }
+
+    /// Append a command to the log if we're the leader, returning the index
+    /// it'll be committed at.
+    pub fn propose(&mut self, contents: impl Into<String>) -> Option<usize> {
+        if !self.is_leader() {
+            return None;
+        }
+
+        let index = self.log.last_index() + 1;
+
+        self.log.push(LogEntry {
+            term: self.current_term,
+            index,
+            contents: contents.into(),
+        });
+        self.persist_log();
+
+        // Only matters when we're the whole cluster.
+        self.advance_commit_index();
+
+        Some(index)
+    }
+
+    /// Snapshot the state machine as of the last applied entry and compact
+    /// the log up to it.
+    pub fn take_snapshot(&mut self) {
+        self.snapshot_and_compact(0);
+    }
+
+    fn snapshot_index(&self) -> usize {
+        self.snapshot
+            .as_ref()
+            .map_or(self.log.base().last_included_index, |snapshot| {
+                snapshot.base.last_included_index
+            })
+    }
+
+    fn maybe_take_snapshot(&mut self) {
+        let Some(policy) = self.snapshot_policy else {
+            return;
+        };
+
+        if policy.is_due(self.entries_since_snapshot, self.bytes_since_snapshot) {
+            self.snapshot_and_compact(policy.trailing_entries);
+        }
+    }
+
+    /// Snapshot as of the last applied entry, but leave `trailing_entries`
+    /// behind it in the log.
+    fn snapshot_and_compact(&mut self, trailing_entries: usize) {
+        if self.last_applied <= self.snapshot_index() {
+            return;
+        }
+
+        let Some(term) = self.log.term_at(self.last_applied) else {
+            return;
+        };
+
+        let snapshot = Snapshot {
+            base: SnapshotBase {
+                last_included_index: self.last_applied,
+                last_included_term: term,
+            },
+            data: self.state_machine.snapshot(),
+        };
+
+        // The log can't lose anything until the snapshot covering it is safe.
+        if let Err(error) = self.storage.save_snapshot(&snapshot, &self.configuration()) {
+            eprintln!(
+                "Could not save snapshot at index {}: {error}",
+                self.last_applied
+            );
+            return;
+        }
+
+        self.snapshot = Some(snapshot);
+        self.entries_since_snapshot = 0;
+        self.bytes_since_snapshot = 0;
+        self.log
+            .compact(self.last_applied.saturating_sub(trailing_entries));
+        self.persist_log();
+
+        // Any transfers in flight are for a snapshot we no longer have.
+        for progress in self.progress.values_mut() {
+            progress.snapshot_offset = None;
+        }
+    }
+
+    /// Start an election in a new term, voting for ourselves.
+    fn become_candidate(&mut self) {
+        self.role = Role::Candidate;
+        self.current_term += 1;
+        self.voted_for = Some(self.id);
+        self.votes_received = BTreeMap::from([(self.id, Message::VoteForCandidate)]);
+        self.persist_hard_state();
+
+        // Only happens when we're the whole cluster.
+        if self.received_majority_votes() {
+            self.become_leader();
+        }
+    }
+
     fn solicit_votes(&mut self) {
-        let id = self.id;
-        let log_length = self.log.len();
-        let term = self.current_term;
+        let body = RequestVoteBody {
+            last_log_index: self.log.last_index(),
+            last_log_term: self.log.last_term(),
+        };
 
-        for channel in self.follower_channels() {
-            channel.borrow_mut().push(Message::RequestVote(Body {
-                id,
-                current_term: term,
-                log_length,
-            }))
+        for peer_id in self.peer_ids() {
+            self.send(peer_id, Message::RequestVote(body));
         }
     }
 
-    fn get_channel(&self, id: RaftId) -> &RcMutChannel {
+    fn envelope(&self, to: RaftId, message: Message) -> Envelope {
+        Envelope {
+            from: self.id,
+            to,
+            term: self.current_term,
+            cluster_id: self.cluster_id,
+            message,
+        }
+    }
+
+    /// Votes and responses a full channel rejects are lost, same as if
+    /// they'd been lost on the way. One that blocks has them held like
+    /// replication is.
+    fn send(&mut self, to: RaftId, message: Message) {
+        let envelope = self.envelope(to, message);
+
+        self.push(to, envelope);
+    }
+
+    fn get_channel(&self, id: RaftId) -> &ArcMutChannel {
         let (peer_id, channel) = self
             .topology
             .iter()
@@ -161,7 +928,7 @@ impl RaftBuddy {
         channel
     }
 
-    pub fn channel(&self) -> &RcMutChannel {
+    pub fn channel(&self) -> &ArcMutChannel {
         let (peer_id, channel) = self
             .topology
             .iter()
@@ -171,28 +938,20 @@ impl RaftBuddy {
         channel
     }
 
-    fn accept_candidate(&self, candidate_id: RaftId) {
-        self.get_channel(candidate_id)
-            .borrow_mut()
-            .push(Message::VoteForCandidate(Body {
-                id: self.id,
-                current_term: self.current_term,
-                log_length: self.log.len(),
-            }))
-    }
-
-    fn reject_candidate(&self, candidate_id: RaftId) {
-        self.get_channel(candidate_id)
-            .borrow_mut()
-            .push(Message::RejectCandidateVote(Body {
-                id: self.id,
-                current_term: self.current_term,
-                log_length: self.log.len(),
-            }))
+    fn check_peer_health(&mut self) {
+        for peer_id in self.peer_ids() {
+            let health = self.get_channel(peer_id).lock().unwrap().health();
+            self.peer_health.insert(peer_id, health);
+        }
     }
 
-    fn has_messages(&self) -> bool {
-        !self.channel().borrow_mut().all_messages().is_empty()
+    /// Peers we've tried and failed to reach since we last got through.
+    pub fn unreachable_peers(&self) -> Vec<RaftId> {
+        self.peer_health
+            .iter()
+            .filter(|(_, health)| matches!(health, PeerHealth::Unreachable { .. }))
+            .map(|(&peer_id, _)| peer_id)
+            .collect()
     }
 
     fn is_leader(&self) -> bool {
@@ -209,28 +968,86 @@ impl RaftBuddy {
         let received: usize = self
             .votes_received
             .values()
-            .filter(|vote| matches!(vote, Message::VoteForCandidate(..)))
+            .filter(|vote| matches!(vote, Message::VoteForCandidate))
             .count();
 
         received >= majority
     }
 
+    /// What happens when whichever timer we're waiting on runs out: a leader
+    /// sends out a heartbeat, and anyone else starts an election.
+    pub fn time_out(&mut self) {
+        if self.is_leader() {
+            self.replicate();
+        } else {
+            self.become_candidate();
+
+            if self.is_candidate() {
+                self.solicit_votes();
+            }
+        }
+    }
+
     fn become_leader(&mut self) {
         self.role = Role::Leader;
+
+        let next_index = self.log.last_index() + 1;
+
+        self.progress = self
+            .peer_ids()
+            .into_iter()
+            .map(|peer_id| {
+                (
+                    peer_id,
+                    Progress {
+                        next_index,
+                        match_index: 0,
+                        snapshot_offset: None,
+                        snapshot_compression: Compression::None,
+                        paused: false,
+                    },
+                )
+            })
+            .collect();
+        self.blocked.clear();
+
+        self.replicate();
     }
 }
 
-impl Temporal for RaftBuddy {
-    fn tick(&mut self) {
+impl RaftBuddy {
+    /// A `tick`, saying whether it ran out whichever timer we were waiting on.
+    pub fn tick_timed_out(&mut self) -> bool {
         self.timer.tick();
 
-        if self.has_messages() {
-            self.process_inbox();
+        let held: Vec<RaftId> = self.blocked.keys().copied().collect();
+        for peer_id in held {
+            self.unblock(peer_id);
         }
 
-        if self.timer.ticks_left == 0 {
-            self.become_candidate();
-            self.solicit_votes();
+        self.process_inbox();
+
+        self.maybe_take_snapshot();
+
+        let timed_out = if self.is_leader() {
+            self.heartbeat.tick();
+            self.heartbeat.ticks_left == 0
+        } else {
+            self.timer.ticks_left == 0
+        };
+
+        if timed_out {
+            self.time_out();
         }
+
+        self.check_peer_health();
+
+        timed_out
+    }
+}
+
+impl Temporal for RaftBuddy {
+    fn tick(&mut self) {
+        self.tick_timed_out();
     }
 }
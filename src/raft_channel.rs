@@ -1,26 +1,268 @@
-use crate::raft_message::RaftMessage;
+use std::collections::VecDeque;
 
-#[derive(PartialEq, Eq, Clone, Default)]
+use crate::raft_message::Envelope;
+
+/// What a full channel does with one more message.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum OverflowPolicy {
+    /// Make room by dropping the oldest heartbeat waiting from the same
+    /// sender, in the same term or an older one, since anything newer from
+    /// them says everything it did. Anything else is dropped if there isn't
+    /// one.
+    DropOldestHeartbeat,
+    /// Drop the new message.
+    #[default]
+    Reject,
+    /// Hand the new message back so the sender can hold onto it and try
+    /// again once there's room.
+    Block,
+}
+
+/// Why `try_push` didn't take a message.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Overflow {
+    /// The message was dropped.
+    Rejected,
+    /// The message is handed back, to be sent again once there's room.
+    Blocked(Box<Envelope>),
+}
+
+/// Whether we can get through to a peer, as far as its channel knows.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum PeerHealth {
+    /// Nothing's been sent yet, so we haven't found out.
+    #[default]
+    Unknown,
+    Connected,
+    Unreachable {
+        failed_attempts: u32,
+    },
+}
+
+/// Unbounded unless given a `capacity`.
+#[derive(PartialEq, Eq, Clone, Default, Debug)]
 pub struct RaftChannel {
-    pub queue: Vec<RaftMessage>,
+    pub queue: VecDeque<Envelope>,
+    pub capacity: Option<usize>,
+    pub overflow: OverflowPolicy,
+}
+
+impl RaftChannel {
+    pub fn bounded(capacity: usize, overflow: OverflowPolicy) -> Self {
+        Self {
+            queue: VecDeque::with_capacity(capacity),
+            capacity: Some(capacity),
+            overflow,
+        }
+    }
 }
 
-pub trait Channel {
-    fn push(&mut self, message: RaftMessage);
-    fn pop(&mut self) -> Option<RaftMessage>;
-    fn all_messages(&mut self) -> Vec<RaftMessage>;
+/// A buddy's inbox. Channels are first in, first out: `pop` hands messages
+/// back in the order they were `push`ed, so a buddy handles messages from any
+/// one sender in the order that sender sent them.
+///
+/// Bounded channels say so through `is_full` and `try_push`. Unbounded ones
+/// can leave both alone.
+pub trait Channel: Send {
+    /// Anything a full channel won't take is dropped.
+    fn push(&mut self, envelope: Envelope);
+    fn try_push(&mut self, envelope: Envelope) -> Result<(), Overflow> {
+        self.push(envelope);
+        Ok(())
+    }
+    fn is_full(&mut self) -> bool {
+        false
+    }
+    /// In-memory channels are always connected. Network ones say whether
+    /// they are.
+    fn health(&mut self) -> PeerHealth {
+        PeerHealth::Connected
+    }
+    /// The oldest message still waiting, if any.
+    fn pop(&mut self) -> Option<Envelope>;
+    /// Everything still waiting, oldest first, without taking any of it.
+    fn all_messages(&mut self) -> Vec<Envelope>;
+    /// Throw away everything still waiting.
+    fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
 }
 
 impl Channel for RaftChannel {
-    fn push(&mut self, message: RaftMessage) {
-        todo!()
+    fn push(&mut self, envelope: Envelope) {
+        let _ = self.try_push(envelope);
     }
 
-    fn pop(&mut self) -> Option<RaftMessage> {
-        todo!()
+    fn try_push(&mut self, envelope: Envelope) -> Result<(), Overflow> {
+        if !self.is_full() {
+            self.queue.push_back(envelope);
+            return Ok(());
+        }
+
+        match self.overflow {
+            OverflowPolicy::DropOldestHeartbeat => {
+                let oldest = self.queue.iter().position(|queued| {
+                    queued.is_heartbeat()
+                        && queued.from == envelope.from
+                        && queued.term <= envelope.term
+                });
+                let Some(oldest) = oldest else {
+                    return Err(Overflow::Rejected);
+                };
+
+                self.queue.remove(oldest);
+                self.queue.push_back(envelope);
+                Ok(())
+            }
+            OverflowPolicy::Reject => Err(Overflow::Rejected),
+            OverflowPolicy::Block => Err(Overflow::Blocked(Box::new(envelope))),
+        }
     }
 
-    fn all_messages(&mut self) -> Vec<RaftMessage> {
-        todo!()
+    fn is_full(&mut self) -> bool {
+        self.capacity
+            .is_some_and(|capacity| self.queue.len() >= capacity)
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.queue.pop_front()
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        self.queue.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_message::{AppendEntriesBody, RaftMessage};
+
+    fn vote(id: usize) -> Envelope {
+        Envelope {
+            from: RaftId(id),
+            to: RaftId(0),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        }
+    }
+
+    #[test]
+    fn it_pops_in_the_order_messages_were_pushed() {
+        let mut channel = RaftChannel::default();
+
+        channel.push(vote(1));
+        channel.push(vote(2));
+        channel.push(vote(3));
+
+        assert_eq!(channel.pop(), Some(vote(1)));
+        assert_eq!(channel.pop(), Some(vote(2)));
+
+        channel.push(vote(4));
+
+        assert_eq!(channel.pop(), Some(vote(3)));
+        assert_eq!(channel.pop(), Some(vote(4)));
+        assert_eq!(channel.pop(), None);
+    }
+
+    fn heartbeat(from: usize, term: usize) -> Envelope {
+        Envelope {
+            from: RaftId(from),
+            to: RaftId(0),
+            term,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::AppendEntries(AppendEntriesBody {
+                prev_log_index: 0,
+                prev_log_term: 0,
+                entries: vec![],
+                leader_commit: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn unbounded_channels_are_never_full() {
+        let mut channel = RaftChannel::default();
+
+        for id in 0..1000 {
+            channel.push(vote(id));
+        }
+
+        assert!(!channel.is_full());
+        assert_eq!(channel.queue.len(), 1000);
+    }
+
+    #[test]
+    fn full_channels_reject() {
+        let mut channel = RaftChannel::bounded(2, OverflowPolicy::Reject);
+
+        assert_eq!(channel.try_push(vote(1)), Ok(()));
+        assert_eq!(channel.try_push(vote(2)), Ok(()));
+        assert!(channel.is_full());
+        assert_eq!(channel.try_push(vote(3)), Err(Overflow::Rejected));
+
+        channel.push(vote(4));
+
+        assert_eq!(channel.all_messages(), [vote(1), vote(2)]);
+    }
+
+    #[test]
+    fn full_channels_make_room_by_dropping_the_oldest_heartbeat() {
+        let mut channel = RaftChannel::bounded(3, OverflowPolicy::DropOldestHeartbeat);
+
+        channel.push(vote(2));
+        channel.push(heartbeat(1, 1));
+        channel.push(heartbeat(1, 1));
+
+        assert_eq!(channel.try_push(vote(1)), Ok(()));
+        assert_eq!(channel.all_messages(), [vote(2), heartbeat(1, 1), vote(1)]);
+
+        assert_eq!(channel.try_push(heartbeat(1, 2)), Ok(()));
+        assert_eq!(channel.all_messages(), [vote(2), vote(1), heartbeat(1, 2)]);
+
+        // Nothing left that's safe to drop.
+        assert_eq!(channel.try_push(vote(1)), Err(Overflow::Rejected));
+    }
+
+    #[test]
+    fn heartbeats_only_make_room_for_newer_messages_from_their_sender() {
+        let mut channel = RaftChannel::bounded(2, OverflowPolicy::DropOldestHeartbeat);
+
+        channel.push(heartbeat(1, 2));
+        channel.push(heartbeat(1, 2));
+
+        assert_eq!(channel.try_push(vote(3)), Err(Overflow::Rejected));
+        assert_eq!(channel.try_push(heartbeat(3, 2)), Err(Overflow::Rejected));
+        assert_eq!(channel.try_push(heartbeat(1, 1)), Err(Overflow::Rejected));
+        assert_eq!(channel.all_messages(), [heartbeat(1, 2), heartbeat(1, 2)]);
+    }
+
+    #[test]
+    fn full_channels_hand_back_what_they_block() {
+        let mut channel = RaftChannel::bounded(1, OverflowPolicy::Block);
+
+        channel.push(vote(1));
+
+        assert_eq!(
+            channel.try_push(vote(2)),
+            Err(Overflow::Blocked(Box::new(vote(2))))
+        );
+
+        channel.pop();
+
+        assert_eq!(channel.try_push(vote(2)), Ok(()));
+    }
+
+    #[test]
+    fn it_peeks_at_all_messages_without_taking_them() {
+        let mut channel = RaftChannel::default();
+
+        channel.push(vote(1));
+        channel.push(vote(2));
+
+        assert_eq!(channel.all_messages(), [vote(1), vote(2)]);
+        assert_eq!(channel.pop(), Some(vote(1)));
     }
 }
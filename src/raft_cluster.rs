@@ -0,0 +1,779 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
+
+use crate::raft_buddy::{RaftBuddy, Role};
+use crate::raft_id::RaftId;
+use crate::raft_invariants::{dump, InvariantChecker, Violation};
+use crate::raft_network::SimNetwork;
+use crate::raft_rng::Rng;
+use crate::raft_storage::MemoryStorage;
+use crate::raft_temporal::RaftTimer;
+use crate::raft_topology::Topology;
+use crate::raft_trace::{Event, Trace};
+
+/// A whole cluster in one thread, ticked one step at a time. Everything that
+/// could go one way or another (election timeouts, who ticks first) comes
+/// from a seeded generator, so the same seed always plays out the same way.
+///
+/// Buddies talk over a `SimNetwork`, which is perfect until told otherwise.
+/// Each keeps its durable state in a `MemoryStorage` the cluster holds on to,
+/// so it can be crashed and brought back from what it persisted.
+///
+/// The Raft safety properties are checked after every tick, and the first
+/// one that doesn't hold panics with the tick and the state of every buddy.
+/// If the cluster's `recording`, the trace of how it got there is saved too.
+#[derive(Debug)]
+pub struct Cluster {
+    pub buddies: Vec<RaftBuddy>,
+    pub topology: Topology,
+    pub network: SimNetwork,
+    /// BTreeMap<RaftId, MemoryStorage>, shared with each buddy
+    storage: BTreeMap<RaftId, MemoryStorage>,
+    /// Buddies that have crashed and haven't restarted. They sit out every
+    /// tick, and nothing sent to them arrives.
+    crashed: BTreeSet<RaftId>,
+    invariants: Option<InvariantChecker>,
+    trace: Option<Trace>,
+    rng: Rng,
+    now: u64,
+    patience: u64,
+}
+
+impl Cluster {
+    /// Election timeouts are picked from here, in ticks.
+    pub const ELECTION_TIMEOUT: Range<usize> = Range {
+        start: 100,
+        end: 200,
+    };
+
+    /// Buddies `0..size` on a simulated network.
+    pub fn new(size: usize, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let network = SimNetwork::new(rng.next_u64());
+        let topology = network.topology(size);
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let mut storage = BTreeMap::new();
+
+        for buddy in &mut buddies {
+            let memory = MemoryStorage::default();
+            buddy.storage = Box::new(memory.clone());
+            buddy.timer = RaftTimer::from(rng.range(Self::ELECTION_TIMEOUT));
+            storage.insert(buddy.id, memory);
+        }
+
+        Self {
+            buddies,
+            topology,
+            network,
+            storage,
+            crashed: BTreeSet::new(),
+            invariants: Some(InvariantChecker::default()),
+            trace: None,
+            rng,
+            now: 0,
+            patience: 10_000,
+        }
+    }
+
+    /// How many ticks the `run_until` helpers wait before giving up.
+    pub fn with_patience(self, patience: u64) -> Self {
+        Self { patience, ..self }
+    }
+
+    /// Don't check the safety properties after each tick, for scenarios
+    /// that break them on purpose.
+    pub fn without_invariant_checks(self) -> Self {
+        Self {
+            invariants: None,
+            ..self
+        }
+    }
+
+    /// Keep a `Trace` of everything that happens from here on, which can be
+    /// replayed against fresh buddies. Only what goes through the cluster is
+    /// recorded, so call it before anything's happened and leave
+    /// `buddy_mut` alone.
+    pub fn recording(self) -> Self {
+        let election_timeouts = self
+            .buddies
+            .iter()
+            .map(|buddy| buddy.timer.default_timeout)
+            .collect();
+
+        Self {
+            trace: Some(Trace::new(election_timeouts)),
+            ..self
+        }
+    }
+
+    pub fn trace(&self) -> Option<&Trace> {
+        self.trace.as_ref()
+    }
+
+    fn record(&mut self, event: Event) {
+        let Some(trace) = &mut self.trace else {
+            return;
+        };
+
+        let id = event.id();
+        let buddy = self
+            .buddies
+            .iter()
+            .find(|buddy| buddy.id == id)
+            .unwrap_or_else(|| panic!("No buddy with id {}", *id));
+
+        trace.record(event, buddy);
+    }
+
+    /// Ticks since the cluster was made.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Panics if there's no buddy with that id.
+    pub fn buddy(&self, id: RaftId) -> &RaftBuddy {
+        self.buddies
+            .iter()
+            .find(|buddy| buddy.id == id)
+            .unwrap_or_else(|| panic!("No buddy with id {}", *id))
+    }
+
+    /// Panics if there's no buddy with that id.
+    pub fn buddy_mut(&mut self, id: RaftId) -> &mut RaftBuddy {
+        self.buddies
+            .iter_mut()
+            .find(|buddy| buddy.id == id)
+            .unwrap_or_else(|| panic!("No buddy with id {}", *id))
+    }
+
+    /// The leader with the newest term. An older leader can hang on after
+    /// losing touch with the rest, but it can't get anything committed.
+    /// Crashed buddies don't count.
+    pub fn leader(&self) -> Option<RaftId> {
+        self.live_buddies()
+            .filter(|buddy| buddy.role == Role::Leader)
+            .max_by_key(|buddy| buddy.current_term)
+            .map(|buddy| buddy.id)
+    }
+
+    /// Every buddy still up that thinks it's leader, in any term.
+    pub fn leaders(&self) -> Vec<RaftId> {
+        self.live_buddies()
+            .filter(|buddy| buddy.role == Role::Leader)
+            .map(|buddy| buddy.id)
+            .collect()
+    }
+
+    fn live_buddies(&self) -> impl Iterator<Item = &RaftBuddy> {
+        self.buddies
+            .iter()
+            .filter(|buddy| !self.crashed.contains(&buddy.id))
+    }
+
+    fn ids(&self) -> Vec<RaftId> {
+        self.buddies.iter().map(|buddy| buddy.id).collect()
+    }
+
+    /// Cut `id` off from everyone else, both ways.
+    pub fn isolate(&mut self, id: RaftId) {
+        for peer_id in self.ids() {
+            if peer_id != id {
+                self.network.cut(id, peer_id);
+                self.network.cut(peer_id, id);
+            }
+        }
+    }
+
+    /// Partition the cluster so buddies only hear from those in their own
+    /// group. Anyone left out of every group is on their own.
+    pub fn split(&mut self, groups: &[&[RaftId]]) {
+        let group_of = |id: RaftId| groups.iter().position(|group| group.contains(&id));
+
+        for from in self.ids() {
+            for to in self.ids() {
+                if from != to && (group_of(from).is_none() || group_of(from) != group_of(to)) {
+                    self.network.cut(from, to);
+                }
+            }
+        }
+    }
+
+    /// Lose everything `from` sends `to`, while the other way still works.
+    pub fn cut_one_way(&mut self, from: RaftId, to: RaftId) {
+        self.network.cut(from, to);
+    }
+
+    /// Undo every partition.
+    pub fn heal(&mut self) {
+        self.network.heal();
+    }
+
+    /// Stop `id` where it is. Everything it hadn't persisted is gone, along
+    /// with whatever was waiting in its inbox, and anything sent to it until
+    /// it restarts is lost.
+    pub fn crash(&mut self, id: RaftId) {
+        self.crashed.insert(id);
+        self.network.crash(id);
+        self.topology[&id].lock().unwrap().clear();
+        self.record(Event::Crash(id));
+    }
+
+    /// Bring a crashed buddy back from its storage, with a fresh election
+    /// timeout and nothing else it had before.
+    pub fn restart(&mut self, id: RaftId) {
+        assert!(self.crashed.remove(&id), "Buddy {} isn't crashed", *id);
+
+        let storage = Box::new(self.storage[&id].clone());
+        let election_timeout = self.rng.range(Self::ELECTION_TIMEOUT);
+        let mut buddy = RaftBuddy::restore(id, self.topology.clone(), storage)
+            .expect("Memory storage can't fail to load");
+        buddy.timer = RaftTimer::from(election_timeout);
+
+        self.network.restart(id);
+        *self.buddy_mut(id) = buddy;
+        self.record(Event::Restart {
+            id,
+            election_timeout,
+        });
+    }
+
+    pub fn is_crashed(&self, id: RaftId) -> bool {
+        self.crashed.contains(&id)
+    }
+
+    /// Tick every buddy that's up once, in an order shuffled each time. Messages go
+    /// straight into inboxes, so whoever ticks later sees what earlier
+    /// buddies just sent.
+    pub fn tick(&mut self) {
+        self.now += 1;
+        self.network.advance();
+
+        let mut order: Vec<usize> = (0..self.buddies.len()).collect();
+        for i in (1..order.len()).rev() {
+            order.swap(i, self.rng.range(0..i + 1));
+        }
+
+        for i in order {
+            let id = self.buddies[i].id;
+
+            if self.crashed.contains(&id) {
+                continue;
+            }
+
+            let delivered = match self.trace {
+                Some(_) => self.topology[&id].lock().unwrap().all_messages(),
+                None => vec![],
+            };
+            let timed_out = self.buddies[i].tick_timed_out();
+
+            self.record(Event::Tick {
+                id,
+                delivered,
+                timed_out,
+            });
+        }
+
+        if let Err(violation) = self.check_invariants() {
+            let saved = self.trace.as_ref().map(|trace| {
+                let path = std::env::temp_dir().join(format!(
+                    "raft-{}-tick-{}.trace",
+                    std::process::id(),
+                    self.now
+                ));
+
+                match trace.save(&path) {
+                    Ok(()) => format!("\nTrace saved to {}", path.display()),
+                    Err(error) => format!("\nCould not save trace to {}: {error}", path.display()),
+                }
+            });
+
+            panic!(
+                "Invariant violated at tick {}: {violation}\n{}{}",
+                self.now,
+                dump(&self.buddies),
+                saved.unwrap_or_default()
+            );
+        }
+    }
+
+    /// Check the safety properties against every buddy that's up. Crashed
+    /// buddies were checked up until they went down.
+    pub fn check_invariants(&mut self) -> Result<(), Violation> {
+        let Some(checker) = &mut self.invariants else {
+            return Ok(());
+        };
+
+        checker.check(
+            self.buddies
+                .iter()
+                .filter(|buddy| !self.crashed.contains(&buddy.id)),
+        )
+    }
+
+    pub fn run(&mut self, ticks: u64) {
+        for _ in 0..ticks {
+            self.tick();
+        }
+    }
+
+    /// Tick until `condition` holds, giving up after our patience runs out.
+    /// Returns whether it ever held.
+    pub fn run_until(&mut self, mut condition: impl FnMut(&Cluster) -> bool) -> bool {
+        for _ in 0..self.patience {
+            if condition(self) {
+                return true;
+            }
+
+            self.tick();
+        }
+
+        condition(self)
+    }
+
+    pub fn run_until_leader(&mut self) -> Option<RaftId> {
+        self.run_until(|cluster| cluster.leader().is_some());
+        self.leader()
+    }
+
+    /// Hand `contents` to buddy `id`, returning the index it'll be committed
+    /// at if it's leader.
+    pub fn propose(&mut self, id: RaftId, contents: impl Into<String>) -> Option<usize> {
+        let contents = contents.into();
+        let index = self.buddy_mut(id).propose(contents.clone());
+
+        self.record(Event::Propose { id, contents });
+
+        index
+    }
+
+    /// Hand `contents` to the leader, electing one first if need be, and
+    /// tick until it's committed. Returns the index it was committed at, or
+    /// `None` if the leader lost it to a newer one or it took too long.
+    pub fn propose_and_commit(&mut self, contents: impl Into<String>) -> Option<usize> {
+        let leader = self.run_until_leader()?;
+        let term = self.buddy(leader).current_term;
+        let index = self.propose(leader, contents)?;
+
+        let committed = |cluster: &Cluster| {
+            cluster
+                .buddies
+                .iter()
+                .any(|buddy| buddy.commit_index >= index && buddy.log.term_at(index) == Some(term))
+        };
+        let lost = |cluster: &Cluster| {
+            cluster
+                .buddies
+                .iter()
+                .any(|buddy| buddy.commit_index >= index && buddy.log.term_at(index) != Some(term))
+        };
+
+        self.run_until(|cluster| committed(cluster) || lost(cluster));
+
+        committed(self).then_some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_network::Faults;
+    use crate::raft_temporal::Temporal;
+    use std::panic::AssertUnwindSafe;
+
+    #[test]
+    fn it_elects_a_leader() {
+        let mut cluster = Cluster::new(5, 1);
+
+        let leader = cluster.run_until_leader().unwrap();
+
+        assert_eq!(cluster.buddy(leader).role, Role::Leader);
+        assert!(cluster
+            .buddies
+            .iter()
+            .filter(|buddy| buddy.id != leader)
+            .all(|buddy| buddy.role == Role::Follower));
+    }
+
+    #[test]
+    fn the_same_seed_plays_out_the_same_way() {
+        let history = |seed| {
+            let mut cluster = Cluster::new(5, seed);
+            let leader = cluster.run_until_leader();
+            let timeouts: Vec<_> = cluster
+                .buddies
+                .iter()
+                .map(|buddy| buddy.timer.default_timeout)
+                .collect();
+
+            (leader, cluster.now(), timeouts)
+        };
+
+        assert_eq!(history(7), history(7));
+        assert_ne!(history(7), history(8));
+    }
+
+    #[test]
+    fn proposals_are_committed_and_applied_everywhere() {
+        let mut cluster = Cluster::new(3, 3);
+
+        assert_eq!(cluster.propose_and_commit("set x 1"), Some(1));
+        assert_eq!(cluster.propose_and_commit("set y 2"), Some(2));
+
+        assert!(cluster.run_until(|cluster| {
+            cluster
+                .buddies
+                .iter()
+                .all(|buddy| buddy.state_machine.get("y") == Some(&"2".to_owned()))
+        }));
+    }
+
+    #[test]
+    fn it_makes_progress_over_a_bad_network() {
+        let mut cluster = Cluster::new(5, 5);
+
+        cluster.network.set_faults(Faults {
+            drop: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+            delay: 0.2,
+            max_delay: 5,
+        });
+
+        for i in 0..5 {
+            assert!(cluster.propose_and_commit(format!("set x {i}")).is_some());
+        }
+
+        let stats = cluster.network.stats();
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0);
+        assert!(stats.delayed > 0);
+    }
+
+    /// Whether every buddy has every one of `expected`'s keys set to its
+    /// value.
+    fn applied_everywhere(cluster: &Cluster, expected: &[(&str, &str)]) -> bool {
+        cluster.buddies.iter().all(|buddy| {
+            expected.iter().all(|&(key, value)| {
+                buddy.state_machine.get(key).map(String::as_str) == Some(value)
+            })
+        })
+    }
+
+    /// Heal, then wait for things to settle on one leader with every
+    /// committed key set to its value everywhere.
+    fn heal_and_settle(cluster: &mut Cluster, committed: &[(&str, &str)]) {
+        cluster.heal();
+
+        assert!(cluster.run_until(|cluster| {
+            cluster.leaders().len() == 1 && applied_everywhere(cluster, committed)
+        }));
+    }
+
+    #[test]
+    fn an_isolated_leader_is_replaced_and_steps_down_on_healing() {
+        let mut cluster = Cluster::new(5, 11);
+        let old = cluster.run_until_leader().unwrap();
+
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+
+        cluster.isolate(old);
+        assert!(cluster.run_until(|cluster| cluster.leader().is_some_and(|id| id != old)));
+
+        // It still thinks it's leader, but nothing it's given gets anywhere.
+        assert_eq!(cluster.buddy(old).role, Role::Leader);
+        cluster.buddy_mut(old).propose("set lost 1");
+
+        assert!(cluster.propose_and_commit("set b 2").is_some());
+
+        heal_and_settle(&mut cluster, &[("a", "1"), ("b", "2")]);
+
+        assert_ne!(cluster.leader(), Some(old));
+        assert!(cluster
+            .buddies
+            .iter()
+            .all(|buddy| buddy.state_machine.get("lost").is_none()));
+    }
+
+    #[test]
+    fn only_the_majority_side_of_a_split_makes_progress() {
+        let mut cluster = Cluster::new(5, 12);
+        let leader = cluster.run_until_leader().unwrap();
+
+        // The leader's stuck with one other buddy on the minority side.
+        let others: Vec<_> = cluster
+            .ids()
+            .into_iter()
+            .filter(|&id| id != leader)
+            .collect();
+        let minority = [leader, others[0]];
+        let majority = &others[1..];
+        cluster.split(&[&minority, majority]);
+
+        cluster.buddy_mut(leader).propose("set lost 1");
+        assert!(cluster
+            .run_until(|cluster| { cluster.leader().is_some_and(|id| majority.contains(&id)) }));
+
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+        assert!(cluster
+            .buddies
+            .iter()
+            .filter(|buddy| minority.contains(&buddy.id))
+            .all(|buddy| buddy.commit_index == 0));
+
+        heal_and_settle(&mut cluster, &[("a", "1")]);
+
+        assert!(cluster
+            .buddies
+            .iter()
+            .all(|buddy| buddy.state_machine.get("lost").is_none()));
+    }
+
+    #[test]
+    fn commits_keep_coming_while_one_follower_cannot_answer() {
+        let mut cluster = Cluster::new(3, 13);
+        let leader = cluster.run_until_leader().unwrap();
+        let follower = RaftId((*leader + 1) % 3);
+
+        // It hears the leader, but the leader never hears back from it.
+        cluster.cut_one_way(follower, leader);
+
+        let keys: Vec<_> = (0..5).map(|i| format!("k{i}")).collect();
+        for key in &keys {
+            assert!(cluster.propose_and_commit(format!("set {key} 1")).is_some());
+        }
+
+        let committed: Vec<_> = keys.iter().map(|key| (key.as_str(), "1")).collect();
+        heal_and_settle(&mut cluster, &committed);
+    }
+
+    #[test]
+    fn a_follower_that_cannot_hear_the_leader_does_not_lose_commits() {
+        let mut cluster = Cluster::new(3, 14);
+        let leader = cluster.run_until_leader().unwrap();
+        let follower = RaftId((*leader + 1) % 3);
+
+        // It'll keep calling elections the others won't let it win.
+        cluster.cut_one_way(leader, follower);
+
+        // Its elections never get anywhere, so none of these are lost to
+        // a change of leader.
+        let keys: Vec<_> = (0..5).map(|i| format!("k{i}")).collect();
+        for key in &keys {
+            assert!(cluster.propose_and_commit(format!("set {key} 1")).is_some());
+        }
+
+        let committed: Vec<_> = keys.iter().map(|key| (key.as_str(), "1")).collect();
+        heal_and_settle(&mut cluster, &committed);
+    }
+
+    #[test]
+    fn a_restarted_buddy_keeps_its_term_and_vote() {
+        let mut cluster = Cluster::new(3, 21);
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+
+        let follower = cluster
+            .ids()
+            .into_iter()
+            .find(|&id| Some(id) != cluster.leader())
+            .unwrap();
+        let before = cluster.buddy(follower);
+        let (term, voted_for, last_index) = (
+            before.current_term,
+            before.voted_for,
+            before.log.last_index(),
+        );
+
+        cluster.crash(follower);
+        assert!(cluster.is_crashed(follower));
+        cluster.restart(follower);
+
+        let after = cluster.buddy(follower);
+        assert_eq!(after.role, Role::Follower);
+        assert_eq!(after.current_term, term);
+        assert_eq!(after.voted_for, voted_for);
+        assert_eq!(after.log.last_index(), last_index);
+        // It has to hear the commit index again before applying anything.
+        assert_eq!(after.commit_index, 0);
+        assert_eq!(after.state_machine.get("a"), None);
+
+        heal_and_settle(&mut cluster, &[("a", "1")]);
+    }
+
+    #[test]
+    fn a_crashed_leader_is_replaced_and_catches_up_after_restarting() {
+        let mut cluster = Cluster::new(5, 22);
+        let old = cluster.run_until_leader().unwrap();
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+
+        cluster.crash(old);
+        assert_eq!(cluster.leader(), None);
+
+        assert!(cluster.propose_and_commit("set b 2").is_some());
+        assert_ne!(cluster.leader(), Some(old));
+
+        cluster.restart(old);
+        heal_and_settle(&mut cluster, &[("a", "1"), ("b", "2")]);
+    }
+
+    #[test]
+    fn candidates_restarted_mid_election_do_not_split_the_vote() {
+        let mut cluster = Cluster::new(3, 23);
+
+        assert!(cluster.run_until(|cluster| {
+            cluster
+                .buddies
+                .iter()
+                .any(|buddy| buddy.role == Role::Candidate)
+        }));
+        let candidate = cluster
+            .buddies
+            .iter()
+            .find(|buddy| buddy.role == Role::Candidate)
+            .unwrap();
+        let (id, term) = (candidate.id, candidate.current_term);
+
+        // Down before any of the votes it asked for get back to it.
+        cluster.crash(id);
+        cluster.restart(id);
+
+        let restarted = cluster.buddy(id);
+        assert_eq!(restarted.current_term, term);
+        assert_eq!(restarted.voted_for, Some(id));
+
+        assert!(cluster.run_until_leader().is_some());
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+        heal_and_settle(&mut cluster, &[("a", "1")]);
+    }
+
+    #[test]
+    fn voters_restarted_mid_election_do_not_vote_twice() {
+        let mut cluster = Cluster::new(5, 24);
+
+        // Stop as soon as anyone has voted for someone other than themselves.
+        let voted = |cluster: &Cluster| {
+            cluster
+                .buddies
+                .iter()
+                .find(|buddy| buddy.voted_for.is_some_and(|id| id != buddy.id))
+                .map(|buddy| (buddy.id, buddy.current_term, buddy.voted_for))
+        };
+        assert!(cluster.run_until(|cluster| voted(cluster).is_some()));
+        let (voter, term, voted_for) = voted(&cluster).unwrap();
+
+        cluster.crash(voter);
+        cluster.restart(voter);
+
+        let restarted = cluster.buddy(voter);
+        assert_eq!(
+            (restarted.current_term, restarted.voted_for),
+            (term, voted_for)
+        );
+
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+        heal_and_settle(&mut cluster, &[("a", "1")]);
+    }
+
+    #[test]
+    fn followers_restarted_mid_replication_lose_no_commits() {
+        let mut cluster = Cluster::new(5, 25);
+        let leader = cluster.run_until_leader().unwrap();
+        let followers: Vec<_> = cluster
+            .ids()
+            .into_iter()
+            .filter(|&id| id != leader)
+            .collect();
+        let keys: Vec<_> = (0..followers.len())
+            .flat_map(|i| [format!("k{i}"), format!("after{i}")])
+            .collect();
+
+        for (i, &follower) in followers.iter().enumerate() {
+            // The entry's on its way when the follower goes down, so it has
+            // to be sent again once it's back.
+            cluster.buddy_mut(leader).propose(format!("set k{i} 1"));
+            cluster.crash(follower);
+            cluster.run(3);
+            cluster.restart(follower);
+
+            assert!(cluster
+                .propose_and_commit(format!("set after{i} 1"))
+                .is_some());
+        }
+
+        let committed: Vec<_> = keys.iter().map(|key| (key.as_str(), "1")).collect();
+        heal_and_settle(&mut cluster, &committed);
+    }
+
+    #[test]
+    fn a_crashed_buddy_loses_what_was_waiting_for_it() {
+        let mut cluster = Cluster::new(3, 26);
+        let leader = cluster.run_until_leader().unwrap();
+        let follower = RaftId((*leader + 1) % 3);
+
+        cluster.buddy_mut(leader).propose("set a 1");
+        cluster.buddy_mut(leader).heartbeat.ticks_left = 1;
+        cluster.buddy_mut(leader).tick();
+        assert!(!cluster.topology[&follower]
+            .lock()
+            .unwrap()
+            .all_messages()
+            .is_empty());
+
+        cluster.crash(follower);
+
+        assert!(cluster.topology[&follower]
+            .lock()
+            .unwrap()
+            .all_messages()
+            .is_empty());
+        assert!(cluster.network.is_down(follower));
+    }
+
+    #[test]
+    #[should_panic(expected = "Invariant violated at tick")]
+    fn a_second_leader_in_the_same_term_fails_the_next_tick() {
+        let mut cluster = Cluster::new(3, 27);
+        let leader = cluster.run_until_leader().unwrap();
+        let term = cluster.buddy(leader).current_term;
+
+        let usurper = cluster.buddy_mut(RaftId((*leader + 1) % 3));
+        usurper.role = Role::Leader;
+        usurper.current_term = term;
+
+        cluster.tick();
+    }
+
+    #[test]
+    fn a_recording_cluster_saves_its_trace_when_an_invariant_fails() {
+        let mut cluster = Cluster::new(3, 27).recording();
+        let leader = cluster.run_until_leader().unwrap();
+        let term = cluster.buddy(leader).current_term;
+
+        let usurper = cluster.buddy_mut(RaftId((*leader + 1) % 3));
+        usurper.role = Role::Leader;
+        usurper.current_term = term;
+
+        let panic = std::panic::catch_unwind(AssertUnwindSafe(|| cluster.tick())).unwrap_err();
+        let message = panic.downcast_ref::<String>().unwrap();
+        let (_, path) = message.split_once("Trace saved to ").unwrap();
+
+        let saved = Trace::load(path);
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(saved.unwrap(), *cluster.trace().unwrap());
+    }
+
+    #[test]
+    fn the_newest_leader_is_the_leader() {
+        let mut cluster = Cluster::new(3, 4);
+        cluster.run_until_leader();
+
+        let stale = cluster.leader().unwrap();
+        let newer = RaftId((*stale + 1) % 3);
+        let term = cluster.buddy(stale).current_term;
+
+        let buddy = cluster.buddy_mut(newer);
+        buddy.role = Role::Leader;
+        buddy.current_term = term + 1;
+
+        assert_eq!(cluster.leader(), Some(newer));
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt::Debug;
+use std::io;
+
+use crate::raft_encoding::{self, invalid_data};
+use crate::raft_log::RaftLog;
+use crate::raft_message::Envelope;
+
+/// How envelopes and logs are turned into bytes, for the transports and for
+/// `DiskStorage`. Each codec has an id that's written alongside whatever it
+/// encodes, so the reading side can pick the right codec without being told.
+pub trait Codec: Debug + Send + Sync {
+    fn id(&self) -> u8;
+    fn encode_envelope(&self, envelope: &Envelope) -> Vec<u8>;
+    fn decode_envelope(&self, bytes: &[u8]) -> io::Result<Envelope>;
+    fn encode_log(&self, log: &RaftLog) -> Vec<u8>;
+    fn decode_log(&self, bytes: &[u8]) -> io::Result<RaftLog>;
+}
+
+/// The compact hand-rolled format from `raft_encoding`. Always available,
+/// and what everything uses unless told otherwise.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BinaryCodec;
+
+impl Codec for BinaryCodec {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn encode_envelope(&self, envelope: &Envelope) -> Vec<u8> {
+        raft_encoding::encode_envelope(envelope)
+    }
+
+    fn decode_envelope(&self, bytes: &[u8]) -> io::Result<Envelope> {
+        raft_encoding::decode_envelope(bytes)
+    }
+
+    fn encode_log(&self, log: &RaftLog) -> Vec<u8> {
+        raft_encoding::encode_log(log)
+    }
+
+    fn decode_log(&self, bytes: &[u8]) -> io::Result<RaftLog> {
+        raft_encoding::decode_log(bytes)
+    }
+}
+
+/// Readable, and much bigger. Handy when you want to see what's going over
+/// the wire or onto disk.
+#[cfg(feature = "serde")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonCodec;
+
+#[cfg(feature = "serde")]
+impl Codec for JsonCodec {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn encode_envelope(&self, envelope: &Envelope) -> Vec<u8> {
+        serde_json::to_vec(envelope).expect("Envelopes always serialize")
+    }
+
+    fn decode_envelope(&self, bytes: &[u8]) -> io::Result<Envelope> {
+        serde_json::from_slice(bytes).map_err(|error| invalid_data(&error.to_string()))
+    }
+
+    fn encode_log(&self, log: &RaftLog) -> Vec<u8> {
+        serde_json::to_vec(log).expect("Logs always serialize")
+    }
+
+    fn decode_log(&self, bytes: &[u8]) -> io::Result<RaftLog> {
+        let log: RaftLog =
+            serde_json::from_slice(bytes).map_err(|error| invalid_data(&error.to_string()))?;
+
+        if !log.is_well_formed() {
+            return Err(invalid_data("log entries aren't contiguous"));
+        }
+
+        Ok(log)
+    }
+}
+
+/// The codec that wrote the id, if this build has it.
+pub fn codec_for(id: u8) -> io::Result<&'static dyn Codec> {
+    match id {
+        0 => Ok(&BinaryCodec),
+        #[cfg(feature = "serde")]
+        1 => Ok(&JsonCodec),
+        _ => Err(invalid_data(&format!(
+            "codec {id} isn't available in this build"
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_log::{LogEntry, SnapshotBase};
+    use crate::raft_message::{AppendEntriesBody, RaftMessage};
+
+    fn codecs() -> Vec<&'static dyn Codec> {
+        [0, 1]
+            .into_iter()
+            .filter_map(|id| codec_for(id).ok())
+            .collect()
+    }
+
+    fn entry(index: usize) -> LogEntry {
+        LogEntry {
+            term: 2,
+            index,
+            contents: format!("set x {index}"),
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips_envelopes() {
+        let envelope = Envelope {
+            from: RaftId(0),
+            to: RaftId(1),
+            term: 2,
+            cluster_id: ClusterId(7),
+            message: RaftMessage::AppendEntries(AppendEntriesBody {
+                prev_log_index: 4,
+                prev_log_term: 1,
+                entries: vec![entry(5)],
+                leader_commit: 4,
+            }),
+        };
+
+        for codec in codecs() {
+            let decoded = codec.decode_envelope(&codec.encode_envelope(&envelope));
+
+            assert_eq!(decoded.unwrap(), envelope, "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn every_codec_round_trips_logs() {
+        let mut log = RaftLog::with_base(SnapshotBase {
+            last_included_index: 4,
+            last_included_term: 1,
+        });
+        log.push(entry(5));
+        log.push(entry(6));
+
+        for codec in codecs() {
+            let decoded = codec.decode_log(&codec.encode_log(&log)).unwrap();
+
+            assert_eq!(decoded.base(), log.base(), "{codec:?}");
+            assert!(decoded.iter().eq(log.iter()), "{codec:?}");
+        }
+    }
+
+    #[test]
+    fn codecs_are_found_by_their_own_id() {
+        for codec in codecs() {
+            assert_eq!(codec_for(codec.id()).unwrap().id(), codec.id());
+        }
+
+        assert!(codec_for(42).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn json_codec_refuses_logs_with_gaps() {
+        let mut log = RaftLog::default();
+        log.push(entry(1));
+        log.push(entry(3));
+
+        assert!(JsonCodec.decode_log(&JsonCodec.encode_log(&log)).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+use std::io;
+
+use crate::raft_encoding::invalid_data;
+
+/// How a blob of bytes was compressed. Every compressed thing we write says
+/// which of these it used, so data written by a build with the `compression`
+/// feature can sit alongside data written without it.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+impl Compression {
+    /// Deflate if we were built with the `compression` feature, otherwise
+    /// nothing.
+    pub fn preferred() -> Self {
+        if cfg!(feature = "compression") {
+            Compression::Deflate
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn tag(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            Compression::Deflate => 1,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> io::Result<Self> {
+        match tag {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(invalid_data("unknown compression codec")),
+        }
+    }
+
+    /// The best codec two sides can both read, given the best each can.
+    pub fn common(self, other: Self) -> Self {
+        if self == other {
+            self
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Fails if this build can't use the codec.
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(bytes.to_vec()),
+            Compression::Deflate => deflate::compress(bytes),
+        }
+    }
+
+    /// Fails rather than give back more than `limit` bytes, so a small
+    /// message can't inflate into something that eats all our memory.
+    pub fn decompress(self, bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let decompressed = match self {
+            Compression::None => bytes.to_vec(),
+            Compression::Deflate => deflate::decompress(bytes, limit)?,
+        };
+
+        if decompressed.len() > limit {
+            return Err(invalid_data("decompressed data is over the size limit"));
+        }
+
+        Ok(decompressed)
+    }
+}
+
+/// Compress with the preferred codec and put its tag in front.
+pub fn pack(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    let compression = Compression::preferred();
+    let mut packed = vec![compression.tag()];
+
+    packed.extend(compression.compress(bytes)?);
+
+    Ok(packed)
+}
+
+/// Undo `pack`, whichever codec it happened to use, as long as that comes
+/// to no more than `limit` bytes.
+pub fn unpack(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    let (&tag, rest) = bytes
+        .split_first()
+        .ok_or_else(|| invalid_data("missing compression codec"))?;
+
+    Compression::from_tag(tag)?.decompress(rest, limit)
+}
+
+#[cfg(feature = "compression")]
+mod deflate {
+    use std::io::{self, Read, Write};
+
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+
+    pub fn compress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(vec![], flate2::Compression::default());
+
+        encoder.write_all(bytes)?;
+        encoder.finish()
+    }
+
+    /// Stops one byte past `limit`, which is enough for the caller to tell
+    /// it's been exceeded.
+    pub fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        let mut decompressed = vec![];
+        DeflateDecoder::new(bytes)
+            .take((limit as u64).saturating_add(1))
+            .read_to_end(&mut decompressed)?;
+
+        Ok(decompressed)
+    }
+}
+
+#[cfg(not(feature = "compression"))]
+mod deflate {
+    use std::io;
+
+    pub fn compress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "can't deflate without the `compression` feature",
+        ))
+    }
+
+    pub fn decompress(bytes: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "data is deflated, but this build doesn't have the `compression` feature",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_with_the_preferred_codec() {
+        let bytes = "set x 42\n".repeat(100).into_bytes();
+
+        assert_eq!(unpack(&pack(&bytes).unwrap(), usize::MAX).unwrap(), bytes);
+    }
+
+    #[test]
+    fn it_reads_uncompressed_data_whatever_the_build() {
+        let mut packed = vec![Compression::None.tag()];
+        packed.extend_from_slice(b"set x 42");
+
+        assert_eq!(unpack(&packed, usize::MAX).unwrap(), b"set x 42");
+    }
+
+    #[test]
+    fn it_rejects_unknown_codecs() {
+        assert!(unpack(&[7, 1, 2, 3], usize::MAX).is_err());
+        assert!(unpack(&[], usize::MAX).is_err());
+    }
+
+    #[test]
+    fn it_refuses_to_go_over_the_limit() {
+        let bytes = vec![0; 1024];
+        let packed = pack(&bytes).unwrap();
+
+        assert_eq!(unpack(&packed, 1024).unwrap(), bytes);
+        assert!(unpack(&packed, 1023).is_err());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn it_shrinks_repetitive_commands() {
+        let bytes = "set x 42\n".repeat(100).into_bytes();
+
+        assert!(Compression::Deflate.compress(&bytes).unwrap().len() < bytes.len() / 4);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn it_explains_why_it_cannot_inflate() {
+        let error = Compression::Deflate
+            .decompress(b"whatever", usize::MAX)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[cfg(not(feature = "compression"))]
+    #[test]
+    fn it_explains_why_it_cannot_deflate() {
+        let error = Compression::Deflate.compress(b"whatever").unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+}
@@ -0,0 +1,814 @@
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::io::{self, BufWriter, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::raft_auth::ClusterKeys;
+use crate::raft_channel::{Channel, Overflow, OverflowPolicy, PeerHealth, RaftChannel};
+use crate::raft_codec::{BinaryCodec, Codec};
+use crate::raft_frame::write_envelope;
+use crate::raft_message::Envelope;
+use crate::raft_rng::Rng;
+
+/// Something we can open a stream to, like a socket address.
+pub trait Connect: Clone + Debug + Send + 'static {
+    type Stream: Write + Debug + Send;
+
+    fn connect(&self) -> io::Result<Self::Stream>;
+}
+
+/// How long to wait before trying a peer again. The wait doubles with each
+/// failed attempt up to `max`, and is jittered between half and all of that
+/// so buddies that lost the same peer don't all come knocking at once.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(50),
+            max: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Backoff {
+    pub fn delay(&self, failed_attempts: u32, rng: &mut Rng) -> Duration {
+        let doublings = failed_attempts.saturating_sub(1).min(31);
+        let ceiling = self.initial.saturating_mul(1 << doublings).min(self.max);
+
+        ceiling / 2 + (ceiling / 2).mul_f64(rng.unit())
+    }
+}
+
+/// What happens to messages sent while a peer is unreachable.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum DisconnectedPolicy {
+    /// Lose them, same as a lossy network would. Raft sends everything that
+    /// matters again anyway.
+    Drop,
+    /// Keep up to `capacity` of the most recent ones to send once we're
+    /// back, dropping the oldest to make room.
+    Buffer { capacity: usize },
+}
+
+impl Default for DisconnectedPolicy {
+    fn default() -> Self {
+        Self::Buffer { capacity: 64 }
+    }
+}
+
+/// How many messages a network channel holds by default before it counts
+/// as full. A peer that's keeping up never has anywhere near this many
+/// waiting.
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// How long a write can stall before we give up on the stream and count the
+/// peer as unreachable. `Connect` implementations set it on what they open,
+/// so a peer that stops reading can't hold up its connection for good.
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Somebody else's inbox, reached over a stream that's reopened whenever it
+/// breaks. There's never anything to pop.
+///
+/// Pushing only hands the envelope to a thread of the connection's own,
+/// which does the connecting and writing, so a peer that's slow to answer
+/// never holds up whoever's pushing. The thread starts on the first push and
+/// finishes once the connection's dropped.
+///
+/// What's been pushed waits in a bounded outbox until the thread gets to it.
+/// Once a slow peer lets that fill up, the connection's full and pushing
+/// goes by its `OverflowPolicy`, same as a bounded `RaftChannel`.
+///
+/// The thread also tries the peer again on its own once the backoff is up,
+/// so whatever's been held for it goes out as soon as it's back rather than
+/// with the next message.
+#[derive(Debug)]
+pub struct Connection<C: Connect> {
+    target: C,
+    codec: &'static dyn Codec,
+    keys: Option<Arc<ClusterKeys>>,
+    backoff: Backoff,
+    policy: DisconnectedPolicy,
+    /// Whether the writer thread's been started yet.
+    started: bool,
+    outbox: OutboxHandle,
+}
+
+/// What a connection and its writer thread share.
+#[derive(Debug, Default)]
+struct Shared {
+    outbox: Mutex<Outbox>,
+    /// Woken whenever there's something new for the writer to do.
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Outbox {
+    /// Pushed, and not yet picked up by the writer.
+    queue: RaftChannel,
+    /// Drop the stream before writing anything else.
+    disconnect: bool,
+    /// The connection's gone, so the writer finishes once the queue's empty.
+    closed: bool,
+    /// The writer's working on something it's picked up.
+    busy: bool,
+    health: PeerHealth,
+    buffered: usize,
+}
+
+/// The connection's end of what it shares with the writer. Letting go of it
+/// tells the writer to finish up.
+#[derive(Debug)]
+struct OutboxHandle(Arc<Shared>);
+
+impl Drop for OutboxHandle {
+    fn drop(&mut self) {
+        self.0.outbox.lock().unwrap().closed = true;
+        self.0.changed.notify_one();
+    }
+}
+
+/// Something for the writer to do.
+enum Work {
+    Send(Envelope),
+    Disconnect,
+    /// The backoff's up, so try the peer again.
+    Retry,
+}
+
+impl<C: Connect> Connection<C> {
+    pub fn new(target: impl Into<C>) -> Self {
+        let shared = Shared::default();
+        shared.outbox.lock().unwrap().queue =
+            RaftChannel::bounded(DEFAULT_CAPACITY, OverflowPolicy::default());
+
+        Self {
+            target: target.into(),
+            codec: &BinaryCodec,
+            keys: None,
+            backoff: Backoff::default(),
+            policy: DisconnectedPolicy::default(),
+            started: false,
+            outbox: OutboxHandle(Arc::new(shared)),
+        }
+    }
+
+    pub fn with_codec(self, codec: &'static dyn Codec) -> Self {
+        Self { codec, ..self }
+    }
+
+    /// Sign everything we send with `keys`.
+    pub fn with_keys(self, keys: Arc<ClusterKeys>) -> Self {
+        Self {
+            keys: Some(keys),
+            ..self
+        }
+    }
+
+    pub fn with_backoff(self, backoff: Backoff) -> Self {
+        Self { backoff, ..self }
+    }
+
+    pub fn with_policy(self, policy: DisconnectedPolicy) -> Self {
+        Self { policy, ..self }
+    }
+
+    /// How many messages can wait for the writer, and what happens to any
+    /// more.
+    pub fn with_capacity(self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        self.outbox.0.outbox.lock().unwrap().queue = RaftChannel::bounded(capacity, overflow);
+        self
+    }
+
+    pub fn target(&self) -> &C {
+        &self.target
+    }
+
+    /// How many messages are waiting for the peer to come back.
+    pub fn buffered(&self) -> usize {
+        self.outbox.0.outbox.lock().unwrap().buffered
+    }
+
+    /// Drop the stream as if it had broken, without waiting to reconnect.
+    pub fn disconnect(&mut self) {
+        self.start();
+        self.outbox.0.outbox.lock().unwrap().disconnect = true;
+        self.outbox.0.changed.notify_one();
+    }
+
+    fn start(&mut self) {
+        if self.started {
+            return;
+        }
+
+        let writer = Writer {
+            target: self.target.clone(),
+            stream: None,
+            codec: self.codec,
+            keys: self.keys.clone(),
+            backoff: self.backoff,
+            policy: self.policy,
+            buffer: VecDeque::new(),
+            health: PeerHealth::Unknown,
+            retry_at: None,
+            rng: Rng::from_entropy(),
+            shared: self.outbox.0.clone(),
+        };
+
+        thread::spawn(move || writer.run());
+        self.started = true;
+    }
+}
+
+impl<C: Connect> Channel for Connection<C> {
+    fn push(&mut self, envelope: Envelope) {
+        let _ = self.try_push(envelope);
+    }
+
+    fn try_push(&mut self, envelope: Envelope) -> Result<(), Overflow> {
+        self.start();
+
+        let pushed = self
+            .outbox
+            .0
+            .outbox
+            .lock()
+            .unwrap()
+            .queue
+            .try_push(envelope);
+        self.outbox.0.changed.notify_one();
+
+        pushed
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.outbox.0.outbox.lock().unwrap().queue.is_full()
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        None
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        vec![]
+    }
+
+    fn health(&mut self) -> PeerHealth {
+        self.outbox.0.outbox.lock().unwrap().health
+    }
+}
+
+/// The connection's thread, which owns the stream and whatever's waiting to
+/// go down it.
+struct Writer<C: Connect> {
+    target: C,
+    stream: Option<BufWriter<C::Stream>>,
+    codec: &'static dyn Codec,
+    keys: Option<Arc<ClusterKeys>>,
+    backoff: Backoff,
+    policy: DisconnectedPolicy,
+    buffer: VecDeque<Envelope>,
+    health: PeerHealth,
+    retry_at: Option<Instant>,
+    rng: Rng,
+    shared: Arc<Shared>,
+}
+
+impl<C: Connect> Writer<C> {
+    fn run(mut self) {
+        while let Some(work) = self.next() {
+            match work {
+                Work::Send(envelope) => self.send(envelope),
+                Work::Disconnect => self.stream = None,
+                Work::Retry => {
+                    if self.reconnect() {
+                        self.catch_up();
+                    }
+                }
+            }
+
+            let mut outbox = self.shared.outbox.lock().unwrap();
+            outbox.health = self.health;
+            outbox.buffered = self.buffer.len();
+            outbox.busy = false;
+        }
+    }
+
+    /// Wait for something to do, or `None` once the connection's gone and
+    /// there's nothing left to send. While the peer's unreachable, it's only
+    /// waited for until it's time to try them again.
+    fn next(&self) -> Option<Work> {
+        let mut outbox = self.shared.outbox.lock().unwrap();
+
+        loop {
+            let work = if std::mem::take(&mut outbox.disconnect) {
+                Work::Disconnect
+            } else if let Some(envelope) = outbox.queue.pop() {
+                Work::Send(envelope)
+            } else if outbox.closed {
+                return None;
+            } else {
+                match self.retry_at.filter(|_| self.stream.is_none()) {
+                    Some(retry_at) if Instant::now() >= retry_at => Work::Retry,
+                    Some(retry_at) => {
+                        let wait = retry_at.saturating_duration_since(Instant::now());
+                        outbox = self.shared.changed.wait_timeout(outbox, wait).unwrap().0;
+                        continue;
+                    }
+                    None => {
+                        outbox = self.shared.changed.wait(outbox).unwrap();
+                        continue;
+                    }
+                }
+            };
+
+            outbox.busy = true;
+            return Some(work);
+        }
+    }
+
+    fn send(&mut self, envelope: Envelope) {
+        if self.stream.is_none() && !self.reconnect() {
+            self.hold(envelope);
+            return;
+        }
+
+        // Whatever was held goes first, so the peer still sees our messages
+        // in the order we sent them.
+        self.buffer.push_back(envelope);
+        self.catch_up();
+    }
+
+    /// Write out everything held, until it's all gone or the stream breaks.
+    fn catch_up(&mut self) {
+        while let Some(envelope) = self.buffer.pop_front() {
+            let stream = self.stream.as_mut().expect("Connected just now");
+
+            if let Err(error) = write_envelope(stream, self.codec, self.keys.as_deref(), &envelope)
+            {
+                self.failed(error);
+                self.buffer.push_front(envelope);
+                self.trim_buffer();
+                return;
+            }
+        }
+    }
+
+    /// Only once the backoff is up.
+    fn reconnect(&mut self) -> bool {
+        if self
+            .retry_at
+            .is_some_and(|retry_at| Instant::now() < retry_at)
+        {
+            return false;
+        }
+
+        match self.target.connect() {
+            Ok(stream) => {
+                if self.health != PeerHealth::Unknown && self.health != PeerHealth::Connected {
+                    eprintln!("Reconnected to {:?}", self.target);
+                }
+
+                self.stream = Some(BufWriter::new(stream));
+                self.health = PeerHealth::Connected;
+                self.retry_at = None;
+                true
+            }
+            Err(error) => {
+                self.failed(error);
+                false
+            }
+        }
+    }
+
+    fn failed(&mut self, error: io::Error) {
+        let failed_attempts = match self.health {
+            PeerHealth::Unreachable { failed_attempts } => failed_attempts + 1,
+            _ => {
+                eprintln!("Lost touch with {:?}: {error}", self.target);
+                1
+            }
+        };
+
+        self.stream = None;
+        self.health = PeerHealth::Unreachable { failed_attempts };
+        self.retry_at = Some(Instant::now() + self.backoff.delay(failed_attempts, &mut self.rng));
+    }
+
+    fn hold(&mut self, envelope: Envelope) {
+        if let DisconnectedPolicy::Buffer { .. } = self.policy {
+            self.buffer.push_back(envelope);
+            self.trim_buffer();
+        }
+    }
+
+    fn trim_buffer(&mut self) {
+        let capacity = match self.policy {
+            DisconnectedPolicy::Drop => 0,
+            DisconnectedPolicy::Buffer { capacity } => capacity,
+        };
+
+        while self.buffer.len() > capacity {
+            self.buffer.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_buddy::{RaftBuddy, Role};
+    use crate::raft_frame::read_envelope;
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_message::RaftMessage;
+    use crate::raft_temporal::Temporal;
+    use crate::raft_topology::Topology;
+    use crate::raft_type_aliases::ArcMutChannel;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// A peer that can be taken down and brought back, and that remembers
+    /// every byte it was sent.
+    #[derive(Debug, Default, Clone)]
+    struct Flaky {
+        up: Arc<AtomicBool>,
+        received: Arc<Mutex<Vec<u8>>>,
+    }
+
+    impl Flaky {
+        fn received(&self) -> Vec<Envelope> {
+            let bytes = self.received.lock().unwrap().clone();
+            let mut reader = bytes.as_slice();
+            let mut envelopes = vec![];
+
+            while let Some(envelope) = read_envelope(&mut reader, None).unwrap() {
+                envelopes.push(envelope);
+            }
+
+            envelopes
+        }
+    }
+
+    impl Connect for Flaky {
+        type Stream = Flaky;
+
+        fn connect(&self) -> io::Result<Flaky> {
+            if self.up.load(Ordering::Relaxed) {
+                Ok(self.clone())
+            } else {
+                Err(io::ErrorKind::ConnectionRefused.into())
+            }
+        }
+    }
+
+    impl Write for Flaky {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if !self.up.load(Ordering::Relaxed) {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+
+            self.received.lock().unwrap().extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn vote(term: usize) -> Envelope {
+        Envelope {
+            from: RaftId(1),
+            to: RaftId(0),
+            term,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        }
+    }
+
+    /// Wait for the writer to get through everything we've given it.
+    fn settle<C: Connect>(connection: &Connection<C>) {
+        eventually(|| {
+            let outbox = connection.outbox.0.outbox.lock().unwrap();
+            outbox.queue.queue.is_empty() && !outbox.disconnect && !outbox.busy
+        });
+    }
+
+    /// Wait for `condition` to hold, for as long as anyone could reasonably
+    /// expect to.
+    fn eventually(mut condition: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while !condition() {
+            assert!(Instant::now() < deadline, "Gave up waiting");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn hardly_waiting() -> Backoff {
+        Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_with_jitter() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+        };
+        let mut rng = Rng::new(7);
+
+        for (attempts, ceiling) in [
+            (1, 100),
+            (2, 200),
+            (3, 400),
+            (4, 800),
+            (5, 1000),
+            (60, 1000),
+        ] {
+            let ceiling = Duration::from_millis(ceiling);
+
+            for _ in 0..100 {
+                let delay = backoff.delay(attempts, &mut rng);
+                assert!(
+                    delay >= ceiling / 2 && delay <= ceiling,
+                    "{attempts}: {delay:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn it_buffers_while_unreachable_and_catches_up_in_order() {
+        let peer = Flaky::default();
+        let mut connection = Connection::<Flaky>::new(peer.clone()).with_backoff(hardly_waiting());
+
+        connection.push(vote(1));
+        connection.push(vote(2));
+        settle(&connection);
+
+        assert!(matches!(
+            connection.health(),
+            PeerHealth::Unreachable { .. }
+        ));
+        assert_eq!(connection.buffered(), 2);
+
+        peer.up.store(true, Ordering::Relaxed);
+        connection.push(vote(3));
+
+        eventually(|| peer.received().len() == 3);
+        assert_eq!(peer.received(), [vote(1), vote(2), vote(3)]);
+        assert_eq!(connection.health(), PeerHealth::Connected);
+        assert_eq!(connection.buffered(), 0);
+    }
+
+    #[test]
+    fn it_catches_up_as_soon_as_the_peer_is_back() {
+        let peer = Flaky::default();
+        let mut connection = Connection::<Flaky>::new(peer.clone()).with_backoff(hardly_waiting());
+
+        connection.push(vote(1));
+        connection.push(vote(2));
+        settle(&connection);
+
+        // Without anything more to send.
+        peer.up.store(true, Ordering::Relaxed);
+
+        eventually(|| peer.received().len() == 2);
+        assert_eq!(peer.received(), [vote(1), vote(2)]);
+        assert_eq!(connection.health(), PeerHealth::Connected);
+    }
+
+    #[test]
+    fn it_keeps_only_the_most_recent_messages() {
+        let peer = Flaky::default();
+        let mut connection = Connection::<Flaky>::new(peer.clone())
+            .with_backoff(hardly_waiting())
+            .with_policy(DisconnectedPolicy::Buffer { capacity: 2 });
+
+        for term in 1..=5 {
+            connection.push(vote(term));
+        }
+        settle(&connection);
+
+        peer.up.store(true, Ordering::Relaxed);
+        eventually(|| connection.health() == PeerHealth::Connected);
+        connection.push(vote(6));
+
+        eventually(|| peer.received().len() == 3);
+        assert_eq!(peer.received(), [vote(4), vote(5), vote(6)]);
+    }
+
+    #[test]
+    fn it_can_drop_everything_while_unreachable() {
+        let peer = Flaky::default();
+        let mut connection = Connection::<Flaky>::new(peer.clone())
+            .with_backoff(hardly_waiting())
+            .with_policy(DisconnectedPolicy::Drop);
+
+        connection.push(vote(1));
+        settle(&connection);
+        peer.up.store(true, Ordering::Relaxed);
+        eventually(|| connection.health() == PeerHealth::Connected);
+        connection.push(vote(2));
+
+        eventually(|| !peer.received().is_empty());
+        assert_eq!(peer.received(), [vote(2)]);
+    }
+
+    #[test]
+    fn it_notices_a_broken_stream_and_reconnects() {
+        let peer = Flaky::default();
+        peer.up.store(true, Ordering::Relaxed);
+        let mut connection = Connection::<Flaky>::new(peer.clone()).with_backoff(hardly_waiting());
+
+        connection.push(vote(1));
+        settle(&connection);
+        peer.up.store(false, Ordering::Relaxed);
+        connection.push(vote(2));
+        settle(&connection);
+
+        assert!(matches!(
+            connection.health(),
+            PeerHealth::Unreachable { .. }
+        ));
+
+        peer.up.store(true, Ordering::Relaxed);
+        connection.push(vote(3));
+
+        eventually(|| peer.received().len() == 3);
+        assert_eq!(peer.received(), [vote(1), vote(2), vote(3)]);
+    }
+
+    #[test]
+    fn it_waits_out_the_backoff_before_trying_again() {
+        let peer = Flaky::default();
+        let mut connection = Connection::<Flaky>::new(peer.clone()).with_backoff(Backoff {
+            initial: Duration::from_secs(60),
+            max: Duration::from_secs(60),
+        });
+
+        connection.push(vote(1));
+        settle(&connection);
+        peer.up.store(true, Ordering::Relaxed);
+        connection.push(vote(2));
+        settle(&connection);
+
+        assert_eq!(
+            connection.health(),
+            PeerHealth::Unreachable { failed_attempts: 1 }
+        );
+        assert!(peer.received().is_empty());
+    }
+
+    /// Connecting to it takes as long as it's told to.
+    #[derive(Debug, Clone)]
+    struct Slow(Duration);
+
+    impl Connect for Slow {
+        type Stream = io::Sink;
+
+        fn connect(&self) -> io::Result<io::Sink> {
+            thread::sleep(self.0);
+            Ok(io::sink())
+        }
+    }
+
+    #[test]
+    fn pushing_does_not_wait_for_the_peer() {
+        let mut connection = Connection::<Slow>::new(Slow(Duration::from_secs(1)));
+        let started = Instant::now();
+
+        for term in 1..=10 {
+            connection.push(vote(term));
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+
+    /// A peer that's stopped reading. Writing to it hangs until it's let go,
+    /// the way writing to a socket does once the other end's buffers are
+    /// full.
+    #[derive(Debug, Default, Clone)]
+    struct Stuck {
+        released: Arc<AtomicBool>,
+        writing: Arc<AtomicBool>,
+    }
+
+    impl Connect for Stuck {
+        type Stream = Stuck;
+
+        fn connect(&self) -> io::Result<Stuck> {
+            Ok(self.clone())
+        }
+    }
+
+    impl Write for Stuck {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            self.writing.store(true, Ordering::Relaxed);
+
+            while !self.released.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+
+            Ok(bytes.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn a_peer_that_stops_reading_fills_the_connection_up() {
+        let peer = Stuck::default();
+        let mut connection =
+            Connection::<Stuck>::new(peer.clone()).with_capacity(2, OverflowPolicy::Reject);
+
+        connection.push(vote(1));
+        eventually(|| peer.writing.load(Ordering::Relaxed));
+
+        assert_eq!(connection.try_push(vote(2)), Ok(()));
+        assert_eq!(connection.try_push(vote(3)), Ok(()));
+        assert!(connection.is_full());
+        assert_eq!(connection.try_push(vote(4)), Err(Overflow::Rejected));
+
+        peer.released.store(true, Ordering::Relaxed);
+        settle(&connection);
+
+        assert!(!connection.is_full());
+        assert_eq!(connection.health(), PeerHealth::Connected);
+    }
+
+    #[test]
+    fn full_connections_hand_back_what_they_block() {
+        let peer = Stuck::default();
+        let mut connection =
+            Connection::<Stuck>::new(peer.clone()).with_capacity(1, OverflowPolicy::Block);
+
+        connection.push(vote(1));
+        eventually(|| peer.writing.load(Ordering::Relaxed));
+        connection.push(vote(2));
+
+        assert_eq!(
+            connection.try_push(vote(3)),
+            Err(Overflow::Blocked(Box::new(vote(3))))
+        );
+
+        peer.released.store(true, Ordering::Relaxed);
+        settle(&connection);
+
+        assert_eq!(connection.try_push(vote(3)), Ok(()));
+    }
+
+    #[test]
+    fn the_leader_pauses_replication_to_a_peer_that_stops_reading() {
+        let peer = Stuck::default();
+        let connection =
+            Connection::<Stuck>::new(peer.clone()).with_capacity(4, OverflowPolicy::Reject);
+        let topology = Topology::from_iter([
+            (
+                RaftId(0),
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
+            ),
+            (RaftId(1), Arc::new(Mutex::new(connection)) as ArcMutChannel),
+            (
+                RaftId(2),
+                Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
+            ),
+        ]);
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let [leader, _, follower] = &mut buddies[..] else {
+            unreachable!()
+        };
+
+        leader.timer.ticks_left = 1;
+        leader.tick();
+        follower.tick();
+        leader.tick();
+
+        assert_eq!(leader.role, Role::Leader);
+
+        for value in 1..=20 {
+            leader.propose(format!("set x {value}"));
+            leader.heartbeat.ticks_left = 1;
+            leader.tick();
+            follower.tick();
+            leader.tick();
+        }
+
+        assert!(leader.progress[&RaftId(1)].paused);
+        assert!(topology[&RaftId(1)].lock().unwrap().is_full());
+        // The other follower is enough to keep committing.
+        assert_eq!(leader.commit_index, 20);
+
+        peer.released.store(true, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,371 @@
+use std::io;
+
+use crate::raft_compression::Compression;
+use crate::raft_id::{ClusterId, RaftId};
+use crate::raft_log::{LogEntry, RaftLog, SnapshotBase};
+use crate::raft_message::{
+    AppendEntriesBody, AppendEntriesResponseBody, Envelope, InstallSnapshotBody,
+    InstallSnapshotResponseBody, RaftMessage, RequestVoteBody,
+};
+
+/// Everything is written big-endian, with variable length fields prefixed by
+/// their length as a `u64`.
+pub fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    bytes.extend_from_slice(&value.to_be_bytes());
+}
+
+pub fn put_usize(bytes: &mut Vec<u8>, value: usize) {
+    put_u64(bytes, value as u64);
+}
+
+pub fn put_bool(bytes: &mut Vec<u8>, value: bool) {
+    bytes.push(value as u8);
+}
+
+pub fn put_bytes(bytes: &mut Vec<u8>, value: &[u8]) {
+    put_usize(bytes, value.len());
+    bytes.extend_from_slice(value);
+}
+
+pub fn put_entry(bytes: &mut Vec<u8>, entry: &LogEntry) {
+    put_usize(bytes, entry.term);
+    put_usize(bytes, entry.index);
+    put_bytes(bytes, entry.contents.as_bytes());
+}
+
+pub fn encode_log(log: &RaftLog) -> Vec<u8> {
+    let mut bytes = vec![];
+    let base = log.base();
+
+    put_usize(&mut bytes, base.last_included_index);
+    put_usize(&mut bytes, base.last_included_term);
+    put_usize(&mut bytes, log.iter().len());
+
+    for entry in log.iter() {
+        put_entry(&mut bytes, entry);
+    }
+
+    bytes
+}
+
+pub fn decode_log(bytes: &[u8]) -> io::Result<RaftLog> {
+    let mut reader = Reader(bytes);
+
+    let mut log = RaftLog::with_base(SnapshotBase {
+        last_included_index: reader.usize()?,
+        last_included_term: reader.usize()?,
+    });
+
+    for _entry in 0..reader.usize()? {
+        let entry = reader.entry()?;
+
+        if log.last_index().checked_add(1) != Some(entry.index) {
+            return Err(invalid_data("log entries aren't contiguous"));
+        }
+
+        log.push(entry);
+    }
+
+    reader.finish()?;
+
+    if !log.is_well_formed() {
+        return Err(invalid_data("log has no room after its base"));
+    }
+
+    Ok(log)
+}
+
+/// The envelope's fields, then one byte for the variant, then its fields in
+/// declaration order.
+pub fn encode_envelope(envelope: &Envelope) -> Vec<u8> {
+    let mut bytes = vec![];
+
+    put_usize(&mut bytes, *envelope.from);
+    put_usize(&mut bytes, *envelope.to);
+    put_usize(&mut bytes, envelope.term);
+    put_u64(&mut bytes, envelope.cluster_id.0);
+
+    match &envelope.message {
+        RaftMessage::RequestVote(body) => {
+            bytes.push(0);
+            put_usize(&mut bytes, body.last_log_index);
+            put_usize(&mut bytes, body.last_log_term);
+        }
+        RaftMessage::VoteForCandidate => bytes.push(1),
+        RaftMessage::RejectCandidateVote => bytes.push(2),
+        RaftMessage::AppendEntries(body) => {
+            bytes.push(3);
+            put_usize(&mut bytes, body.prev_log_index);
+            put_usize(&mut bytes, body.prev_log_term);
+            put_usize(&mut bytes, body.entries.len());
+            for entry in &body.entries {
+                put_entry(&mut bytes, entry);
+            }
+            put_usize(&mut bytes, body.leader_commit);
+        }
+        RaftMessage::AppendEntriesResponse(body) => {
+            bytes.push(4);
+            put_bool(&mut bytes, body.success);
+            put_usize(&mut bytes, body.last_log_index);
+        }
+        RaftMessage::InstallSnapshot(body) => {
+            bytes.push(5);
+            put_usize(&mut bytes, body.last_included_index);
+            put_usize(&mut bytes, body.last_included_term);
+            put_usize(&mut bytes, body.offset);
+            put_bytes(&mut bytes, &body.data);
+            bytes.push(body.compression.tag());
+            put_bool(&mut bytes, body.done);
+        }
+        RaftMessage::InstallSnapshotResponse(body) => {
+            bytes.push(6);
+            put_usize(&mut bytes, body.last_included_index);
+            put_usize(&mut bytes, body.next_offset);
+            put_bool(&mut bytes, body.done);
+            bytes.push(body.compression.tag());
+        }
+    }
+
+    bytes
+}
+
+pub fn decode_envelope(bytes: &[u8]) -> io::Result<Envelope> {
+    let mut reader = Reader(bytes);
+
+    let from = RaftId(reader.usize()?);
+    let to = RaftId(reader.usize()?);
+    let term = reader.usize()?;
+    let cluster_id = ClusterId(reader.u64()?);
+
+    let message = match reader.u8()? {
+        0 => RaftMessage::RequestVote(RequestVoteBody {
+            last_log_index: reader.usize()?,
+            last_log_term: reader.usize()?,
+        }),
+        1 => RaftMessage::VoteForCandidate,
+        2 => RaftMessage::RejectCandidateVote,
+        3 => RaftMessage::AppendEntries(AppendEntriesBody {
+            prev_log_index: reader.usize()?,
+            prev_log_term: reader.usize()?,
+            entries: (0..reader.usize()?)
+                .map(|_| reader.entry())
+                .collect::<io::Result<_>>()?,
+            leader_commit: reader.usize()?,
+        }),
+        4 => RaftMessage::AppendEntriesResponse(AppendEntriesResponseBody {
+            success: reader.bool()?,
+            last_log_index: reader.usize()?,
+        }),
+        5 => RaftMessage::InstallSnapshot(InstallSnapshotBody {
+            last_included_index: reader.usize()?,
+            last_included_term: reader.usize()?,
+            offset: reader.usize()?,
+            data: reader.bytes()?.to_vec(),
+            compression: Compression::from_tag(reader.u8()?)?,
+            done: reader.bool()?,
+        }),
+        6 => RaftMessage::InstallSnapshotResponse(InstallSnapshotResponseBody {
+            last_included_index: reader.usize()?,
+            next_offset: reader.usize()?,
+            done: reader.bool()?,
+            compression: Compression::from_tag(reader.u8()?)?,
+        }),
+        _ => return Err(invalid_data("unknown message type")),
+    };
+
+    reader.finish()?;
+
+    Ok(Envelope {
+        from,
+        to,
+        term,
+        cluster_id,
+        message,
+    })
+}
+
+pub fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads back what the `put_*` functions wrote, failing rather than panicking
+/// on anything short or malformed.
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < length {
+            return Err(invalid_data("ran out of bytes"));
+        }
+
+        let (taken, rest) = self.0.split_at(length);
+        self.0 = rest;
+
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("bool isn't 0 or 1")),
+        }
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        let bytes = self.take(8)?;
+
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> io::Result<usize> {
+        usize::try_from(self.u64()?).map_err(|_| invalid_data("length doesn't fit in a usize"))
+    }
+
+    pub fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let length = self.usize()?;
+
+        self.take(length)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|_| invalid_data("string isn't UTF-8"))
+    }
+
+    pub fn entry(&mut self) -> io::Result<LogEntry> {
+        Ok(LogEntry {
+            term: self.usize()?,
+            index: self.usize()?,
+            contents: self.string()?,
+        })
+    }
+
+    /// Fail if there's anything left over.
+    pub fn finish(&self) -> io::Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(invalid_data("trailing bytes"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_round_trips_a_compacted_log() {
+        let mut log = RaftLog::with_base(SnapshotBase {
+            last_included_index: 4,
+            last_included_term: 2,
+        });
+        log.push(LogEntry {
+            term: 3,
+            index: 5,
+            contents: "set x 5".to_owned(),
+        });
+
+        let decoded = decode_log(&encode_log(&log)).unwrap();
+
+        assert_eq!(decoded.base(), log.base());
+        assert!(decoded.iter().eq(log.iter()));
+    }
+
+    #[test]
+    fn it_rejects_truncated_logs() {
+        let mut log = RaftLog::default();
+        log.push(LogEntry {
+            term: 1,
+            index: 1,
+            contents: "set x 1".to_owned(),
+        });
+        let bytes = encode_log(&log);
+
+        assert!(decode_log(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn it_rejects_logs_with_no_room_after_the_base() {
+        let log = RaftLog::with_base(SnapshotBase {
+            last_included_index: usize::MAX,
+            last_included_term: 1,
+        });
+
+        assert!(decode_log(&encode_log(&log)).is_err());
+    }
+
+    #[test]
+    fn it_round_trips_every_kind_of_message() {
+        let messages = [
+            RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: 4,
+                last_log_term: 2,
+            }),
+            RaftMessage::VoteForCandidate,
+            RaftMessage::RejectCandidateVote,
+            RaftMessage::AppendEntries(AppendEntriesBody {
+                prev_log_index: 1,
+                prev_log_term: 2,
+                entries: vec![LogEntry {
+                    term: 3,
+                    index: 2,
+                    contents: "set x 2".to_owned(),
+                }],
+                leader_commit: 1,
+            }),
+            RaftMessage::AppendEntriesResponse(AppendEntriesResponseBody {
+                success: true,
+                last_log_index: 2,
+            }),
+            RaftMessage::InstallSnapshot(InstallSnapshotBody {
+                last_included_index: 9,
+                last_included_term: 2,
+                offset: 1024,
+                data: vec![1, 2, 3],
+                compression: Compression::None,
+                done: false,
+            }),
+            RaftMessage::InstallSnapshotResponse(InstallSnapshotResponseBody {
+                last_included_index: 9,
+                next_offset: 1027,
+                done: true,
+                compression: Compression::Deflate,
+            }),
+        ];
+
+        for message in messages {
+            let envelope = Envelope {
+                from: RaftId(1),
+                to: RaftId(2),
+                term: 3,
+                cluster_id: ClusterId(7),
+                message,
+            };
+
+            assert_eq!(
+                decode_envelope(&encode_envelope(&envelope)).unwrap(),
+                envelope
+            );
+        }
+    }
+
+    #[test]
+    fn it_rejects_unknown_message_types() {
+        let mut bytes = encode_envelope(&Envelope {
+            from: RaftId(0),
+            to: RaftId(1),
+            term: 0,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        });
+        *bytes.last_mut().unwrap() = 42;
+
+        assert!(decode_envelope(&bytes).is_err());
+        assert!(decode_envelope(&[]).is_err());
+    }
+}
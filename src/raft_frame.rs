@@ -0,0 +1,334 @@
+use std::fmt::Display;
+use std::io::{self, BufReader, Read, Write};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::raft_auth::{ClusterKeys, TAG_LENGTH};
+use crate::raft_channel::{Channel, Overflow};
+use crate::raft_codec::{codec_for, Codec};
+use crate::raft_encoding::invalid_data;
+use crate::raft_message::Envelope;
+use crate::raft_type_aliases::SharedQueue;
+
+/// Bumped whenever the layout of a frame or the message encoding inside it
+/// changes, so mismatched builds refuse each other's frames instead of
+/// misreading them.
+pub const FRAME_VERSION: u8 = 5;
+
+/// Anything bigger is assumed to be garbage rather than a real message, so a
+/// bad length can't make us allocate the world.
+pub const MAX_FRAME_LENGTH: usize = 64 * 1024 * 1024;
+
+/// A frame is a big-endian `u32` length, then that many bytes: the frame
+/// version, then either a 0 or a 1 and an HMAC-SHA256 tag of the version and
+/// payload if it's signed, then the payload.
+///
+/// Signing proves a frame came from someone with the cluster key and wasn't
+/// changed on the way, but not that it's new, so anyone who can reach an
+/// inbox can still replay what they've seen.
+pub fn write_frame(
+    writer: &mut impl Write,
+    keys: Option<&ClusterKeys>,
+    payload: &[u8],
+) -> io::Result<()> {
+    let tag = keys.map(|keys| keys.sign(FRAME_VERSION, payload));
+    let length = 2 + tag.map_or(0, |tag| tag.len()) + payload.len();
+
+    if length > MAX_FRAME_LENGTH {
+        return Err(invalid_data("frame is too long"));
+    }
+
+    let mut frame = Vec::with_capacity(4 + length);
+    frame.extend_from_slice(&(length as u32).to_be_bytes());
+    frame.push(FRAME_VERSION);
+    match tag {
+        Some(tag) => {
+            frame.push(1);
+            frame.extend_from_slice(&tag);
+        }
+        None => frame.push(0),
+    }
+    frame.extend_from_slice(payload);
+
+    writer.write_all(&frame)?;
+    writer.flush()
+}
+
+/// The next frame's payload, or `None` if the stream ended cleanly between
+/// frames. With `keys`, frames that aren't signed with one of them are
+/// refused. Without, signatures are ignored.
+pub fn read_frame(
+    reader: &mut impl Read,
+    keys: Option<&ClusterKeys>,
+) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0; 4];
+    let mut read = 0;
+
+    // Only running out before the first byte is a clean end. Part of a
+    // length means the stream was cut off in the middle of a frame.
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+
+    if !(2..=MAX_FRAME_LENGTH).contains(&length) {
+        return Err(invalid_data("frame length is out of range"));
+    }
+
+    let mut frame = vec![0; length];
+    reader.read_exact(&mut frame)?;
+
+    let [version, signed, rest @ ..] = frame.as_slice() else {
+        unreachable!("frames are at least two bytes long");
+    };
+
+    if *version != FRAME_VERSION {
+        return Err(invalid_data("unsupported frame version"));
+    }
+
+    let payload = match (signed, keys) {
+        (0, None) => rest,
+        (0, Some(_)) => return Err(unauthenticated("frame isn't signed")),
+        (1, keys) => {
+            if rest.len() < TAG_LENGTH {
+                return Err(invalid_data("frame is too short for its signature"));
+            }
+
+            let (tag, payload) = rest.split_at(TAG_LENGTH);
+
+            if keys.is_some_and(|keys| !keys.verify(*version, payload, tag)) {
+                return Err(unauthenticated("frame's signature doesn't match"));
+            }
+
+            payload
+        }
+        _ => return Err(invalid_data("frame is neither signed nor unsigned")),
+    };
+
+    Ok(Some(payload.to_vec()))
+}
+
+fn unauthenticated(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, message.to_owned())
+}
+
+/// An envelope's payload starts with the id of the codec that encoded it, so
+/// each sender can pick its own.
+pub fn write_envelope(
+    writer: &mut impl Write,
+    codec: &dyn Codec,
+    keys: Option<&ClusterKeys>,
+    envelope: &Envelope,
+) -> io::Result<()> {
+    let mut payload = vec![codec.id()];
+    payload.extend(codec.encode_envelope(envelope));
+
+    write_frame(writer, keys, &payload)
+}
+
+pub fn read_envelope(
+    reader: &mut impl Read,
+    keys: Option<&ClusterKeys>,
+) -> io::Result<Option<Envelope>> {
+    let Some(payload) = read_frame(reader, keys)? else {
+        return Ok(None);
+    };
+
+    let (&codec, envelope) = payload
+        .split_first()
+        .ok_or_else(|| invalid_data("frame has no codec"))?;
+
+    codec_for(codec)?.decode_envelope(envelope).map(Some)
+}
+
+/// Read envelopes off `stream` into `queue` on a thread of its own, until the
+/// other end hangs up or sends something we can't read or won't accept.
+pub fn spawn_reader(
+    stream: impl Read + Send + 'static,
+    from: impl Display + Send + 'static,
+    queue: SharedQueue,
+    keys: Option<Arc<ClusterKeys>>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(stream);
+
+        loop {
+            match read_envelope(&mut reader, keys.as_deref()) {
+                Ok(Some(envelope)) => deliver(&queue, envelope),
+                Ok(None) => break,
+                Err(error) => {
+                    eprintln!("Dropping connection from {from}: {error}");
+                    break;
+                }
+            }
+        }
+    });
+}
+
+/// Whatever a full queue blocks waits here for room, and we don't read any
+/// more off the stream until it's in. The sender's writes back up in turn,
+/// so a peer that's flooding us slows down rather than piling up in memory.
+fn deliver(queue: &SharedQueue, mut envelope: Envelope) {
+    loop {
+        let pushed = queue.lock().unwrap().try_push(envelope);
+
+        match pushed {
+            Err(Overflow::Blocked(blocked)) => {
+                envelope = *blocked;
+                thread::sleep(Duration::from_millis(1));
+            }
+            _ => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_auth::ClusterKey;
+    use crate::raft_codec::BinaryCodec;
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_message::{RaftMessage, RequestVoteBody};
+
+    fn vote() -> Envelope {
+        Envelope {
+            from: RaftId(1),
+            to: RaftId(2),
+            term: 2,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::RequestVote(RequestVoteBody {
+                last_log_index: 3,
+                last_log_term: 1,
+            }),
+        }
+    }
+
+    #[test]
+    fn it_reads_back_what_it_writes() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+
+        let mut reader = stream.as_slice();
+
+        assert_eq!(read_envelope(&mut reader, None).unwrap(), Some(vote()));
+        assert_eq!(read_envelope(&mut reader, None).unwrap(), Some(vote()));
+        assert_eq!(read_envelope(&mut reader, None).unwrap(), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_reads_whichever_codec_the_sender_used() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+        write_envelope(&mut stream, &crate::raft_codec::JsonCodec, None, &vote()).unwrap();
+
+        let mut reader = stream.as_slice();
+
+        assert_eq!(read_envelope(&mut reader, None).unwrap(), Some(vote()));
+        assert_eq!(read_envelope(&mut reader, None).unwrap(), Some(vote()));
+    }
+
+    #[test]
+    fn it_refuses_codecs_it_does_not_have() {
+        let mut stream = vec![];
+        write_frame(&mut stream, None, &[42, 1, 2, 3]).unwrap();
+
+        assert!(read_envelope(&mut stream.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn it_refuses_other_versions() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+        stream[4] = FRAME_VERSION + 1;
+
+        assert!(read_envelope(&mut stream.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn it_refuses_absurd_lengths() {
+        let stream = u32::MAX.to_be_bytes();
+
+        assert!(read_frame(&mut stream.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn it_complains_about_frames_cut_short() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+        stream.pop();
+
+        assert!(read_envelope(&mut stream.as_slice(), None).is_err());
+    }
+
+    #[test]
+    fn it_only_counts_a_stream_ending_between_frames_as_clean() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+
+        assert_eq!(read_frame(&mut &stream[..0], None).unwrap(), None);
+
+        for cut in 1..4 {
+            let error = read_frame(&mut &stream[..cut], None).unwrap_err();
+
+            assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+        }
+    }
+
+    fn keys(secret: &str) -> ClusterKeys {
+        ClusterKeys::new(ClusterKey::new(secret))
+    }
+
+    #[test]
+    fn it_reads_back_signed_frames() {
+        let keys = keys("hunter2");
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, Some(&keys), &vote()).unwrap();
+
+        assert_eq!(
+            read_envelope(&mut stream.as_slice(), Some(&keys)).unwrap(),
+            Some(vote())
+        );
+    }
+
+    #[test]
+    fn it_refuses_unsigned_frames_when_it_has_keys() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, None, &vote()).unwrap();
+
+        let error = read_envelope(&mut stream.as_slice(), Some(&keys("hunter2"))).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn it_refuses_frames_signed_with_another_key() {
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, Some(&keys("swordfish")), &vote()).unwrap();
+
+        let error = read_envelope(&mut stream.as_slice(), Some(&keys("hunter2"))).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn it_refuses_tampered_frames() {
+        let keys = keys("hunter2");
+        let mut stream = vec![];
+        write_envelope(&mut stream, &BinaryCodec, Some(&keys), &vote()).unwrap();
+        *stream.last_mut().unwrap() ^= 1;
+
+        let error = read_envelope(&mut stream.as_slice(), Some(&keys)).unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -1,4 +1,5 @@
 #[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RaftId(pub usize);
 
 impl std::ops::Deref for RaftId {
@@ -19,3 +20,9 @@ impl From<usize> for RaftId {
         Self(value)
     }
 }
+
+/// Which cluster a buddy belongs to. Messages from any other cluster are
+/// dropped, so two clusters sharing a network can't disrupt each other.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClusterId(pub u64);
@@ -0,0 +1,393 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::raft_buddy::{RaftBuddy, Role};
+use crate::raft_id::RaftId;
+
+/// One of the safety properties from the Raft paper that didn't hold.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+pub enum Violation {
+    /// Two buddies were leader in the same term.
+    ElectionSafety {
+        term: usize,
+        leaders: (RaftId, RaftId),
+    },
+    /// Two logs agree on the term at `matched` but differ at `index`, which
+    /// comes before it.
+    LogMatching {
+        buddies: (RaftId, RaftId),
+        matched: usize,
+        index: usize,
+    },
+    /// A leader is missing an entry that was committed by the time it was
+    /// elected.
+    LeaderCompleteness {
+        leader: RaftId,
+        term: usize,
+        index: usize,
+    },
+    /// Two buddies committed, and so will apply, different entries at the
+    /// same index.
+    StateMachineSafety {
+        index: usize,
+        buddies: (RaftId, RaftId),
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ElectionSafety { term, leaders } => write!(
+                f,
+                "election safety: buddies {} and {} were both leader in term {term}",
+                *leaders.0, *leaders.1
+            ),
+            Self::LogMatching {
+                buddies,
+                matched,
+                index,
+            } => write!(
+                f,
+                "log matching: buddies {} and {} agree on the term at {matched} but not at {index}",
+                *buddies.0, *buddies.1
+            ),
+            Self::LeaderCompleteness {
+                leader,
+                term,
+                index,
+            } => write!(
+                f,
+                "leader completeness: buddy {}, leader in term {term}, is missing committed entry {index}",
+                **leader
+            ),
+            Self::StateMachineSafety { index, buddies } => write!(
+                f,
+                "state machine safety: buddies {} and {} committed different entries at {index}",
+                *buddies.0, *buddies.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Violation {}
+
+/// An entry someone's commit index has covered.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct Committed {
+    by: RaftId,
+    term: usize,
+    contents: String,
+    /// The term the buddy was in when we first saw it committed, which is
+    /// no earlier than the term it was committed in.
+    seen_in: usize,
+}
+
+/// Checks the Raft safety properties against a cluster's buddies, as often
+/// as it's asked to. Some of them are about what's happened over time rather
+/// than any one moment, so it remembers every leader and committed entry it
+/// has seen.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default)]
+pub struct InvariantChecker {
+    /// BTreeMap<term, RaftId>
+    leaders: BTreeMap<usize, RaftId>,
+    /// BTreeMap<index, Committed>
+    committed: BTreeMap<usize, Committed>,
+    /// BTreeMap<RaftId, index>, the last of each buddy's committed entries
+    /// we've compared, so each tick only looks at what's newly committed.
+    checked: BTreeMap<RaftId, usize>,
+}
+
+impl InvariantChecker {
+    pub fn check<'a>(
+        &mut self,
+        buddies: impl IntoIterator<Item = &'a RaftBuddy>,
+    ) -> Result<(), Violation> {
+        let buddies: Vec<&RaftBuddy> = buddies.into_iter().collect();
+
+        self.check_election_safety(&buddies)?;
+        check_log_matching(&buddies)?;
+        self.check_state_machine_safety(&buddies)?;
+        self.check_leader_completeness(&buddies)
+    }
+
+    fn check_election_safety(&mut self, buddies: &[&RaftBuddy]) -> Result<(), Violation> {
+        for buddy in buddies.iter().filter(|buddy| buddy.role == Role::Leader) {
+            let term = buddy.current_term;
+            let leader = *self.leaders.entry(term).or_insert(buddy.id);
+
+            if leader != buddy.id {
+                return Err(Violation::ElectionSafety {
+                    term,
+                    leaders: (leader, buddy.id),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every entry up to a buddy's commit index has to be the same entry
+    /// everywhere else it's been committed. A buddy's commit index only goes
+    /// back when it restarts, and then whatever it commits again is checked
+    /// again.
+    fn check_state_machine_safety(&mut self, buddies: &[&RaftBuddy]) -> Result<(), Violation> {
+        for buddy in buddies {
+            let last = buddy.commit_index.min(buddy.log.last_index());
+            let checked = self.checked.entry(buddy.id).or_default();
+            let first = buddy.log.first_index().max(last.min(*checked) + 1);
+
+            *checked = last;
+
+            for index in first..=last {
+                let entry = &buddy.log[index];
+                let committed = self.committed.entry(index).or_insert_with(|| Committed {
+                    by: buddy.id,
+                    term: entry.term,
+                    contents: entry.contents.clone(),
+                    seen_in: buddy.current_term,
+                });
+
+                if (committed.term, &committed.contents) != (entry.term, &entry.contents) {
+                    return Err(Violation::StateMachineSafety {
+                        index,
+                        buddies: (committed.by, buddy.id),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// A leader only has to have what was committed by the time its term
+    /// started. Anything compacted into its snapshot is taken as read.
+    fn check_leader_completeness(&self, buddies: &[&RaftBuddy]) -> Result<(), Violation> {
+        for leader in buddies.iter().filter(|buddy| buddy.role == Role::Leader) {
+            let term = leader.current_term;
+            let compacted = leader.log.base().last_included_index;
+
+            for (&index, committed) in self.committed.range(compacted + 1..) {
+                if committed.seen_in <= term && leader.log.term_at(index) != Some(committed.term) {
+                    return Err(Violation::LeaderCompleteness {
+                        leader: leader.id,
+                        term,
+                        index,
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// If two logs have an entry with the same index and term, they're the same
+/// entry and so is everything before it. Only what's still in both logs can
+/// be compared.
+fn check_log_matching(buddies: &[&RaftBuddy]) -> Result<(), Violation> {
+    for (i, a) in buddies.iter().enumerate() {
+        for b in &buddies[i + 1..] {
+            let first = a.log.first_index().max(b.log.first_index());
+            let last = a.log.last_index().min(b.log.last_index());
+
+            let Some(matched) = (first..=last)
+                .rev()
+                .find(|&index| a.log[index].term == b.log[index].term)
+            else {
+                continue;
+            };
+
+            if let Some(index) = (first..=matched).find(|&index| a.log[index] != b.log[index]) {
+                return Err(Violation::LogMatching {
+                    buddies: (a.id, b.id),
+                    matched,
+                    index,
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Every buddy's role, term and log, one buddy to a line, for when a check
+/// fails and someone has to work out why.
+pub fn dump<'a>(buddies: impl IntoIterator<Item = &'a RaftBuddy>) -> String {
+    buddies
+        .into_iter()
+        .map(|buddy| {
+            let base = buddy.log.base();
+            let entries: Vec<String> = buddy
+                .log
+                .iter()
+                .map(|entry| format!("{}@{} {:?}", entry.index, entry.term, entry.contents))
+                .collect();
+
+            format!(
+                "buddy {}: {:?} in term {}, voted for {:?}, committed {}, applied {}, base {}@{}, log [{}]",
+                *buddy.id,
+                buddy.role,
+                buddy.current_term,
+                buddy.voted_for.map(|id| *id),
+                buddy.commit_index,
+                buddy.last_applied,
+                base.last_included_index,
+                base.last_included_term,
+                entries.join(", ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_log::LogEntry;
+
+    fn buddy(id: usize, term: usize, entries: &[(usize, &str)]) -> RaftBuddy {
+        let mut buddy = RaftBuddy {
+            id: RaftId(id),
+            current_term: term,
+            ..Default::default()
+        };
+
+        for (index, &(term, contents)) in (1..).zip(entries) {
+            buddy.log.push(LogEntry {
+                term,
+                index,
+                contents: contents.to_owned(),
+            });
+        }
+
+        buddy
+    }
+
+    fn leader(id: usize, term: usize, entries: &[(usize, &str)]) -> RaftBuddy {
+        RaftBuddy {
+            role: Role::Leader,
+            ..buddy(id, term, entries)
+        }
+    }
+
+    #[test]
+    fn agreeing_buddies_pass() {
+        let mut a = leader(0, 2, &[(1, "set x 1"), (2, "set x 2")]);
+        let mut b = buddy(1, 2, &[(1, "set x 1")]);
+        a.commit_index = 2;
+        b.commit_index = 1;
+
+        assert_eq!(InvariantChecker::default().check([&a, &b]), Ok(()));
+    }
+
+    #[test]
+    fn two_leaders_in_one_term_is_caught_even_at_different_times() {
+        let mut checker = InvariantChecker::default();
+
+        assert_eq!(checker.check([&leader(0, 1, &[])]), Ok(()));
+        assert_eq!(
+            checker.check([&buddy(0, 1, &[]), &leader(1, 1, &[])]),
+            Err(Violation::ElectionSafety {
+                term: 1,
+                leaders: (RaftId(0), RaftId(1))
+            })
+        );
+    }
+
+    #[test]
+    fn logs_that_agree_on_a_term_but_not_before_it_are_caught() {
+        let a = buddy(0, 2, &[(1, "set x 1"), (2, "set x 2")]);
+        let b = buddy(1, 2, &[(1, "set x 9"), (2, "set x 2")]);
+
+        assert_eq!(
+            InvariantChecker::default().check([&a, &b]),
+            Err(Violation::LogMatching {
+                buddies: (RaftId(0), RaftId(1)),
+                matched: 2,
+                index: 1
+            })
+        );
+    }
+
+    #[test]
+    fn logs_that_diverge_after_their_last_match_pass() {
+        let a = buddy(0, 3, &[(1, "set x 1"), (2, "set x 2")]);
+        let b = buddy(1, 3, &[(1, "set x 1"), (3, "set x 3")]);
+
+        assert_eq!(InvariantChecker::default().check([&a, &b]), Ok(()));
+    }
+
+    #[test]
+    fn conflicting_commits_are_caught() {
+        let mut a = buddy(0, 2, &[(1, "set x 1")]);
+        let mut b = buddy(1, 2, &[(2, "set x 2")]);
+        a.commit_index = 1;
+        b.commit_index = 1;
+
+        assert_eq!(
+            InvariantChecker::default().check([&a, &b]),
+            Err(Violation::StateMachineSafety {
+                index: 1,
+                buddies: (RaftId(0), RaftId(1))
+            })
+        );
+    }
+
+    #[test]
+    fn only_new_commits_are_looked_at_until_a_buddy_restarts() {
+        let mut checker = InvariantChecker::default();
+        let mut a = buddy(0, 2, &[(1, "set x 1")]);
+        let mut b = buddy(1, 2, &[(1, "set x 1")]);
+        a.commit_index = 1;
+        b.commit_index = 1;
+
+        assert_eq!(checker.check([&a, &b]), Ok(()));
+        assert_eq!(checker.checked[&RaftId(1)], 1);
+
+        // Back from a restart with nothing committed, then committing
+        // something else at the same index.
+        b = buddy(1, 2, &[(2, "set x 2")]);
+        assert_eq!(checker.check([&a, &b]), Ok(()));
+        assert_eq!(checker.checked[&RaftId(1)], 0);
+
+        b.commit_index = 1;
+        assert_eq!(
+            checker.check([&a, &b]),
+            Err(Violation::StateMachineSafety {
+                index: 1,
+                buddies: (RaftId(0), RaftId(1))
+            })
+        );
+    }
+
+    #[test]
+    fn leaders_missing_earlier_commits_are_caught() {
+        let mut checker = InvariantChecker::default();
+        let mut follower = buddy(1, 1, &[(1, "set x 1")]);
+        follower.commit_index = 1;
+
+        assert_eq!(checker.check([&follower]), Ok(()));
+
+        // A stale leader from before the commit doesn't have to have it.
+        assert_eq!(checker.check([&leader(2, 0, &[])]), Ok(()));
+        assert_eq!(
+            checker.check([&leader(2, 2, &[])]),
+            Err(Violation::LeaderCompleteness {
+                leader: RaftId(2),
+                term: 2,
+                index: 1
+            })
+        );
+    }
+
+    #[test]
+    fn the_dump_shows_every_buddy() {
+        let dump = dump([&leader(0, 2, &[(1, "set x 1")]), &buddy(1, 2, &[])]);
+
+        assert_eq!(
+            dump,
+            "buddy 0: Leader in term 2, voted for None, committed 0, applied 0, base 0@0, log [1@1 \"set x 1\"]\n\
+             buddy 1: Follower in term 2, voted for None, committed 0, applied 0, base 0@0, log []"
+        );
+    }
+}
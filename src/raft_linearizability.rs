@@ -0,0 +1,628 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+
+use crate::raft_cluster::Cluster;
+use crate::raft_id::RaftId;
+
+/// One client operation: what was asked, when, and what came back.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Operation<I, O> {
+    pub client: usize,
+    pub input: I,
+    /// Tick the operation was invoked at.
+    pub call: u64,
+    /// What it returned and the tick it returned at. `None` if it never
+    /// did, in which case it may or may not have taken effect.
+    pub result: Option<(O, u64)>,
+}
+
+impl<I, O> Operation<I, O> {
+    /// When the operation returned, or forever if it never did.
+    fn returned(&self) -> u64 {
+        self.result.as_ref().map_or(u64::MAX, |(_, at)| *at)
+    }
+}
+
+/// Every operation clients have invoked, in the order they invoked them.
+#[derive(Clone, Debug)]
+pub struct History<I, O> {
+    /// `None` for operations known never to have taken effect.
+    operations: Vec<Option<Operation<I, O>>>,
+}
+
+impl<I, O> Default for History<I, O> {
+    fn default() -> Self {
+        Self { operations: vec![] }
+    }
+}
+
+impl<I: Clone, O: Clone> History<I, O> {
+    /// Record `input` being invoked, returning the id to complete it with.
+    pub fn invoke(&mut self, client: usize, input: I, at: u64) -> usize {
+        self.operations.push(Some(Operation {
+            client,
+            input,
+            call: at,
+            result: None,
+        }));
+
+        self.operations.len() - 1
+    }
+
+    pub fn complete(&mut self, id: usize, output: O, at: u64) {
+        if let Some(operation) = &mut self.operations[id] {
+            operation.result = Some((output, at));
+        }
+    }
+
+    /// Leave out an operation that's known not to have happened, so it
+    /// isn't left pending forever.
+    pub fn discard(&mut self, id: usize) {
+        self.operations[id] = None;
+    }
+
+    pub fn operations(&self) -> Vec<Operation<I, O>> {
+        self.operations.iter().flatten().cloned().collect()
+    }
+
+    pub fn completed(&self) -> usize {
+        self.operations
+            .iter()
+            .flatten()
+            .filter(|operation| operation.result.is_some())
+            .count()
+    }
+}
+
+/// A sequential specification to check histories against.
+pub trait Model {
+    type State: Clone + Eq + Hash + Debug;
+    type Input: Clone + Debug;
+    type Output: Clone + Debug;
+
+    fn init(&self) -> Self::State;
+
+    /// The state after applying `input` to `state`, or `None` if doing so
+    /// couldn't have returned `output`. Operations that never returned have
+    /// no `output`, and can return anything.
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State>;
+
+    /// Split a history into pieces that can be checked on their own, such as
+    /// one per key. A history is linearizable if every piece is.
+    fn partition(
+        &self,
+        history: Vec<Operation<Self::Input, Self::Output>>,
+    ) -> Vec<Vec<Operation<Self::Input, Self::Output>>> {
+        vec![history]
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum RegisterInput {
+    Read,
+    Write(String),
+}
+
+/// A single register that starts out empty. Reads return what's in it and
+/// writes return nothing.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RegisterModel;
+
+impl Model for RegisterModel {
+    type State = Option<String>;
+    type Input = RegisterInput;
+    type Output = Option<String>;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        match input {
+            RegisterInput::Read => output
+                .is_none_or(|output| output == state)
+                .then(|| state.clone()),
+            RegisterInput::Write(value) => output
+                .is_none_or(Option::is_none)
+                .then(|| Some(value.clone())),
+        }
+    }
+}
+
+/// The commands `KeyValueStore` understands.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum KeyValueInput {
+    Get(String),
+    Set(String, String),
+}
+
+impl KeyValueInput {
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Get(key) | Self::Set(key, _) => key,
+        }
+    }
+
+    /// The log entry to propose for it.
+    pub fn command(&self) -> String {
+        match self {
+            Self::Get(key) => format!("get {key}"),
+            Self::Set(key, value) => format!("set {key} {value}"),
+        }
+    }
+}
+
+/// A map from keys to values, checked one key at a time. Every key is its
+/// own register, so the state is only ever the one key's value.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyValueModel;
+
+impl Model for KeyValueModel {
+    type State = Option<String>;
+    type Input = KeyValueInput;
+    type Output = Option<String>;
+
+    fn init(&self) -> Self::State {
+        None
+    }
+
+    fn step(
+        &self,
+        state: &Self::State,
+        input: &Self::Input,
+        output: Option<&Self::Output>,
+    ) -> Option<Self::State> {
+        let input = match input {
+            KeyValueInput::Get(_) => RegisterInput::Read,
+            KeyValueInput::Set(_, value) => RegisterInput::Write(value.clone()),
+        };
+
+        RegisterModel.step(state, &input, output)
+    }
+
+    fn partition(
+        &self,
+        history: Vec<Operation<Self::Input, Self::Output>>,
+    ) -> Vec<Vec<Operation<Self::Input, Self::Output>>> {
+        let mut keys: BTreeMap<String, Vec<_>> = BTreeMap::new();
+
+        for operation in history {
+            keys.entry(operation.input.key().to_owned())
+                .or_default()
+                .push(operation);
+        }
+
+        keys.into_values().collect()
+    }
+}
+
+/// A history with no valid linearization, cut down as far as `shrink` can
+/// take it while it still fails.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct NonLinearizable<I, O> {
+    pub operations: Vec<Operation<I, O>>,
+}
+
+impl<I: Debug, O: Debug> fmt::Display for NonLinearizable<I, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "history isn't linearizable:")?;
+
+        for operation in &self.operations {
+            match &operation.result {
+                Some((output, at)) => writeln!(
+                    f,
+                    "  client {}: {:?} -> {output:?}, ticks {}..={at}",
+                    operation.client, operation.input, operation.call
+                )?,
+                None => writeln!(
+                    f,
+                    "  client {}: {:?} -> ?, ticks {}..",
+                    operation.client, operation.input, operation.call
+                )?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<I: Debug, O: Debug> std::error::Error for NonLinearizable<I, O> {}
+
+/// Check `history` against `model` the way Porcupine does: split it with the
+/// model's `partition`, then search each piece for an order that respects
+/// both real time and the model, skipping states already ruled out.
+pub fn check<M: Model>(
+    model: &M,
+    history: Vec<Operation<M::Input, M::Output>>,
+) -> Result<(), NonLinearizable<M::Input, M::Output>> {
+    for piece in model.partition(history) {
+        if !is_linearizable(model, &piece) {
+            return Err(NonLinearizable {
+                operations: shrink(model, piece),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+pub fn is_linearizable<M: Model>(model: &M, history: &[Operation<M::Input, M::Output>]) -> bool {
+    let mut linearized = vec![false; history.len()];
+    let mut ruled_out = HashSet::new();
+
+    search(
+        model,
+        history,
+        &mut linearized,
+        model.init(),
+        &mut ruled_out,
+    )
+}
+
+/// Try every operation that could come next, which is any one invoked
+/// before the earliest return among those left. Operations that never
+/// returned don't have to be linearized at all.
+fn search<M: Model>(
+    model: &M,
+    history: &[Operation<M::Input, M::Output>],
+    linearized: &mut Vec<bool>,
+    state: M::State,
+    ruled_out: &mut HashSet<(Vec<bool>, M::State)>,
+) -> bool {
+    let remaining = || (0..history.len()).filter(|&i| !linearized[i]);
+
+    if remaining().all(|i| history[i].result.is_none()) {
+        return true;
+    }
+
+    if !ruled_out.insert((linearized.clone(), state.clone())) {
+        return false;
+    }
+
+    let deadline = remaining()
+        .map(|i| history[i].returned())
+        .min()
+        .unwrap_or(u64::MAX);
+    let candidates: Vec<usize> = remaining()
+        .filter(|&i| history[i].call <= deadline)
+        .collect();
+
+    for i in candidates {
+        let operation = &history[i];
+        let output = operation.result.as_ref().map(|(output, _)| output);
+
+        if let Some(next) = model.step(&state, &operation.input, output) {
+            linearized[i] = true;
+
+            if search(model, history, linearized, next, ruled_out) {
+                return true;
+            }
+
+            linearized[i] = false;
+        }
+    }
+
+    false
+}
+
+/// Drop operations for as long as what's left still isn't linearizable.
+/// Where the operations could be put in some order if it weren't for real
+/// time, that has to stay true, so a read isn't left returning a value
+/// nobody wrote and the failure stays about when things happened.
+fn shrink<M: Model>(
+    model: &M,
+    mut history: Vec<Operation<M::Input, M::Output>>,
+) -> Vec<Operation<M::Input, M::Output>> {
+    let keep_timeless = is_linearizable(model, &timeless(&history));
+    let still_fails = |history: &[Operation<M::Input, M::Output>]| {
+        !is_linearizable(model, history)
+            && (!keep_timeless || is_linearizable(model, &timeless(history)))
+    };
+
+    // Dropping one operation can let us drop another we couldn't before.
+    let mut shrunk = true;
+
+    while shrunk {
+        shrunk = false;
+        let mut i = 0;
+
+        while i < history.len() {
+            let removed = history.remove(i);
+
+            if still_fails(&history) {
+                shrunk = true;
+            } else {
+                history.insert(i, removed);
+                i += 1;
+            }
+        }
+    }
+
+    history
+}
+
+/// The same operations, all overlapping one another.
+fn timeless<I: Clone, O: Clone>(history: &[Operation<I, O>]) -> Vec<Operation<I, O>> {
+    history
+        .iter()
+        .map(|operation| Operation {
+            call: 0,
+            result: operation
+                .result
+                .clone()
+                .map(|(output, _)| (output, u64::MAX)),
+            ..operation.clone()
+        })
+        .collect()
+}
+
+/// Clients issuing `KeyValueInput`s against a simulated cluster, recording
+/// each one's history. An operation returns when the leader it was
+/// proposed to applies it, and is discarded if that leader sees another
+/// entry applied in its place. Anything else stays pending.
+#[derive(Debug, Default)]
+pub struct KeyValueClients {
+    history: History<KeyValueInput, Option<String>>,
+    /// BTreeMap<(buddy, index), (term, operation id)>
+    pending: BTreeMap<(RaftId, usize), (usize, usize)>,
+}
+
+impl KeyValueClients {
+    /// Hand `input` to the leader. Returns false, recording nothing, if
+    /// there isn't one to take it.
+    pub fn invoke(&mut self, cluster: &mut Cluster, client: usize, input: KeyValueInput) -> bool {
+        let Some(leader) = cluster.leader() else {
+            return false;
+        };
+
+        let now = cluster.now();
+        let Some(index) = cluster.propose(leader, input.command()) else {
+            return false;
+        };
+
+        let buddy = cluster.buddy_mut(leader);
+        buddy.applied.get_or_insert_with(Vec::new);
+        let term = buddy.current_term;
+        let id = self.history.invoke(client, input, now);
+        self.pending.insert((leader, index), (term, id));
+
+        true
+    }
+
+    /// Pick up whatever's been applied since the last poll. Call it after
+    /// every tick.
+    pub fn poll(&mut self, cluster: &mut Cluster) {
+        let now = cluster.now();
+
+        for buddy in &mut cluster.buddies {
+            if !self.pending.keys().any(|&(id, _)| id == buddy.id) {
+                continue;
+            }
+
+            // A restart loses whatever was keeping track.
+            let applied = std::mem::take(buddy.applied.get_or_insert_with(Vec::new));
+
+            for applied in applied {
+                let Some((term, id)) = self.pending.remove(&(buddy.id, applied.index)) else {
+                    continue;
+                };
+
+                if applied.term == term {
+                    self.history.complete(id, applied.output, now);
+                } else {
+                    self.history.discard(id);
+                }
+            }
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn history(&self) -> &History<KeyValueInput, Option<String>> {
+        &self.history
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_network::Faults;
+    use crate::raft_rng::Rng;
+
+    fn op<I>(
+        client: usize,
+        input: I,
+        call: u64,
+        output: Option<&str>,
+        ret: u64,
+    ) -> Operation<I, Option<String>> {
+        Operation {
+            client,
+            input,
+            call,
+            result: Some((output.map(str::to_owned), ret)),
+        }
+    }
+
+    fn pending<I>(client: usize, input: I, call: u64) -> Operation<I, Option<String>> {
+        Operation {
+            client,
+            input,
+            call,
+            result: None,
+        }
+    }
+
+    fn write(value: &str) -> RegisterInput {
+        RegisterInput::Write(value.to_owned())
+    }
+
+    #[test]
+    fn sequential_register_histories_are_linearizable() {
+        let history = vec![
+            op(0, RegisterInput::Read, 0, None, 1),
+            op(0, write("1"), 2, None, 3),
+            op(1, RegisterInput::Read, 4, Some("1"), 5),
+        ];
+
+        assert_eq!(check(&RegisterModel, history), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_reads_can_see_either_value() {
+        for seen in [None, Some("1")] {
+            let history = vec![
+                op(0, write("1"), 0, None, 10),
+                op(1, RegisterInput::Read, 2, seen, 4),
+            ];
+
+            assert_eq!(check(&RegisterModel, history), Ok(()));
+        }
+    }
+
+    #[test]
+    fn stale_reads_are_not_linearizable() {
+        let history = vec![
+            op(0, write("1"), 0, None, 1),
+            op(0, write("2"), 2, None, 3),
+            op(1, RegisterInput::Read, 4, Some("1"), 5),
+        ];
+
+        assert!(check(&RegisterModel, history).is_err());
+    }
+
+    #[test]
+    fn pending_writes_may_or_may_not_have_happened() {
+        let taken = vec![
+            pending(0, write("1"), 0),
+            op(1, RegisterInput::Read, 5, Some("1"), 6),
+        ];
+        let not_taken = vec![
+            pending(0, write("1"), 0),
+            op(1, RegisterInput::Read, 5, None, 6),
+        ];
+
+        assert_eq!(check(&RegisterModel, taken), Ok(()));
+        assert_eq!(check(&RegisterModel, not_taken), Ok(()));
+    }
+
+    #[test]
+    fn reads_cannot_see_writes_that_were_never_made() {
+        let history = vec![op(1, RegisterInput::Read, 0, Some("1"), 1)];
+
+        assert!(check(&RegisterModel, history).is_err());
+    }
+
+    #[test]
+    fn failures_are_cut_down_to_what_matters() {
+        let history = vec![
+            op(0, write("1"), 0, None, 1),
+            op(2, RegisterInput::Read, 1, Some("1"), 2),
+            op(0, write("2"), 3, None, 4),
+            op(2, write("3"), 3, None, 9),
+            op(1, RegisterInput::Read, 5, Some("1"), 6),
+            op(2, RegisterInput::Read, 10, Some("3"), 11),
+        ];
+
+        let failure = check(&RegisterModel, history).unwrap_err();
+
+        assert_eq!(
+            failure.operations,
+            [
+                op(0, write("1"), 0, None, 1),
+                op(0, write("2"), 3, None, 4),
+                op(1, RegisterInput::Read, 5, Some("1"), 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn keys_are_checked_on_their_own() {
+        let set = |key: &str, value: &str| KeyValueInput::Set(key.to_owned(), value.to_owned());
+        let get = |key: &str| KeyValueInput::Get(key.to_owned());
+        let history = vec![
+            op(0, set("x", "1"), 0, None, 1),
+            op(0, set("y", "1"), 2, None, 3),
+            op(1, get("x"), 4, Some("1"), 5),
+            op(1, get("y"), 6, None, 7),
+        ];
+
+        let failure = check(&KeyValueModel, history).unwrap_err();
+
+        assert_eq!(
+            failure.operations,
+            [
+                op(0, set("y", "1"), 2, None, 3),
+                op(1, get("y"), 6, None, 7)
+            ]
+        );
+        assert!(failure.to_string().contains("client 1: Get(\"y\") -> None"));
+    }
+
+    #[test]
+    fn clients_of_a_faulty_crashing_cluster_see_a_linearizable_history() {
+        let mut cluster = Cluster::new(5, 31);
+        let mut clients = KeyValueClients::default();
+        let mut rng = Rng::new(31);
+        let keys = ["x", "y"];
+
+        cluster.network.set_faults(Faults {
+            drop: 0.05,
+            duplicate: 0.05,
+            reorder: 0.05,
+            delay: 0.1,
+            max_delay: 5,
+        });
+
+        for tick in 0..3_000 {
+            if tick == 1_000 {
+                if let Some(leader) = cluster.leader() {
+                    cluster.crash(leader);
+                }
+            }
+            if tick == 1_500 {
+                let crashed: Vec<_> = cluster
+                    .buddies
+                    .iter()
+                    .map(|buddy| buddy.id)
+                    .filter(|&id| cluster.is_crashed(id))
+                    .collect();
+                crashed.into_iter().for_each(|id| cluster.restart(id));
+            }
+
+            if rng.chance(0.05) {
+                let client = rng.range(0..3);
+                let key = keys[rng.range(0..keys.len())].to_owned();
+                let input = if rng.chance(0.5) {
+                    KeyValueInput::Get(key)
+                } else {
+                    KeyValueInput::Set(key, tick.to_string())
+                };
+
+                clients.invoke(&mut cluster, client, input);
+            }
+
+            cluster.tick();
+            clients.poll(&mut cluster);
+        }
+
+        assert!(clients.history().completed() > 50);
+        assert_eq!(
+            check(&KeyValueModel, clients.history().operations()),
+            Ok(())
+        );
+    }
+}
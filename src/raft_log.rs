@@ -1,20 +1,35 @@
-use std::ops::{Deref, DerefMut};
-
-#[derive(PartialEq, Eq, Clone, Debug)]
-pub enum LogEntry {
-    Root,
-    Node {
-        term: usize,
-        index: usize,
-        // Might be nice for the contents to be an Option or something
-        contents: String,
-    },
+use std::ops::Index;
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogEntry {
+    pub term: usize,
+    pub index: usize,
+    // Might be nice for the contents to be an Option or something
+    pub contents: String,
 }
 
-#[derive(Debug)]
-pub struct RaftLog(Vec<LogEntry>);
+/// The point the log has been compacted up to. Everything up to and including
+/// `last_included_index` lives in a snapshot rather than in the log. A fresh
+/// log has a base of `(0, 0)`, which stands in for the old `Root` sentinel.
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SnapshotBase {
+    pub last_included_index: usize,
+    pub last_included_term: usize,
+}
+
+/// Entries are addressed by their Raft index, not by their position in the
+/// underlying `Vec`, so `log[5]` is the entry with index 5 no matter how much
+/// of the log has been compacted away.
+#[derive(PartialEq, Eq, Hash, Debug, Default, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RaftLog {
+    base: SnapshotBase,
+    entries: Vec<LogEntry>,
+}
 
-trait RaftAppendable {
+pub trait RaftAppendable {
     fn append_entries(&mut self, prev_index: usize, prev_term: usize, entries: &[LogEntry])
         -> bool;
 }
@@ -30,60 +45,198 @@ impl RaftAppendable for RaftLog {
         prev_term: usize,
         entries: &[LogEntry],
     ) -> bool {
-        let Some(prev_entry) = self.get(prev_index) else {
-            eprintln!("Could not get log entry for index {prev_index}");
+        // Every entry has to follow on from the one before it, not just the
+        // first, or a gap further along would end up in the log.
+        let entries_are_contiguous = (1..)
+            .zip(entries)
+            .all(|(offset, entry)| entry.index.checked_sub(offset) == Some(prev_index));
+
+        if !entries_are_contiguous {
             return false;
+        }
+
+        // Entries at or below the base are already in a snapshot, so they're
+        // committed and can't conflict with anything. Skip past them and
+        // treat the base as the previous entry instead.
+        let (prev_index, prev_term, entries) = if prev_index < self.base.last_included_index {
+            let covered = self.base.last_included_index - prev_index;
+
+            if entries.len() <= covered {
+                return true;
+            }
+
+            (
+                self.base.last_included_index,
+                self.base.last_included_term,
+                &entries[covered..],
+            )
+        } else {
+            (prev_index, prev_term, entries)
         };
 
-        let prev_term_matches = match prev_entry {
-            LogEntry::Root => true,
-            LogEntry::Node { term, index, .. } => *term == prev_term && *index == prev_index,
+        let Some(term) = self.term_at(prev_index) else {
+            eprintln!("Could not get log entry for index {prev_index}");
+            return false;
         };
 
-        let new_entry = entries.first();
+        let prev_term_matches = term == prev_term;
+
+        if prev_term_matches {
+            // Only a conflicting entry and everything after it goes. Anything
+            // we already have is left where it is, so a stale or replayed
+            // request can't take back entries appended since.
+            for entry in entries {
+                match self.term_at(entry.index) {
+                    Some(term) if term == entry.term => continue,
+                    Some(_) => {
+                        self.truncate_after(entry.index - 1);
+                        self.entries.push(entry.clone());
+                    }
+                    None => self.entries.push(entry.clone()),
+                }
+            }
+        }
 
-        if new_entry.is_none() {
-            return true;
+        prev_term_matches
+    }
+}
+
+impl RaftLog {
+    pub fn with_base(base: SnapshotBase) -> Self {
+        Self {
+            base,
+            entries: vec![],
         }
+    }
 
-        let new_entry_is_contiguous = match new_entry.unwrap() {
-            LogEntry::Node { index, .. } => *index == prev_index + 1,
-            LogEntry::Root => false,
-        };
+    pub fn base(&self) -> SnapshotBase {
+        self.base
+    }
+
+    /// Index of the first entry still held in the log.
+    pub fn first_index(&self) -> usize {
+        self.base.last_included_index + 1
+    }
 
-        if prev_term_matches && new_entry_is_contiguous {
-            let mut entries = entries.to_vec();
-            self.truncate(prev_index + 1);
-            self.append(&mut entries);
+    pub fn last_index(&self) -> usize {
+        self.entries
+            .last()
+            .map_or(self.base.last_included_index, |entry| entry.index)
+    }
+
+    pub fn last_term(&self) -> usize {
+        self.entries
+            .last()
+            .map_or(self.base.last_included_term, |entry| entry.term)
+    }
+
+    /// Length of the log as though nothing had been compacted, counting the
+    /// base at index 0. This is what the log length used to be when the log
+    /// started with a `Root` entry. It's never 0, so there's no `is_empty`.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.last_index() + 1
+    }
+
+    pub fn get(&self, index: usize) -> Option<&LogEntry> {
+        index
+            .checked_sub(self.first_index())
+            .and_then(|position| self.entries.get(position))
+    }
+
+    pub fn last(&self) -> Option<&LogEntry> {
+        self.entries.last()
+    }
+
+    /// Term of the entry at `index`, including the base, which has no entry
+    /// of its own but still knows the term it was compacted at.
+    pub fn term_at(&self, index: usize) -> Option<usize> {
+        if index == self.base.last_included_index {
+            Some(self.base.last_included_term)
+        } else {
+            self.get(index).map(|entry| entry.term)
         }
+    }
 
-        prev_term_matches && new_entry_is_contiguous
+    pub fn iter(&self) -> std::slice::Iter<'_, LogEntry> {
+        self.entries.iter()
     }
-}
 
-impl Deref for RaftLog {
-    type Target = Vec<LogEntry>;
+    /// Every entry from `index` onwards that's still in the log.
+    pub fn entries_from(&self, index: usize) -> &[LogEntry] {
+        let position = index.saturating_sub(self.first_index());
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.entries[position.min(self.entries.len())..]
     }
-}
 
-impl DerefMut for RaftLog {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+    /// Tack an entry onto the end of the log. Unlike `append_entries` this
+    /// doesn't check anything, so it's only for the leader's own proposals.
+    pub fn push(&mut self, entry: LogEntry) {
+        self.entries.push(entry);
+    }
+
+    /// Every entry follows on from the one before it, starting right after
+    /// the base, and there's room after the base for them. Logs we build
+    /// ourselves always are, but ones read back from elsewhere need checking.
+    pub fn is_well_formed(&self) -> bool {
+        let Some(first_index) = self.base.last_included_index.checked_add(1) else {
+            return false;
+        };
+
+        self.entries
+            .iter()
+            .zip(first_index..)
+            .all(|(entry, index)| entry.index == index)
+    }
+
+    /// Discard every entry up to and including `index`, making it the new
+    /// base. Returns false if `index` has already been compacted or isn't in
+    /// the log yet.
+    pub fn compact(&mut self, index: usize) -> bool {
+        if index <= self.base.last_included_index {
+            return false;
+        }
+
+        let Some(term) = self.term_at(index) else {
+            return false;
+        };
+
+        self.entries.drain(..=(index - self.first_index()));
+        self.base = SnapshotBase {
+            last_included_index: index,
+            last_included_term: term,
+        };
+
+        true
+    }
+
+    /// Drop every entry after `index`.
+    fn truncate_after(&mut self, index: usize) {
+        self.entries
+            .truncate(index.saturating_sub(self.base.last_included_index));
     }
 }
 
-impl Default for RaftLog {
-    fn default() -> Self {
-        Self(vec![LogEntry::Root])
+impl Index<usize> for RaftLog {
+    type Output = LogEntry;
+
+    fn index(&self, index: usize) -> &Self::Output {
+        self.get(index).unwrap_or_else(|| {
+            panic!(
+                "Index {index} is outside of the log, which holds {}..={}",
+                self.first_index(),
+                self.last_index()
+            )
+        })
     }
 }
 
 impl From<&[LogEntry]> for RaftLog {
     fn from(entries: &[LogEntry]) -> Self {
-        RaftLog(entries.to_owned())
+        RaftLog {
+            base: SnapshotBase::default(),
+            entries: entries.to_owned(),
+        }
     }
 }
 
@@ -92,22 +245,25 @@ mod tests {
     use super::*;
 
     #[test]
-    fn it_has_an_empty_log_at_the_root() {
+    fn it_starts_with_an_empty_base() {
         let log = RaftLog::default();
 
-        assert!(log[0] == LogEntry::Root)
+        assert!(log.base() == SnapshotBase::default());
+        assert!(log.last_index() == 0);
+        assert!(log.term_at(0) == Some(0));
+        assert!(log.get(0).is_none());
     }
 
     #[test]
     fn it_appends() {
         let mut log = RaftLog::default();
-        let log_entry = LogEntry::Node {
+        let log_entry = LogEntry {
             term: 1,
             index: 1,
             contents: "set x 42".to_owned(),
         };
 
-        let can_append = log.append_entries(0, 0, &[log_entry.clone()]);
+        let can_append = log.append_entries(0, 0, std::slice::from_ref(&log_entry));
 
         assert!(can_append);
         assert!(log.len() == 2);
@@ -121,7 +277,7 @@ mod tests {
         let cannot_append = !log.append_entries(
             0,
             0,
-            &[LogEntry::Node {
+            &[LogEntry {
                 term: 1,
                 index: 2,
                 contents: "set x 42".to_owned(),
@@ -131,19 +287,43 @@ mod tests {
         assert!(cannot_append);
     }
 
+    #[test]
+    fn it_does_not_append_with_gap_after_the_first_entry() {
+        let mut log = RaftLog::default();
+
+        let cannot_append = !log.append_entries(
+            0,
+            0,
+            &[
+                LogEntry {
+                    term: 1,
+                    index: 1,
+                    contents: "set x 1".to_owned(),
+                },
+                LogEntry {
+                    term: 1,
+                    index: 3,
+                    contents: "set x 3".to_owned(),
+                },
+            ],
+        );
+
+        assert!(cannot_append);
+        assert!(log.last_index() == 0);
+    }
+
     #[test]
     // This test was green to start with so either I'm doing something wrong or
     // I'm doing something right, but clearly I don't know which
     fn it_does_not_append_when_current_term_is_higher() {
         let mut log = RaftLog::from(
             [
-                LogEntry::Root,
-                LogEntry::Node {
+                LogEntry {
                     term: 1,
                     index: 1,
                     contents: "set x 42".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 2,
                     index: 2,
                     contents: "set x 24".to_owned(),
@@ -155,7 +335,7 @@ mod tests {
         let cannot_append = !log.append_entries(
             2,
             0,
-            &[LogEntry::Node {
+            &[LogEntry {
                 term: 1,
                 index: 3,
                 contents: "set x 42".to_owned(),
@@ -179,18 +359,17 @@ mod tests {
     fn it_returns_true_for_matching_log_entries() {
         let mut log = RaftLog::from(
             [
-                LogEntry::Root,
-                LogEntry::Node {
+                LogEntry {
                     term: 1,
                     index: 1,
                     contents: "set x 1".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 2,
                     index: 2,
                     contents: "set x 2".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 3,
                     index: 3,
                     contents: "set x 3".to_owned(),
@@ -203,17 +382,17 @@ mod tests {
             0,
             0,
             [
-                LogEntry::Node {
+                LogEntry {
                     term: 1,
                     index: 1,
                     contents: "set x 1".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 2,
                     index: 2,
                     contents: "set x 2".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 3,
                     index: 3,
                     contents: "set x 3".to_owned(),
@@ -229,18 +408,17 @@ mod tests {
     fn it_truncates_logs() {
         let mut log = RaftLog::from(
             [
-                LogEntry::Root,
-                LogEntry::Node {
+                LogEntry {
                     term: 1,
                     index: 1,
                     contents: "set x 1".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 2,
                     index: 2,
                     contents: "set x 2".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 3,
                     index: 3,
                     contents: "set x 3".to_owned(),
@@ -253,17 +431,17 @@ mod tests {
             0,
             0,
             [
-                LogEntry::Node {
+                LogEntry {
                     term: 4,
                     index: 1,
                     contents: "set x 1".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 5,
                     index: 2,
                     contents: "set x 2".to_owned(),
                 },
-                LogEntry::Node {
+                LogEntry {
                     term: 6,
                     index: 3,
                     contents: "set x 3".to_owned(),
@@ -281,11 +459,298 @@ mod tests {
 
         assert!(
             log.last()
-                == Some(&LogEntry::Node {
+                == Some(&LogEntry {
                     term: 6,
                     index: 3,
                     contents: "set x 3".to_owned(),
                 })
         );
     }
+
+    fn log_with_terms(terms: &[usize]) -> RaftLog {
+        let entries: Vec<LogEntry> = terms
+            .iter()
+            .enumerate()
+            .map(|(position, &term)| LogEntry {
+                term,
+                index: position + 1,
+                contents: format!("set x {}", position + 1),
+            })
+            .collect();
+
+        RaftLog::from(entries.as_slice())
+    }
+
+    #[test]
+    fn it_compacts_a_prefix_into_the_base() {
+        let mut log = log_with_terms(&[1, 1, 2, 3]);
+
+        assert!(log.compact(2));
+
+        assert!(
+            log.base()
+                == SnapshotBase {
+                    last_included_index: 2,
+                    last_included_term: 1,
+                }
+        );
+        assert!(log.first_index() == 3);
+        assert!(log.last_index() == 4);
+        assert!(log.len() == 5);
+        assert!(log.get(2).is_none());
+        assert!(log.term_at(2) == Some(1));
+        assert!(log[3].term == 2);
+    }
+
+    #[test]
+    fn it_does_not_compact_past_the_end_or_behind_the_base() {
+        let mut log = log_with_terms(&[1, 1, 2]);
+
+        assert!(!log.compact(4));
+        assert!(log.compact(2));
+        assert!(!log.compact(1));
+        assert!(!log.compact(2));
+        assert!(log.last_index() == 3);
+    }
+
+    #[test]
+    fn it_appends_after_compaction() {
+        let mut log = log_with_terms(&[1, 1, 2]);
+        log.compact(3);
+
+        let can_append = log.append_entries(
+            3,
+            2,
+            &[LogEntry {
+                term: 2,
+                index: 4,
+                contents: "set x 4".to_owned(),
+            }],
+        );
+
+        assert!(can_append);
+        assert!(log.first_index() == 4);
+        assert!(log.last_index() == 4);
+    }
+
+    #[test]
+    fn it_does_not_append_when_base_term_mismatches() {
+        let mut log = log_with_terms(&[1, 1, 2]);
+        log.compact(3);
+
+        let cannot_append = !log.append_entries(
+            3,
+            1,
+            &[LogEntry {
+                term: 2,
+                index: 4,
+                contents: "set x 4".to_owned(),
+            }],
+        );
+
+        assert!(cannot_append);
+    }
+
+    #[test]
+    fn it_skips_entries_already_covered_by_the_base() {
+        let mut log = log_with_terms(&[1, 1, 2]);
+        log.compact(2);
+
+        let can_append = log.append_entries(
+            1,
+            1,
+            &[
+                LogEntry {
+                    term: 1,
+                    index: 2,
+                    contents: "set x 2".to_owned(),
+                },
+                LogEntry {
+                    term: 3,
+                    index: 3,
+                    contents: "set x 3".to_owned(),
+                },
+            ],
+        );
+
+        assert!(can_append);
+        assert!(log.first_index() == 3);
+        assert!(log.term_at(3) == Some(3));
+    }
+
+    mod properties {
+        use super::*;
+        use proptest::collection::vec;
+        use proptest::prelude::*;
+
+        /// A leader's log, a follower's log and an AppendEntries request the
+        /// leader could send it. The logs share a prefix, part of which the
+        /// follower may have compacted, and then each goes its own way in
+        /// terms the other never had, the way they can after a leader change.
+        #[derive(Clone, Debug)]
+        struct Scenario {
+            leader: RaftLog,
+            follower: RaftLog,
+            prev_index: usize,
+            last_index: usize,
+        }
+
+        impl Scenario {
+            fn prev_term(&self) -> usize {
+                self.leader.term_at(self.prev_index).unwrap()
+            }
+
+            fn entries(&self) -> Vec<LogEntry> {
+                self.leader.entries_from(self.prev_index + 1)[..self.last_index - self.prev_index]
+                    .to_vec()
+            }
+
+            /// Send the request to a copy of the follower's log.
+            fn append(&self) -> (bool, RaftLog) {
+                let mut follower = self.follower.clone();
+                let appended =
+                    follower.append_entries(self.prev_index, self.prev_term(), &self.entries());
+
+                (appended, follower)
+            }
+        }
+
+        /// Running totals of `steps`, starting from `start`, so terms never go
+        /// backwards.
+        fn terms(start: usize, steps: &[usize]) -> Vec<usize> {
+            steps
+                .iter()
+                .scan(start, |term, step| {
+                    *term += step;
+                    Some(*term)
+                })
+                .collect()
+        }
+
+        prop_compose! {
+            fn logs()(
+                shared in vec(0..3usize, 0..8),
+                leader_own in vec(0..3usize, 0..6),
+                follower_own in vec(0..3usize, 0..6),
+            ) -> (Vec<usize>, Vec<usize>) {
+                // Shared terms are even, the leader's own are later even ones
+                // and the follower's are odd, so they differ wherever they
+                // aren't shared.
+                let shared: Vec<usize> = terms(1, &shared).iter().map(|term| term * 2).collect();
+                let last = shared.last().map_or(0, |term| term / 2);
+                let leader_own = terms(last + 1, &leader_own).into_iter().map(|term| term * 2);
+                let follower_own = terms(last, &follower_own).into_iter().map(|term| term * 2 + 1);
+
+                (
+                    shared.iter().copied().chain(leader_own).collect(),
+                    shared.iter().copied().chain(follower_own).collect(),
+                )
+            }
+        }
+
+        prop_compose! {
+            fn scenarios()((leader, follower) in logs())(
+                a in 0..=leader.len(),
+                b in 0..=leader.len(),
+                compacted in 0..=shared_len(&leader, &follower),
+                leader in Just(leader),
+                follower in Just(follower),
+            ) -> Scenario {
+                let mut follower = log_with_terms(&follower);
+                follower.compact(compacted);
+
+                Scenario {
+                    leader: log_with_terms(&leader),
+                    follower,
+                    prev_index: a.min(b),
+                    last_index: a.max(b),
+                }
+            }
+        }
+
+        fn shared_len(a: &[usize], b: &[usize]) -> usize {
+            a.iter().zip(b).take_while(|(a, b)| a == b).count()
+        }
+
+        /// The last index at which the two logs hold the same term.
+        fn last_match(a: &RaftLog, b: &RaftLog) -> usize {
+            (0..=a.last_index().min(b.last_index()))
+                .rev()
+                .find(|&index| a.term_at(index).is_some() && a.term_at(index) == b.term_at(index))
+                .unwrap_or(0)
+        }
+
+        /// If two logs hold an entry with the same index and term, they hold
+        /// the same entries up to there, as far as both still have them.
+        fn logs_match(a: &RaftLog, b: &RaftLog) -> bool {
+            let matched = last_match(a, b);
+            let first = a.first_index().max(b.first_index());
+
+            (first..=matched).all(|index| a.get(index) == b.get(index))
+        }
+
+        fn same_log(a: &RaftLog, b: &RaftLog) -> bool {
+            a.base() == b.base() && a.iter().eq(b.iter())
+        }
+
+        proptest! {
+            #[test]
+            fn it_appends_exactly_when_the_previous_entry_matches(scenario in scenarios()) {
+                let (appended, follower) = scenario.append();
+                let base = scenario.follower.base().last_included_index;
+                let matches = scenario.prev_index < base
+                    || scenario.follower.term_at(scenario.prev_index) == Some(scenario.prev_term());
+
+                prop_assert_eq!(appended, matches);
+                prop_assert!(follower.is_well_formed());
+
+                if !appended {
+                    prop_assert!(same_log(&follower, &scenario.follower));
+                }
+            }
+
+            #[test]
+            fn appended_logs_match_the_leader(scenario in scenarios()) {
+                let (appended, follower) = scenario.append();
+                prop_assume!(appended);
+
+                for index in follower.first_index()..=scenario.last_index {
+                    prop_assert_eq!(follower.get(index), scenario.leader.get(index));
+                }
+                prop_assert!(logs_match(&follower, &scenario.leader));
+            }
+
+            #[test]
+            fn replaying_a_request_changes_nothing(scenario in scenarios()) {
+                let (appended, mut follower) = scenario.append();
+                let once = follower.clone();
+
+                let again = follower.append_entries(
+                    scenario.prev_index,
+                    scenario.prev_term(),
+                    &scenario.entries(),
+                );
+
+                prop_assert_eq!(appended, again);
+                prop_assert!(same_log(&follower, &once));
+            }
+
+            #[test]
+            fn matching_prefixes_are_never_truncated(scenario in scenarios()) {
+                let (appended, follower) = scenario.append();
+                prop_assume!(appended);
+                let matched = last_match(&scenario.follower, &scenario.leader);
+
+                for index in scenario.follower.first_index()..=matched {
+                    prop_assert_eq!(follower.get(index), scenario.follower.get(index));
+                }
+
+                // A request with nothing new in it leaves even the follower's
+                // own entries alone.
+                if scenario.last_index <= matched {
+                    prop_assert!(same_log(&follower, &scenario.follower));
+                }
+            }
+        }
+    }
 }
@@ -1,15 +1,103 @@
-use crate::raft_id::RaftId;
+use crate::raft_compression::Compression;
+use crate::raft_id::{ClusterId, RaftId};
+use crate::raft_log::LogEntry;
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
-pub struct RaftMessageBody {
-    pub id: RaftId,
-    pub current_term: usize,
-    pub log_length: usize,
+/// What every message travels in. The payload only carries what's particular
+/// to its kind; who it's from and to, the sender's term, and which cluster it
+/// belongs to are the same for all of them.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Envelope {
+    pub from: RaftId,
+    pub to: RaftId,
+    /// The sender's current term when it sent the message.
+    pub term: usize,
+    pub cluster_id: ClusterId,
+    pub message: RaftMessage,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+impl Envelope {
+    /// An AppendEntries with nothing to append, which only matters until the
+    /// next one from the same leader.
+    pub fn is_heartbeat(&self) -> bool {
+        matches!(&self.message, RaftMessage::AppendEntries(body) if body.entries.is_empty())
+    }
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RequestVoteBody {
+    pub last_log_index: usize,
+    pub last_log_term: usize,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AppendEntriesBody {
+    pub prev_log_index: usize,
+    pub prev_log_term: usize,
+    pub entries: Vec<LogEntry>,
+    pub leader_commit: usize,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct AppendEntriesResponseBody {
+    pub success: bool,
+    /// On success, the last index the follower now shares with the leader. On
+    /// failure, the follower's last index, so the leader knows how far back to
+    /// start over from.
+    pub last_log_index: usize,
+}
+
+/// One chunk of a snapshot, starting `offset` bytes into the uncompressed
+/// snapshot. Each chunk is compressed on its own, so `data` can be inflated
+/// without any of the chunks around it.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InstallSnapshotBody {
+    pub last_included_index: usize,
+    pub last_included_term: usize,
+    pub offset: usize,
+    pub data: Vec<u8>,
+    pub compression: Compression,
+    pub done: bool,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InstallSnapshotResponseBody {
+    pub last_included_index: usize,
+    /// Where the follower wants the next chunk to start.
+    pub next_offset: usize,
+    /// The follower has installed this snapshot, or one at least as recent.
+    pub done: bool,
+    /// The best compression the follower can read. The leader starts a
+    /// transfer uncompressed and sends the rest with whatever they have in
+    /// common.
+    pub compression: Compression,
+}
+
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum RaftMessage {
-    RequestVote(RaftMessageBody),
-    VoteForCandidate(RaftMessageBody),
-    RejectCandidateVote(RaftMessageBody),
+    RequestVote(RequestVoteBody),
+    VoteForCandidate,
+    RejectCandidateVote,
+    AppendEntries(AppendEntriesBody),
+    AppendEntriesResponse(AppendEntriesResponseBody),
+    InstallSnapshot(InstallSnapshotBody),
+    InstallSnapshotResponse(InstallSnapshotResponseBody),
+}
+
+impl RaftMessage {
+    /// Requests expect an answer even when they're from a stale term, so the
+    /// sender finds out it's behind. Responses to a stale term can just be
+    /// dropped.
+    pub fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Self::RequestVote(..) | Self::AppendEntries(..) | Self::InstallSnapshot(..)
+        )
+    }
 }
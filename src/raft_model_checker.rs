@@ -0,0 +1,495 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::raft_buddy::{Progress, RaftBuddy, Role};
+use crate::raft_id::RaftId;
+use crate::raft_invariants::{dump, InvariantChecker, Violation};
+use crate::raft_log::RaftLog;
+use crate::raft_message::{Envelope, RaftMessage};
+use crate::raft_state_machine::KeyValueStore;
+use crate::raft_topology::Topology;
+
+/// One thing that can happen next to a cluster being model checked.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Action {
+    /// A message in flight arrives and is handled straight away.
+    Deliver(Envelope),
+    /// A message in flight is lost.
+    Drop(Envelope),
+    /// The buddy's election timer, or its heartbeat if it's leader, runs out.
+    TimeOut(RaftId),
+    /// The leader is handed a command to replicate.
+    Propose(RaftId),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = |envelope: &Envelope| {
+            format!(
+                "{:?} from buddy {} to buddy {} in term {}",
+                envelope.message, *envelope.from, *envelope.to, envelope.term
+            )
+        };
+
+        match self {
+            Self::Deliver(envelope) => write!(f, "deliver {}", message(envelope)),
+            Self::Drop(envelope) => write!(f, "drop {}", message(envelope)),
+            Self::TimeOut(id) => write!(f, "buddy {} times out", **id),
+            Self::Propose(id) => write!(f, "buddy {} is proposed to", **id),
+        }
+    }
+}
+
+/// A safety property that didn't hold, and the shortest way there from
+/// where the checker started.
+#[derive(Debug)]
+pub struct Counterexample {
+    pub violation: Violation,
+    pub trace: Vec<Action>,
+    /// Every buddy as it was once the last action was taken.
+    pub buddies: String,
+}
+
+impl fmt::Display for Counterexample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} after {} steps:", self.violation, self.trace.len())?;
+
+        for (step, action) in (1..).zip(&self.trace) {
+            writeln!(f, "{step:>4}. {action}")?;
+        }
+
+        write!(f, "{}", self.buddies)
+    }
+}
+
+impl std::error::Error for Counterexample {}
+
+/// How much of the state space a check got through.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Explored {
+    /// Distinct states reached, counting the first.
+    pub states: usize,
+    /// The most actions it took to reach any of them.
+    pub depth: usize,
+}
+
+/// Everything about a buddy the protocol depends on. Timers are left out
+/// because the checker decides when they run out, and storage because
+/// nobody restarts.
+#[derive(PartialEq, Eq, Hash, Clone, Debug)]
+struct BuddyState {
+    role: Role,
+    current_term: usize,
+    voted_for: Option<RaftId>,
+    log: RaftLog,
+    votes_received: BTreeMap<RaftId, RaftMessage>,
+    commit_index: usize,
+    last_applied: usize,
+    state_machine: KeyValueStore,
+    progress: BTreeMap<RaftId, Progress>,
+}
+
+impl BuddyState {
+    fn of(buddy: &RaftBuddy) -> Self {
+        Self {
+            role: buddy.role.clone(),
+            current_term: buddy.current_term,
+            voted_for: buddy.voted_for,
+            log: buddy.log.clone(),
+            votes_received: buddy.votes_received.clone(),
+            commit_index: buddy.commit_index,
+            last_applied: buddy.last_applied,
+            state_machine: buddy.state_machine.clone(),
+            progress: buddy.progress.clone(),
+        }
+    }
+
+    /// Snapshots would only add states that say the same thing as the log
+    /// they came from, so they're never taken.
+    fn buddy(&self, id: RaftId, topology: Topology) -> RaftBuddy {
+        RaftBuddy {
+            role: self.role.clone(),
+            id,
+            topology,
+            current_term: self.current_term,
+            voted_for: self.voted_for,
+            log: self.log.clone(),
+            votes_received: self.votes_received.clone(),
+            commit_index: self.commit_index,
+            last_applied: self.last_applied,
+            state_machine: self.state_machine.clone(),
+            progress: self.progress.clone(),
+            snapshot_policy: None,
+            ..Default::default()
+        }
+    }
+}
+
+/// The whole cluster between two actions.
+#[derive(Clone, Debug)]
+struct State {
+    buddies: Vec<BuddyState>,
+    /// Every message sent and not yet delivered or dropped, in no
+    /// particular order.
+    in_flight: Vec<Envelope>,
+    proposals: usize,
+    /// The properties are about history as well as the moment, so two
+    /// states are only the same if what's been seen on the way is too.
+    invariants: InvariantChecker,
+}
+
+impl State {
+    /// Messages in flight are summed rather than hashed one after another,
+    /// so the order they were sent in doesn't matter.
+    fn fingerprint(&self) -> u64 {
+        let in_flight = self.in_flight.iter().map(hash).fold(0, u64::wrapping_add);
+
+        hash(&(&self.buddies, in_flight, self.proposals, &self.invariants))
+    }
+
+    /// Whether the same messages are in flight, each as many times, in
+    /// whatever order.
+    fn same_in_flight(&self, other: &State) -> bool {
+        let count = |in_flight: &[Envelope], envelope| {
+            in_flight.iter().filter(|sent| *sent == envelope).count()
+        };
+
+        self.in_flight.len() == other.in_flight.len()
+            && self.in_flight.iter().all(|envelope| {
+                count(&self.in_flight, envelope) == count(&other.in_flight, envelope)
+            })
+    }
+
+    fn buddies(&self) -> Vec<RaftBuddy> {
+        let topology = Topology::in_memory(self.buddies.len());
+
+        (0..)
+            .zip(&self.buddies)
+            .map(|(id, buddy)| buddy.buddy(RaftId(id), topology.clone()))
+            .collect()
+    }
+}
+
+/// Everything's compared in full, since a matching fingerprint alone doesn't
+/// make two states the same.
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.proposals == other.proposals
+            && self.buddies == other.buddies
+            && self.invariants == other.invariants
+            && self.same_in_flight(other)
+    }
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Explores every way a small cluster can play out, up to a number of
+/// actions: each message in flight delivered or dropped, in any order, and
+/// any buddy's timer running out at any point. The Raft safety properties
+/// are checked in every state reached. States already seen are skipped, and
+/// the search goes breadth first, so a counterexample is as short as they
+/// come.
+#[derive(Debug)]
+pub struct ModelChecker {
+    start: State,
+    max_depth: usize,
+    max_proposals: usize,
+}
+
+impl ModelChecker {
+    /// Buddies `0..size`, fresh, with nothing in flight.
+    pub fn new(size: usize, max_depth: usize) -> Self {
+        let buddies: Vec<RaftBuddy> = Topology::in_memory(size).into();
+
+        Self::starting_from(&buddies, max_depth)
+    }
+
+    /// Start from wherever `buddies` have got to, with nothing in flight.
+    /// Their ids have to be `0..buddies.len()`.
+    pub fn starting_from(buddies: &[RaftBuddy], max_depth: usize) -> Self {
+        assert!(
+            (0..).zip(buddies).all(|(id, buddy)| *buddy.id == id),
+            "Buddies have to be numbered from 0"
+        );
+
+        Self {
+            start: State {
+                buddies: buddies.iter().map(BuddyState::of).collect(),
+                in_flight: vec![],
+                proposals: 0,
+                invariants: InvariantChecker::default(),
+            },
+            max_depth,
+            max_proposals: 0,
+        }
+    }
+
+    /// How many commands leaders can be handed between them. Without any,
+    /// there are only elections to check.
+    pub fn with_proposals(self, max_proposals: usize) -> Self {
+        Self {
+            max_proposals,
+            ..self
+        }
+    }
+
+    pub fn check(&self) -> Result<Explored, Counterexample> {
+        let mut start = self.start.clone();
+        let buddies = start.buddies();
+
+        if let Err(violation) = start.invariants.check(&buddies) {
+            return Err(Counterexample {
+                violation,
+                trace: vec![],
+                buddies: dump(&buddies),
+            });
+        }
+
+        // Vec<(parent, Action)>, how each state was first reached, so the
+        // trace can be followed back once something goes wrong.
+        let mut steps: Vec<(Option<usize>, Action)> = vec![];
+        // HashMap<fingerprint, Vec<State>>. States are only skipped if
+        // they're the same as one already seen, not just if their
+        // fingerprints match, so a collision can't cut off part of the
+        // search.
+        let mut seen = HashMap::from([(start.fingerprint(), vec![start.clone()])]);
+        let mut states = 1;
+        let mut frontier: Vec<(Option<usize>, State)> = vec![(None, start)];
+        let mut depth = 0;
+
+        while depth < self.max_depth && !frontier.is_empty() {
+            let mut next = vec![];
+
+            for (parent, state) in frontier {
+                for action in self.actions(&state) {
+                    let (state, result) = self.step(&state, &action);
+
+                    if let Err(violation) = result {
+                        let mut trace = vec![action];
+                        let mut at = parent;
+
+                        while let Some(step) = at {
+                            let (parent, action) = &steps[step];
+                            trace.push(action.clone());
+                            at = *parent;
+                        }
+
+                        trace.reverse();
+
+                        return Err(Counterexample {
+                            violation,
+                            trace,
+                            buddies: dump(&state.buddies()),
+                        });
+                    }
+
+                    let same = seen.entry(state.fingerprint()).or_insert_with(Vec::new);
+
+                    if !same.contains(&state) {
+                        same.push(state.clone());
+                        states += 1;
+                        steps.push((parent, action));
+                        next.push((Some(steps.len() - 1), state));
+                    }
+                }
+            }
+
+            frontier = next;
+            depth += 1;
+        }
+
+        Ok(Explored {
+            states,
+            depth: if frontier.is_empty() {
+                depth - 1
+            } else {
+                depth
+            },
+        })
+    }
+
+    /// Everything that could happen next. The same message sent twice is
+    /// only delivered or dropped once, since either copy goes the same way.
+    fn actions(&self, state: &State) -> Vec<Action> {
+        let mut actions = vec![];
+
+        for (i, envelope) in state.in_flight.iter().enumerate() {
+            if !state.in_flight[..i].contains(envelope) {
+                actions.push(Action::Deliver(envelope.clone()));
+                actions.push(Action::Drop(envelope.clone()));
+            }
+        }
+
+        for (id, buddy) in (0..).zip(&state.buddies) {
+            actions.push(Action::TimeOut(RaftId(id)));
+
+            if buddy.role == Role::Leader && state.proposals < self.max_proposals {
+                actions.push(Action::Propose(RaftId(id)));
+            }
+        }
+
+        actions
+    }
+
+    fn step(&self, state: &State, action: &Action) -> (State, Result<(), Violation>) {
+        let mut in_flight = state.in_flight.clone();
+        let mut proposals = state.proposals;
+
+        if let Action::Deliver(envelope) | Action::Drop(envelope) = action {
+            let position = in_flight.iter().position(|sent| sent == envelope).unwrap();
+            in_flight.remove(position);
+        }
+
+        // Nobody's any different for a message going missing, so there's
+        // nothing new to check.
+        if let Action::Drop(_) = action {
+            return (
+                State {
+                    in_flight,
+                    ..state.clone()
+                },
+                Ok(()),
+            );
+        }
+
+        let mut buddies = state.buddies();
+
+        match action {
+            Action::Deliver(envelope) => buddies[*envelope.to].handle(envelope.clone()),
+            Action::Drop(_) => {}
+            Action::TimeOut(id) => buddies[**id].time_out(),
+            Action::Propose(id) => {
+                proposals += 1;
+                buddies[**id].propose(format!("set x {proposals}"));
+            }
+        }
+
+        // Everything just sent is in somebody's inbox.
+        for channel in buddies[0].topology.values() {
+            let mut channel = channel.lock().unwrap();
+            in_flight.extend(std::iter::from_fn(|| channel.pop()));
+        }
+
+        let mut invariants = state.invariants.clone();
+        let result = invariants.check(&buddies);
+
+        let state = State {
+            buddies: buddies.iter().map(BuddyState::of).collect(),
+            in_flight,
+            proposals,
+            invariants,
+        };
+
+        (state, result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_id::ClusterId;
+    use crate::raft_message::RequestVoteBody;
+
+    #[test]
+    fn three_fresh_buddies_are_safe_to_the_depth_checked() {
+        // Deep enough for a leader to be elected and get an entry committed.
+        let explored = ModelChecker::new(3, 7).with_proposals(1).check().unwrap();
+
+        assert_eq!(explored.depth, 7);
+        assert!(explored.states > 1000, "Only explored {explored:?}");
+    }
+
+    #[test]
+    fn a_lone_buddy_runs_out_of_things_to_do() {
+        // It elects itself, and after that its heartbeats go nowhere.
+        let explored = ModelChecker::new(1, 5).check().unwrap();
+
+        assert_eq!(
+            explored,
+            Explored {
+                states: 2,
+                depth: 1
+            }
+        );
+    }
+
+    #[test]
+    fn a_buddy_that_forgot_its_vote_lets_in_a_second_leader_by_the_shortest_route() {
+        let mut buddies: Vec<RaftBuddy> = Topology::in_memory(3).into();
+
+        // Buddy 0 won term 1 with buddy 2's vote, but buddy 2 has since lost
+        // track of having given it.
+        buddies[0].role = Role::Leader;
+        buddies[0].current_term = 1;
+        buddies[0].voted_for = Some(RaftId(0));
+        buddies[2].current_term = 1;
+
+        let counterexample = ModelChecker::starting_from(&buddies, 6)
+            .check()
+            .unwrap_err();
+
+        assert_eq!(
+            counterexample.violation,
+            Violation::ElectionSafety {
+                term: 1,
+                leaders: (RaftId(0), RaftId(1))
+            }
+        );
+        assert_eq!(
+            counterexample.trace,
+            [
+                Action::TimeOut(RaftId(1)),
+                Action::Deliver(Envelope {
+                    from: RaftId(1),
+                    to: RaftId(2),
+                    term: 1,
+                    cluster_id: ClusterId::default(),
+                    message: RaftMessage::RequestVote(RequestVoteBody {
+                        last_log_index: 0,
+                        last_log_term: 0,
+                    }),
+                }),
+                Action::Deliver(Envelope {
+                    from: RaftId(2),
+                    to: RaftId(1),
+                    term: 1,
+                    cluster_id: ClusterId::default(),
+                    message: RaftMessage::VoteForCandidate,
+                }),
+            ]
+        );
+        assert!(counterexample
+            .to_string()
+            .starts_with("election safety: buddies 0 and 1 were both leader in term 1 after 3 steps:\n   1. buddy 1 times out\n"));
+    }
+
+    #[test]
+    fn states_are_only_the_same_if_everything_in_them_is() {
+        let message = |from: usize, to: usize| Envelope {
+            from: RaftId(from),
+            to: RaftId(to),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        };
+        let state = |in_flight: Vec<Envelope>| State {
+            in_flight,
+            ..ModelChecker::new(3, 0).start
+        };
+
+        let sent = state(vec![message(1, 0), message(2, 0), message(2, 0)]);
+        let reordered = state(vec![message(2, 0), message(1, 0), message(2, 0)]);
+        let other_copies = state(vec![message(1, 0), message(1, 0), message(2, 0)]);
+
+        assert_eq!(sent, reordered);
+        assert_eq!(sent.fingerprint(), reordered.fingerprint());
+        assert_ne!(sent, other_copies);
+        assert_ne!(sent, state(vec![message(1, 0), message(2, 0)]));
+    }
+}
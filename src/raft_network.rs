@@ -0,0 +1,418 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use crate::raft_channel::Channel;
+use crate::raft_id::RaftId;
+use crate::raft_message::Envelope;
+use crate::raft_rng::Rng;
+use crate::raft_topology::Topology;
+use crate::raft_type_aliases::ArcMutChannel;
+
+/// How badly a link between two buddies behaves. Each is the chance of it
+/// happening to any one message sent over the link.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct Faults {
+    /// The message never arrives.
+    pub drop: f64,
+    /// The message arrives twice.
+    pub duplicate: f64,
+    /// The message jumps ahead of some of those already waiting.
+    pub reorder: f64,
+    /// The message takes up to `max_delay` extra ticks to arrive.
+    pub delay: f64,
+    pub max_delay: u64,
+}
+
+impl Faults {
+    /// A link nothing gets through.
+    pub const CUT: Faults = Faults {
+        drop: 1.0,
+        duplicate: 0.0,
+        reorder: 0.0,
+        delay: 0.0,
+        max_delay: 0,
+    };
+}
+
+/// What's happened to messages on the network so far.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct NetworkStats {
+    pub sent: usize,
+    pub dropped: usize,
+    pub duplicated: usize,
+    pub reordered: usize,
+    pub delayed: usize,
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    now: u64,
+    rng: Rng,
+    faults: Faults,
+    /// BTreeMap<(from, to), Faults>, links that don't behave like the rest.
+    links: BTreeMap<(RaftId, RaftId), Faults>,
+    /// BTreeSet<(from, to)>, links partitioned off until the next `heal`.
+    cut: BTreeSet<(RaftId, RaftId)>,
+    /// Buddies that have crashed, which nothing gets through to until they
+    /// restart.
+    down: BTreeSet<RaftId>,
+    stats: NetworkStats,
+}
+
+/// The simulated network every `SimChannel` in a cluster shares: its clock,
+/// how each link behaves, and the generator that decides what happens to
+/// each message. Clones share all of it.
+#[derive(Clone, Debug)]
+pub struct SimNetwork(Arc<Mutex<NetworkState>>);
+
+impl SimNetwork {
+    pub fn new(seed: u64) -> Self {
+        Self(Arc::new(Mutex::new(NetworkState {
+            now: 0,
+            rng: Rng::new(seed),
+            faults: Faults::default(),
+            links: BTreeMap::new(),
+            cut: BTreeSet::new(),
+            down: BTreeSet::new(),
+            stats: NetworkStats::default(),
+        })))
+    }
+
+    /// Buddies `0..size`, each with a `SimChannel` on this network for an
+    /// inbox.
+    pub fn topology(&self, size: usize) -> Topology {
+        (0..size)
+            .map(|id| {
+                (
+                    RaftId(id),
+                    Arc::new(Mutex::new(SimChannel::new(self.clone()))) as ArcMutChannel,
+                )
+            })
+            .collect()
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.lock().unwrap().now
+    }
+
+    /// Move the clock on a tick, letting through whatever was delayed until
+    /// then.
+    pub fn advance(&self) {
+        self.0.lock().unwrap().now += 1;
+    }
+
+    /// How every link behaves unless told otherwise with `set_link`.
+    pub fn set_faults(&self, faults: Faults) {
+        self.0.lock().unwrap().faults = faults;
+    }
+
+    /// How messages from `from` to `to` behave. Only that direction.
+    pub fn set_link(&self, from: RaftId, to: RaftId, faults: Faults) {
+        self.0.lock().unwrap().links.insert((from, to), faults);
+    }
+
+    pub fn faults(&self, from: RaftId, to: RaftId) -> Faults {
+        let state = self.0.lock().unwrap();
+
+        state
+            .links
+            .get(&(from, to))
+            .copied()
+            .unwrap_or(state.faults)
+    }
+
+    /// Partition off messages from `from` to `to`, whatever faults the link
+    /// has. Only that direction.
+    pub fn cut(&self, from: RaftId, to: RaftId) {
+        self.0.lock().unwrap().cut.insert((from, to));
+    }
+
+    pub fn is_cut(&self, from: RaftId, to: RaftId) -> bool {
+        self.0.lock().unwrap().cut.contains(&(from, to))
+    }
+
+    /// Undo every `cut`. Faults are left as they were.
+    pub fn heal(&self) {
+        self.0.lock().unwrap().cut.clear();
+    }
+
+    /// Drop everything sent to `id` until it `restart`s. Whatever's already
+    /// in its inbox is for whoever has to `clear` it.
+    pub fn crash(&self, id: RaftId) {
+        self.0.lock().unwrap().down.insert(id);
+    }
+
+    pub fn restart(&self, id: RaftId) {
+        self.0.lock().unwrap().down.remove(&id);
+    }
+
+    pub fn is_down(&self, id: RaftId) -> bool {
+        self.0.lock().unwrap().down.contains(&id)
+    }
+
+    pub fn stats(&self) -> NetworkStats {
+        self.0.lock().unwrap().stats
+    }
+}
+
+/// A buddy's inbox on a `SimNetwork`. What happens to each message depends
+/// on the link it came over, which is worked out from who sent it to whom.
+#[derive(Debug)]
+pub struct SimChannel {
+    network: SimNetwork,
+    /// Each message with the tick it can be delivered from.
+    queue: VecDeque<(u64, Envelope)>,
+}
+
+impl SimChannel {
+    pub fn new(network: SimNetwork) -> Self {
+        Self {
+            network,
+            queue: VecDeque::new(),
+        }
+    }
+}
+
+impl Channel for SimChannel {
+    fn push(&mut self, envelope: Envelope) {
+        let mut state = self.network.0.lock().unwrap();
+        let state = &mut *state;
+        let faults = state
+            .links
+            .get(&(envelope.from, envelope.to))
+            .copied()
+            .unwrap_or(state.faults);
+
+        state.stats.sent += 1;
+
+        if state.cut.contains(&(envelope.from, envelope.to))
+            || state.down.contains(&envelope.to)
+            || state.rng.chance(faults.drop)
+        {
+            state.stats.dropped += 1;
+            return;
+        }
+
+        let copies = if state.rng.chance(faults.duplicate) {
+            state.stats.duplicated += 1;
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut arrives = state.now;
+
+            if faults.max_delay > 0 && state.rng.chance(faults.delay) {
+                state.stats.delayed += 1;
+                arrives += 1 + state.rng.below(faults.max_delay);
+            }
+
+            let position = if !self.queue.is_empty() && state.rng.chance(faults.reorder) {
+                state.stats.reordered += 1;
+                state.rng.range(0..self.queue.len())
+            } else {
+                self.queue.len()
+            };
+
+            self.queue.insert(position, (arrives, envelope.clone()));
+        }
+    }
+
+    /// The oldest message that's arrived by now, passing over any still on
+    /// their way.
+    fn pop(&mut self) -> Option<Envelope> {
+        let now = self.network.now();
+        let position = self.queue.iter().position(|(arrives, _)| *arrives <= now)?;
+
+        self.queue.remove(position).map(|(_, envelope)| envelope)
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        let now = self.network.now();
+
+        self.queue
+            .iter()
+            .filter(|(arrives, _)| *arrives <= now)
+            .map(|(_, envelope)| envelope.clone())
+            .collect()
+    }
+
+    /// Delayed messages go too, not just those that have arrived.
+    fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_id::ClusterId;
+    use crate::raft_message::RaftMessage;
+
+    fn vote(from: usize, term: usize) -> Envelope {
+        Envelope {
+            from: RaftId(from),
+            to: RaftId(0),
+            term,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        }
+    }
+
+    fn drain(channel: &mut SimChannel) -> Vec<Envelope> {
+        std::iter::from_fn(|| channel.pop()).collect()
+    }
+
+    #[test]
+    fn a_perfect_network_delivers_everything_in_order() {
+        let mut channel = SimChannel::new(SimNetwork::new(1));
+
+        for term in 1..=3 {
+            channel.push(vote(1, term));
+        }
+
+        assert_eq!(drain(&mut channel), [vote(1, 1), vote(1, 2), vote(1, 3)]);
+    }
+
+    #[test]
+    fn faults_only_apply_to_their_own_link() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.set_link(RaftId(1), RaftId(0), Faults::CUT);
+
+        channel.push(vote(1, 1));
+        channel.push(vote(2, 1));
+
+        assert_eq!(drain(&mut channel), [vote(2, 1)]);
+        assert_eq!(network.stats().dropped, 1);
+        assert_eq!(network.faults(RaftId(0), RaftId(1)), Faults::default());
+    }
+
+    #[test]
+    fn nothing_crosses_a_cut_until_it_heals() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.cut(RaftId(1), RaftId(0));
+        channel.push(vote(1, 1));
+
+        assert!(network.is_cut(RaftId(1), RaftId(0)));
+        assert!(!network.is_cut(RaftId(0), RaftId(1)));
+
+        network.heal();
+        channel.push(vote(1, 2));
+
+        assert_eq!(drain(&mut channel), [vote(1, 2)]);
+    }
+
+    #[test]
+    fn crashed_buddies_lose_what_is_sent_to_them() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.set_faults(Faults {
+            delay: 1.0,
+            max_delay: 3,
+            ..Faults::default()
+        });
+        channel.push(vote(1, 1));
+
+        network.crash(RaftId(0));
+        channel.clear();
+        channel.push(vote(1, 2));
+        network.restart(RaftId(0));
+        channel.push(vote(1, 3));
+
+        for _ in 0..3 {
+            network.advance();
+        }
+
+        assert!(!network.is_down(RaftId(0)));
+        assert_eq!(drain(&mut channel), [vote(1, 3)]);
+        assert_eq!(network.stats().dropped, 1);
+    }
+
+    #[test]
+    fn duplicates_arrive_twice() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.set_faults(Faults {
+            duplicate: 1.0,
+            ..Faults::default()
+        });
+        channel.push(vote(1, 1));
+
+        assert_eq!(drain(&mut channel), [vote(1, 1), vote(1, 1)]);
+    }
+
+    #[test]
+    fn delayed_messages_wait_for_the_clock() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.set_faults(Faults {
+            delay: 1.0,
+            max_delay: 5,
+            ..Faults::default()
+        });
+        channel.push(vote(1, 1));
+
+        assert_eq!(channel.pop(), None);
+        assert!(channel.all_messages().is_empty());
+
+        for _ in 0..5 {
+            network.advance();
+        }
+
+        assert_eq!(channel.pop(), Some(vote(1, 1)));
+    }
+
+    #[test]
+    fn reordering_shuffles_but_loses_nothing() {
+        let network = SimNetwork::new(1);
+        let mut channel = SimChannel::new(network.clone());
+
+        network.set_faults(Faults {
+            reorder: 0.5,
+            ..Faults::default()
+        });
+
+        for term in 1..=20 {
+            channel.push(vote(1, term));
+        }
+
+        let mut terms: Vec<_> = drain(&mut channel).iter().map(|e| e.term).collect();
+        assert_ne!(terms, (1..=20).collect::<Vec<_>>());
+
+        terms.sort();
+        assert_eq!(terms, (1..=20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn the_same_seed_does_the_same_damage() {
+        let run = |seed| {
+            let network = SimNetwork::new(seed);
+            let mut channel = SimChannel::new(network.clone());
+
+            network.set_faults(Faults {
+                drop: 0.3,
+                duplicate: 0.3,
+                reorder: 0.3,
+                delay: 0.0,
+                max_delay: 0,
+            });
+
+            for term in 1..=20 {
+                channel.push(vote(1, term));
+            }
+
+            drain(&mut channel)
+        };
+
+        assert_eq!(run(9), run(9));
+        assert_ne!(run(9), run(10));
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Range;
+
+/// A small, fast, seedable generator (xorshift64*). Nowhere near good enough
+/// for anything secret, but the same seed always gives the same numbers,
+/// which is what jitter and simulations need.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // Run the seed through splitmix64 so nearby seeds don't start off
+        // looking alike, and so a seed of 0 (which xorshift can't escape)
+        // still works.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+
+        Self(z.max(1))
+    }
+
+    /// Seeded from the randomness std uses for `HashMap`s.
+    pub fn from_entropy() -> Self {
+        Self::new(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Uniform in `0..bound`, which mustn't be empty.
+    pub fn below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "Can't pick from an empty range");
+
+        // Multiply-shift rather than `%`, which would favour small numbers.
+        ((self.next_u64() as u128 * bound as u128) >> 64) as u64
+    }
+
+    pub fn range(&mut self, range: Range<usize>) -> usize {
+        range.start + self.below((range.end - range.start) as u64) as usize
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn unit(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        self.unit() < p
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_same_seed_gives_the_same_numbers() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        let mut c = Rng::new(43);
+
+        let a: Vec<_> = (0..10).map(|_| a.next_u64()).collect();
+        let b: Vec<_> = (0..10).map(|_| b.next_u64()).collect();
+        let c: Vec<_> = (0..10).map(|_| c.next_u64()).collect();
+
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+    }
+
+    #[test]
+    fn it_stays_in_range() {
+        let mut rng = Rng::new(0);
+
+        for _ in 0..1000 {
+            assert!(rng.below(7) < 7);
+            assert!((3..9).contains(&rng.range(3..9)));
+            assert!((0.0..1.0).contains(&rng.unit()));
+        }
+    }
+
+    #[test]
+    fn it_covers_the_whole_range() {
+        let mut rng = Rng::new(1);
+        let mut seen = [false; 6];
+
+        for _ in 0..1000 {
+            seen[rng.below(6) as usize] = true;
+        }
+
+        assert!(seen.iter().all(|&seen| seen));
+    }
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A directory under the system temp dir for one test to write into. It
+/// starts out empty and is removed again when it's dropped, even if the test
+/// fails.
+#[derive(Debug)]
+pub struct ScratchDir(PathBuf);
+
+impl ScratchDir {
+    /// `name` only has to be unique among the tests of this process.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("raft-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        Self(dir)
+    }
+}
+
+impl Deref for ScratchDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ScratchDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&ScratchDir> for PathBuf {
+    fn from(dir: &ScratchDir) -> Self {
+        dir.0.clone()
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
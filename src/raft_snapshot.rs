@@ -0,0 +1,103 @@
+use crate::raft_log::SnapshotBase;
+
+/// A state machine snapshot along with the point in the log it was taken at.
+/// While an InstallSnapshot transfer is in flight the follower keeps the
+/// chunks it has so far in one of these, so `data` may be incomplete.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Snapshot {
+    pub base: SnapshotBase,
+    pub data: Vec<u8>,
+}
+
+impl Snapshot {
+    /// The chunk starting at `offset`, and whether it's the last one.
+    pub fn chunk(&self, offset: usize, chunk_size: usize) -> (&[u8], bool) {
+        let start = offset.min(self.data.len());
+        let end = offset.saturating_add(chunk_size).min(self.data.len());
+
+        (&self.data[start..end], end == self.data.len())
+    }
+
+    /// Accept a chunk if it picks up exactly where we left off, and return the
+    /// offset we want next either way. Anything else is a duplicate or has
+    /// arrived out of order, and replying with the offset we actually want
+    /// lets the sender resume from there.
+    pub fn receive(&mut self, offset: usize, chunk: &[u8]) -> usize {
+        if offset == self.data.len() {
+            self.data.extend_from_slice(chunk);
+        }
+
+        self.data.len()
+    }
+}
+
+/// Snapshot once enough has been applied since the last snapshot, by either
+/// measure.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct SnapshotPolicy {
+    pub max_entries: usize,
+    /// Counts the contents of each entry, not its term and index.
+    pub max_bytes: usize,
+    /// Entries to keep in the log behind a new snapshot, so followers that
+    /// are only a little behind can still be caught up with AppendEntries
+    /// instead of the whole snapshot.
+    pub trailing_entries: usize,
+}
+
+impl SnapshotPolicy {
+    pub fn is_due(&self, entries: usize, bytes: usize) -> bool {
+        entries >= self.max_entries || bytes >= self.max_bytes
+    }
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 4 * 1024 * 1024,
+            trailing_entries: 100,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_chunks_and_reassembles() {
+        let snapshot = Snapshot {
+            base: SnapshotBase {
+                last_included_index: 3,
+                last_included_term: 1,
+            },
+            data: (0..10).collect(),
+        };
+        let mut received = Snapshot {
+            base: snapshot.base,
+            ..Default::default()
+        };
+
+        let mut offset = 0;
+        loop {
+            let (chunk, done) = snapshot.chunk(offset, 4);
+            offset = received.receive(offset, chunk);
+
+            if done {
+                break;
+            }
+        }
+
+        assert!(received == snapshot);
+    }
+
+    #[test]
+    fn it_ignores_chunks_it_was_not_expecting() {
+        let mut received = Snapshot::default();
+
+        assert!(received.receive(0, &[1, 2]) == 2);
+        assert!(received.receive(0, &[1, 2]) == 2);
+        assert!(received.receive(4, &[5, 6]) == 2);
+        assert!(received.data == [1, 2]);
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::raft_checksum::crc32;
+use crate::raft_compression::Compression;
+use crate::raft_encoding::{invalid_data, put_bytes, put_u64, put_usize, Reader};
+use crate::raft_id::RaftId;
+use crate::raft_log::SnapshotBase;
+use crate::raft_snapshot::Snapshot;
+
+const MAGIC: &[u8; 8] = b"RAFTSNAP";
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SnapshotMetadata {
+    pub base: SnapshotBase,
+    /// The buddies in the cluster when the snapshot was taken.
+    pub configuration: Vec<RaftId>,
+    /// CRC-32 of the snapshot data, before it's compressed.
+    pub checksum: u32,
+    pub compression: Compression,
+}
+
+/// A directory of snapshot files, one per snapshot, named so they sort by
+/// index. Each file is written under a `.tmp` name and renamed into place
+/// once it's on disk, so a crash halfway through leaves a `.tmp` file behind
+/// rather than a snapshot that looks complete but isn't.
+#[derive(Debug)]
+pub struct SnapshotStore {
+    dir: PathBuf,
+    /// How many published snapshots to keep around.
+    retain: usize,
+}
+
+impl SnapshotStore {
+    /// Open (or create) the store, throwing away anything left over from a
+    /// publish that never finished.
+    pub fn open(dir: impl Into<PathBuf>, retain: usize) -> io::Result<Self> {
+        let dir = dir.into();
+
+        fs::create_dir_all(&dir)?;
+        remove_partial_files(&dir)?;
+
+        Ok(Self {
+            dir,
+            retain: retain.max(1),
+        })
+    }
+
+    pub fn publish(
+        &self,
+        snapshot: &Snapshot,
+        configuration: &[RaftId],
+    ) -> io::Result<SnapshotMetadata> {
+        let metadata = SnapshotMetadata {
+            base: snapshot.base,
+            configuration: configuration.to_vec(),
+            checksum: crc32(&snapshot.data),
+            compression: Compression::preferred(),
+        };
+
+        let mut bytes = MAGIC.to_vec();
+        put_usize(&mut bytes, metadata.base.last_included_index);
+        put_usize(&mut bytes, metadata.base.last_included_term);
+        put_usize(&mut bytes, metadata.configuration.len());
+        for id in &metadata.configuration {
+            put_usize(&mut bytes, **id);
+        }
+        put_u64(&mut bytes, metadata.checksum as u64);
+        bytes.push(metadata.compression.tag());
+        put_bytes(&mut bytes, &metadata.compression.compress(&snapshot.data)?);
+
+        write_atomically(&self.dir.join(file_name(snapshot.base)), &bytes)?;
+        self.remove_old_snapshots()?;
+
+        Ok(metadata)
+    }
+
+    /// Published snapshots, oldest first.
+    pub fn list(&self) -> io::Result<Vec<PathBuf>> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(&self.dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<_>>()?;
+
+        paths.retain(|path| {
+            path.extension()
+                .is_some_and(|extension| extension == "snap")
+        });
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    /// The newest snapshot that reads back cleanly. Anything newer that
+    /// doesn't is skipped over with a complaint, since an older snapshot is
+    /// still better than none.
+    pub fn latest(&self) -> io::Result<Option<(SnapshotMetadata, Snapshot)>> {
+        for path in self.list()?.iter().rev() {
+            match read_snapshot(path) {
+                Ok(snapshot) => return Ok(Some(snapshot)),
+                Err(error) => eprintln!("Skipping snapshot {}: {error}", path.display()),
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn remove_old_snapshots(&self) -> io::Result<()> {
+        let paths = self.list()?;
+        let excess = paths.len().saturating_sub(self.retain);
+
+        for path in &paths[..excess] {
+            fs::remove_file(path)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn file_name(base: SnapshotBase) -> String {
+    format!(
+        "snapshot-{:020}-{:020}.snap",
+        base.last_included_index, base.last_included_term
+    )
+}
+
+fn read_snapshot(path: &Path) -> io::Result<(SnapshotMetadata, Snapshot)> {
+    let bytes = fs::read(path)?;
+    let mut reader = Reader(&bytes);
+
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err(invalid_data("not a snapshot file"));
+    }
+
+    let base = SnapshotBase {
+        last_included_index: reader.usize()?,
+        last_included_term: reader.usize()?,
+    };
+    let configuration = (0..reader.usize()?)
+        .map(|_| reader.usize().map(RaftId))
+        .collect::<io::Result<_>>()?;
+    let checksum = reader.u64()? as u32;
+    let compression = Compression::from_tag(reader.u8()?)?;
+    // The checksum covers whatever this comes to, so there's no need to
+    // limit it.
+    let data = compression.decompress(reader.bytes()?, usize::MAX)?;
+    reader.finish()?;
+
+    if crc32(&data) != checksum {
+        return Err(invalid_data("checksum mismatch"));
+    }
+
+    Ok((
+        SnapshotMetadata {
+            base,
+            configuration,
+            checksum,
+            compression,
+        },
+        Snapshot { base, data },
+    ))
+}
+
+/// Write to `<path>.tmp`, flush it to disk, then rename it over `path`, so
+/// readers only ever see the old contents or the new ones.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let partial = path.with_extension("tmp");
+
+    fs::write(&partial, bytes)?;
+    fs::File::open(&partial)?.sync_all()?;
+    fs::rename(&partial, path)?;
+
+    if let Some(dir) = path.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Clear out `.tmp` files from writes that didn't make it to the rename.
+pub fn remove_partial_files(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.extension().is_some_and(|extension| extension == "tmp") {
+            fs::remove_file(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_scratch::ScratchDir;
+
+    fn snapshot(index: usize) -> Snapshot {
+        Snapshot {
+            base: SnapshotBase {
+                last_included_index: index,
+                last_included_term: 1,
+            },
+            data: format!("snapshot at {index}").into_bytes(),
+        }
+    }
+
+    #[test]
+    fn it_publishes_and_reads_back_the_latest_snapshot() {
+        let dir = ScratchDir::new("latest");
+        let store = SnapshotStore::open(&dir, 3).unwrap();
+
+        store
+            .publish(&snapshot(5), &[RaftId(0), RaftId(1)])
+            .unwrap();
+        let published = store
+            .publish(&snapshot(10), &[RaftId(0), RaftId(1)])
+            .unwrap();
+
+        let (metadata, latest) = store.latest().unwrap().unwrap();
+
+        assert_eq!(metadata, published);
+        assert_eq!(latest, snapshot(10));
+    }
+
+    #[test]
+    fn it_keeps_only_the_last_few() {
+        let dir = ScratchDir::new("retain");
+        let store = SnapshotStore::open(&dir, 2).unwrap();
+
+        for index in [5, 10, 15] {
+            store.publish(&snapshot(index), &[]).unwrap();
+        }
+
+        let names: Vec<_> = store
+            .list()
+            .unwrap()
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+
+        assert_eq!(
+            names,
+            [file_name(snapshot(10).base), file_name(snapshot(15).base)]
+        );
+    }
+
+    #[test]
+    fn it_cleans_up_partial_files_on_open() {
+        let dir = ScratchDir::new("partial");
+        let store = SnapshotStore::open(&dir, 3).unwrap();
+        store.publish(&snapshot(5), &[]).unwrap();
+        fs::write(
+            dir.join(file_name(snapshot(10).base)).with_extension("tmp"),
+            b"half a snap",
+        )
+        .unwrap();
+
+        let store = SnapshotStore::open(&dir, 3).unwrap();
+
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(store.latest().unwrap().unwrap().1, snapshot(5));
+    }
+
+    #[test]
+    fn it_falls_back_past_corrupt_snapshots() {
+        let dir = ScratchDir::new("corrupt");
+        let store = SnapshotStore::open(&dir, 3).unwrap();
+        store.publish(&snapshot(5), &[]).unwrap();
+        store.publish(&snapshot(10), &[]).unwrap();
+
+        let newest = dir.join(file_name(snapshot(10).base));
+        let mut bytes = fs::read(&newest).unwrap();
+        *bytes.last_mut().unwrap() ^= 0xFF;
+        fs::write(&newest, bytes).unwrap();
+
+        assert_eq!(store.latest().unwrap().unwrap().1, snapshot(5));
+    }
+}
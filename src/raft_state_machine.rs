@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::io;
+
+use crate::raft_log::LogEntry;
+
+/// Whatever the log is replicating commands for. Entries are applied in index
+/// order once they're committed.
+pub trait StateMachine {
+    fn apply(&mut self, entry: &LogEntry) -> Option<String>;
+    fn snapshot(&self) -> Vec<u8>;
+    fn restore(&mut self, snapshot: &[u8]) -> io::Result<()>;
+}
+
+/// Understands `set <key> <value>` and `get <key>`. Anything else is applied
+/// as a no-op so a bad command can't wedge the log.
+#[derive(PartialEq, Eq, Hash, Clone, Debug, Default)]
+pub struct KeyValueStore(BTreeMap<String, String>);
+
+impl KeyValueStore {
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.0.get(key)
+    }
+}
+
+impl StateMachine for KeyValueStore {
+    fn apply(&mut self, entry: &LogEntry) -> Option<String> {
+        let mut words = entry.contents.splitn(3, ' ');
+
+        match (words.next(), words.next(), words.next()) {
+            (Some("set"), Some(key), Some(value)) => {
+                self.0.insert(key.to_owned(), value.to_owned());
+                None
+            }
+            (Some("get"), Some(key), None) => self.0.get(key).cloned(),
+            _ => None,
+        }
+    }
+
+    /// Each key and value is written as a big-endian `u32` length followed by
+    /// its bytes.
+    fn snapshot(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+
+        for (key, value) in &self.0 {
+            for field in [key, value] {
+                bytes.extend_from_slice(&(field.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(field.as_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    fn restore(&mut self, mut snapshot: &[u8]) -> io::Result<()> {
+        fn read_field(bytes: &mut &[u8]) -> io::Result<String> {
+            let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+
+            let (length, rest) = bytes
+                .split_first_chunk::<4>()
+                .ok_or_else(|| invalid("snapshot ended in the middle of a length"))?;
+            let length = u32::from_be_bytes(*length) as usize;
+
+            if rest.len() < length {
+                return Err(invalid("snapshot ended in the middle of a field"));
+            }
+
+            let (field, rest) = rest.split_at(length);
+            *bytes = rest;
+
+            String::from_utf8(field.to_vec()).map_err(|_| invalid("snapshot field isn't UTF-8"))
+        }
+
+        let mut restored = BTreeMap::new();
+
+        while !snapshot.is_empty() {
+            let key = read_field(&mut snapshot)?;
+            let value = read_field(&mut snapshot)?;
+            restored.insert(key, value);
+        }
+
+        self.0 = restored;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(contents: &str) -> LogEntry {
+        LogEntry {
+            term: 1,
+            index: 1,
+            contents: contents.to_owned(),
+        }
+    }
+
+    #[test]
+    fn it_sets_and_gets() {
+        let mut store = KeyValueStore::default();
+
+        assert!(store.apply(&entry("set x 42")).is_none());
+        assert!(store.apply(&entry("get x")) == Some("42".to_owned()));
+        assert!(store.apply(&entry("get y")).is_none());
+    }
+
+    #[test]
+    fn it_round_trips_through_a_snapshot() {
+        let mut store = KeyValueStore::default();
+        store.apply(&entry("set x 42"));
+        store.apply(&entry("set greeting hello there"));
+
+        let mut restored = KeyValueStore::default();
+        restored.restore(&store.snapshot()).unwrap();
+
+        assert!(restored == store);
+        assert!(restored.get("greeting") == Some(&"hello there".to_owned()));
+    }
+
+    #[test]
+    fn it_refuses_truncated_snapshots() {
+        let mut store = KeyValueStore::default();
+        store.apply(&entry("set x 42"));
+        let snapshot = store.snapshot();
+
+        let mut restored = KeyValueStore::default();
+
+        assert!(restored.restore(&snapshot[..snapshot.len() - 1]).is_err());
+        assert!(restored == KeyValueStore::default());
+    }
+}
@@ -0,0 +1,272 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::raft_codec::{codec_for, BinaryCodec, Codec};
+use crate::raft_compression::{pack, unpack};
+use crate::raft_encoding::{invalid_data, put_bool, put_usize, Reader};
+use crate::raft_id::RaftId;
+use crate::raft_log::RaftLog;
+use crate::raft_snapshot::Snapshot;
+use crate::raft_snapshot_store::{remove_partial_files, write_atomically, SnapshotStore};
+
+/// The bits of state that aren't in the log but still have to survive a
+/// restart.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct HardState {
+    pub current_term: usize,
+    /// Who we voted for in `current_term`, so a restart can't get us to vote
+    /// twice in the same term.
+    pub voted_for: Option<RaftId>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PersistedState {
+    pub hard_state: HardState,
+    pub log: RaftLog,
+    pub snapshot: Option<Snapshot>,
+}
+
+/// Where a buddy keeps what it needs to come back from a restart. Every save
+/// has to be durable by the time it returns, because the buddy is about to
+/// tell someone else about it.
+pub trait Storage: std::fmt::Debug + Send {
+    fn save_hard_state(&mut self, hard_state: HardState) -> io::Result<()>;
+    fn save_log(&mut self, log: &RaftLog) -> io::Result<()>;
+    fn save_snapshot(&mut self, snapshot: &Snapshot, configuration: &[RaftId]) -> io::Result<()>;
+    fn load(&self) -> io::Result<PersistedState>;
+}
+
+/// Keeps everything in memory. Clones share the same state, so holding onto
+/// a clone is enough to bring a buddy back after dropping it.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStorage(Arc<Mutex<PersistedState>>);
+
+impl Storage for MemoryStorage {
+    fn save_hard_state(&mut self, hard_state: HardState) -> io::Result<()> {
+        self.0.lock().unwrap().hard_state = hard_state;
+        Ok(())
+    }
+
+    fn save_log(&mut self, log: &RaftLog) -> io::Result<()> {
+        self.0.lock().unwrap().log = log.clone();
+        Ok(())
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot, configuration: &[RaftId]) -> io::Result<()> {
+        self.0.lock().unwrap().snapshot = Some(snapshot.clone());
+        Ok(())
+    }
+
+    fn load(&self) -> io::Result<PersistedState> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+}
+
+/// One directory per buddy, with the log written by `codec` and compressed
+/// along with snapshots when built with the `compression` feature:
+///
+/// ```text
+/// <dir>/hard_state
+/// <dir>/log
+/// <dir>/snapshots/snapshot-<index>-<term>.snap
+/// ```
+#[derive(Debug)]
+pub struct DiskStorage {
+    dir: PathBuf,
+    snapshots: SnapshotStore,
+    codec: &'static dyn Codec,
+}
+
+impl DiskStorage {
+    pub const RETAINED_SNAPSHOTS: usize = 3;
+
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+
+        fs::create_dir_all(&dir)?;
+        remove_partial_files(&dir)?;
+
+        let snapshots = SnapshotStore::open(dir.join("snapshots"), Self::RETAINED_SNAPSHOTS)?;
+
+        Ok(Self {
+            dir,
+            snapshots,
+            codec: &BinaryCodec,
+        })
+    }
+
+    /// Write the log with `codec` from now on. Logs already written with
+    /// another codec can still be read back.
+    pub fn with_codec(self, codec: &'static dyn Codec) -> Self {
+        Self { codec, ..self }
+    }
+
+    pub fn snapshots(&self) -> &SnapshotStore {
+        &self.snapshots
+    }
+
+    /// The file's contents, or `None` if it hasn't been written yet.
+    fn read(&self, name: &str) -> io::Result<Option<Vec<u8>>> {
+        match fs::read(self.dir.join(name)) {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl Storage for DiskStorage {
+    fn save_hard_state(&mut self, hard_state: HardState) -> io::Result<()> {
+        let mut bytes = vec![];
+        put_usize(&mut bytes, hard_state.current_term);
+        put_bool(&mut bytes, hard_state.voted_for.is_some());
+        put_usize(&mut bytes, hard_state.voted_for.map_or(0, |id| *id));
+
+        write_atomically(&self.dir.join("hard_state"), &bytes)
+    }
+
+    fn save_log(&mut self, log: &RaftLog) -> io::Result<()> {
+        let mut bytes = vec![self.codec.id()];
+        bytes.extend(pack(&self.codec.encode_log(log))?);
+
+        write_atomically(&self.dir.join("log"), &bytes)
+    }
+
+    fn save_snapshot(&mut self, snapshot: &Snapshot, configuration: &[RaftId]) -> io::Result<()> {
+        self.snapshots.publish(snapshot, configuration).map(|_| ())
+    }
+
+    fn load(&self) -> io::Result<PersistedState> {
+        let hard_state = match self.read("hard_state")? {
+            Some(bytes) => {
+                let mut reader = Reader(&bytes);
+                let current_term = reader.usize()?;
+                let voted = reader.bool()?;
+                let voted_for = RaftId(reader.usize()?);
+                reader.finish()?;
+
+                HardState {
+                    current_term,
+                    voted_for: voted.then_some(voted_for),
+                }
+            }
+            None => HardState::default(),
+        };
+
+        let log = match self.read("log")? {
+            Some(bytes) => {
+                let (&codec, packed) = bytes
+                    .split_first()
+                    .ok_or_else(|| invalid_data("log file is empty"))?;
+
+                // We wrote it, so however big it comes out is how big the
+                // log really is.
+                codec_for(codec)?.decode_log(&unpack(packed, usize::MAX)?)?
+            }
+            None => RaftLog::default(),
+        };
+
+        let snapshot = self.snapshots.latest()?.map(|(_, snapshot)| snapshot);
+
+        Ok(PersistedState {
+            hard_state,
+            log,
+            snapshot,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_log::{LogEntry, SnapshotBase};
+    use crate::raft_scratch::ScratchDir;
+
+    #[test]
+    fn it_loads_defaults_from_an_empty_directory() {
+        let dir = ScratchDir::new("empty-storage");
+
+        let state = DiskStorage::open(&dir).unwrap().load().unwrap();
+
+        assert_eq!(state.hard_state, HardState::default());
+        assert_eq!(state.log.last_index(), 0);
+        assert_eq!(state.snapshot, None);
+    }
+
+    #[test]
+    fn it_survives_reopening_the_directory() {
+        let dir = ScratchDir::new("reopen-storage");
+
+        let mut log = RaftLog::with_base(SnapshotBase {
+            last_included_index: 2,
+            last_included_term: 1,
+        });
+        log.push(LogEntry {
+            term: 2,
+            index: 3,
+            contents: "set x 3".to_owned(),
+        });
+        let snapshot = Snapshot {
+            base: log.base(),
+            data: b"x=2".to_vec(),
+        };
+
+        let mut storage = DiskStorage::open(&dir).unwrap();
+        storage
+            .save_hard_state(HardState {
+                current_term: 2,
+                voted_for: Some(RaftId(1)),
+            })
+            .unwrap();
+        storage.save_log(&log).unwrap();
+        storage.save_snapshot(&snapshot, &[RaftId(0)]).unwrap();
+        drop(storage);
+
+        let state = DiskStorage::open(&dir).unwrap().load().unwrap();
+
+        assert_eq!(state.hard_state.current_term, 2);
+        assert_eq!(state.hard_state.voted_for, Some(RaftId(1)));
+        assert_eq!(state.log.base(), log.base());
+        assert!(state.log.iter().eq(log.iter()));
+        assert_eq!(state.snapshot, Some(snapshot));
+    }
+
+    #[test]
+    fn memory_storage_clones_share_state() {
+        let mut storage = MemoryStorage::default();
+        let clone = storage.clone();
+
+        storage
+            .save_hard_state(HardState {
+                current_term: 7,
+                voted_for: None,
+            })
+            .unwrap();
+
+        assert_eq!(clone.load().unwrap().hard_state.current_term, 7);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_reads_logs_written_with_another_codec() {
+        let dir = ScratchDir::new("codec-storage");
+
+        let mut log = RaftLog::default();
+        log.push(LogEntry {
+            term: 1,
+            index: 1,
+            contents: "set x 1".to_owned(),
+        });
+
+        let mut storage = DiskStorage::open(&dir)
+            .unwrap()
+            .with_codec(&crate::raft_codec::JsonCodec);
+        storage.save_log(&log).unwrap();
+
+        let state = DiskStorage::open(&dir).unwrap().load().unwrap();
+
+        assert!(state.log.iter().eq(log.iter()));
+    }
+}
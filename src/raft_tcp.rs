@@ -0,0 +1,324 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::raft_auth::ClusterKeys;
+use crate::raft_channel::{Channel, Overflow, OverflowPolicy, RaftChannel};
+use crate::raft_connection::{Connect, Connection, DEFAULT_CAPACITY, WRITE_TIMEOUT};
+use crate::raft_frame::spawn_reader;
+use crate::raft_message::Envelope;
+use crate::raft_type_aliases::SharedQueue;
+
+/// Our own inbox, fed by whoever connects to us. Every connection gets a
+/// thread that reads frames off it into the queue until it hangs up or sends
+/// something we can't read.
+///
+/// The queue's bounded, and full the same way a bounded `RaftChannel` is.
+#[derive(Debug)]
+pub struct TcpInbox {
+    local_addr: SocketAddr,
+    queue: SharedQueue,
+}
+
+impl TcpInbox {
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Self::bind_with_keys(addr, None)
+    }
+
+    /// With `keys`, anything not signed with one of them is refused and the
+    /// connection it came in on dropped.
+    pub fn bind_with_keys(
+        addr: impl ToSocketAddrs,
+        keys: Option<Arc<ClusterKeys>>,
+    ) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let queue = Arc::new(Mutex::new(RaftChannel::bounded(
+            DEFAULT_CAPACITY,
+            OverflowPolicy::default(),
+        )));
+
+        let accepted = queue.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        let from = stream
+                            .peer_addr()
+                            .map_or_else(|error| error.to_string(), |addr| addr.to_string());
+                        spawn_reader(stream, from, accepted.clone(), keys.clone());
+                    }
+                    Err(error) => eprintln!("Could not accept connection on {local_addr}: {error}"),
+                }
+            }
+        });
+
+        Ok(Self { local_addr, queue })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// How many messages can wait to be popped, and what happens to any
+    /// more. A blocked message holds up the connection it came in on until
+    /// there's room for it.
+    pub fn with_capacity(self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.capacity = Some(capacity);
+            queue.overflow = overflow;
+        }
+
+        self
+    }
+}
+
+impl Channel for TcpInbox {
+    /// Anything we send ourselves skips the network.
+    fn push(&mut self, envelope: Envelope) {
+        self.queue.lock().unwrap().push(envelope);
+    }
+
+    fn try_push(&mut self, envelope: Envelope) -> Result<(), Overflow> {
+        self.queue.lock().unwrap().try_push(envelope)
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.queue.lock().unwrap().is_full()
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.queue.lock().unwrap().pop()
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        self.queue.lock().unwrap().all_messages()
+    }
+}
+
+/// Somebody else's inbox, as far as we're concerned: pushing sends a frame
+/// to their `TcpInbox` from a thread of its own, and there's never anything
+/// to pop. See `Connection` for what happens when they can't be reached or
+/// stop keeping up.
+pub type TcpPeer = Connection<SocketAddr>;
+
+/// How long a peer gets to accept a connection before it counts as
+/// unreachable.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl Connect for SocketAddr {
+    type Stream = TcpStream;
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        let stream = TcpStream::connect_timeout(self, CONNECT_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::raft_auth::ClusterKey;
+    use crate::raft_buddy::{RaftBuddy, Role};
+    use crate::raft_channel::PeerHealth;
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_message::RaftMessage;
+    use crate::raft_temporal::RaftTimer;
+    use crate::raft_temporal::Temporal;
+    use crate::raft_threaded::ThreadedCluster;
+    use crate::raft_topology::{PeerAddress, Topology};
+    use crate::raft_type_aliases::ArcMutChannel;
+    use std::collections::BTreeMap;
+    use std::time::Instant;
+
+    fn vote(id: usize) -> Envelope {
+        Envelope {
+            from: RaftId(id),
+            to: RaftId(0),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        }
+    }
+
+    pub fn wait_for_message(inbox: &mut impl Channel) -> Option<Envelope> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+
+        while Instant::now() < deadline {
+            if let Some(envelope) = inbox.pop() {
+                return Some(envelope);
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        None
+    }
+
+    #[test]
+    fn it_delivers_messages_in_order() {
+        let mut inbox = TcpInbox::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpPeer::new(inbox.local_addr());
+
+        peer.push(vote(1));
+        peer.push(vote(2));
+
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(1)));
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(2)));
+    }
+
+    #[test]
+    fn a_full_inbox_holds_up_the_sender_until_there_is_room() {
+        let mut inbox = TcpInbox::bind("127.0.0.1:0")
+            .unwrap()
+            .with_capacity(1, OverflowPolicy::Block);
+        let mut peer = TcpPeer::new(inbox.local_addr());
+
+        for id in 1..=3 {
+            peer.push(vote(id));
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !inbox.is_full() {
+            assert!(Instant::now() < deadline, "Nothing arrived");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(inbox.all_messages(), [vote(1)]);
+        assert_eq!(
+            inbox.try_push(vote(4)),
+            Err(Overflow::Blocked(Box::new(vote(4))))
+        );
+
+        // Nothing was lost while it waited.
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(1)));
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(2)));
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(3)));
+    }
+
+    #[test]
+    fn it_reconnects_after_the_connection_breaks() {
+        let mut inbox = TcpInbox::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpPeer::new(inbox.local_addr());
+
+        peer.push(vote(1));
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(1)));
+
+        peer.disconnect();
+        peer.push(vote(2));
+
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(2)));
+    }
+
+    #[test]
+    fn signed_inboxes_only_take_signed_messages() {
+        let keys = Arc::new(ClusterKeys::new(ClusterKey::new("hunter2")));
+        let mut inbox = TcpInbox::bind_with_keys("127.0.0.1:0", Some(keys.clone())).unwrap();
+        let mut stranger = TcpPeer::new(inbox.local_addr());
+        let mut buddy = TcpPeer::new(inbox.local_addr()).with_keys(keys);
+
+        stranger.push(vote(1));
+        buddy.push(vote(2));
+
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(2)));
+        assert_eq!(inbox.pop(), None);
+    }
+
+    #[test]
+    fn buddies_keep_track_of_peers_they_cannot_reach() {
+        let inbox = TcpInbox::bind("127.0.0.1:0").unwrap();
+        // Nothing's listening here once the listener's dropped.
+        let gone = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let alive = TcpInbox::bind("127.0.0.1:0").unwrap();
+
+        let topology = Topology::from_iter([
+            (RaftId(0), Arc::new(Mutex::new(inbox)) as ArcMutChannel),
+            (
+                RaftId(1),
+                Arc::new(Mutex::new(TcpPeer::new(gone))) as ArcMutChannel,
+            ),
+            (
+                RaftId(2),
+                Arc::new(Mutex::new(TcpPeer::new(alive.local_addr()))) as ArcMutChannel,
+            ),
+        ]);
+        let mut buddy = RaftBuddy {
+            topology,
+            timer: 1.into(),
+            ..Default::default()
+        };
+
+        assert!(buddy.unreachable_peers().is_empty());
+
+        // Stand for election, which means trying to reach everyone. Peers
+        // are reached in the background, so it takes a few ticks to hear
+        // how that went.
+        buddy.tick();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while buddy
+            .peer_health
+            .values()
+            .any(|health| *health == PeerHealth::Unknown)
+            && Instant::now() < deadline
+        {
+            thread::sleep(Duration::from_millis(1));
+            buddy.tick();
+        }
+
+        assert_eq!(buddy.unreachable_peers(), [RaftId(1)]);
+        assert_eq!(buddy.peer_health[&RaftId(2)], PeerHealth::Connected);
+    }
+
+    #[test]
+    fn it_forms_a_cluster_over_localhost() {
+        // Bind to any free port first so we know what everyone's address is.
+        let ports: Vec<SocketAddr> = (0..3)
+            .map(|_| {
+                TcpListener::bind("127.0.0.1:0")
+                    .unwrap()
+                    .local_addr()
+                    .unwrap()
+            })
+            .collect();
+        let addresses: BTreeMap<RaftId, PeerAddress> = ports
+            .into_iter()
+            .enumerate()
+            .map(|(id, addr)| (RaftId(id), PeerAddress::Tcp(addr)))
+            .collect();
+
+        let buddies = addresses
+            .keys()
+            .map(|&id| RaftBuddy {
+                id,
+                topology: Topology::connect(id, &addresses).unwrap(),
+                timer: RaftTimer::from(100 + *id * 10),
+                ..Default::default()
+            })
+            .collect();
+
+        let cluster = ThreadedCluster::spawn(buddies, Duration::from_millis(1));
+
+        assert!(cluster.wait_until(Duration::from_secs(10), |buddies| {
+            buddies.iter().any(|buddy| buddy.role == Role::Leader)
+        }));
+
+        let leader = cluster.leader().unwrap();
+        cluster.buddy(leader).propose("set x 42");
+
+        assert!(cluster.wait_until(Duration::from_secs(10), |buddies| {
+            buddies
+                .iter()
+                .all(|buddy| buddy.state_machine.get("x") == Some(&"42".to_owned()))
+        }));
+    }
+}
@@ -0,0 +1,196 @@
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::raft_buddy::{RaftBuddy, Role};
+use crate::raft_id::RaftId;
+use crate::raft_temporal::Temporal;
+
+/// Runs each buddy on its own OS thread, ticking it every `tick` until the
+/// cluster is stopped. Each buddy sits behind its own mutex so we can look at
+/// (or poke) them while they run.
+pub struct ThreadedCluster {
+    buddies: Vec<Arc<Mutex<RaftBuddy>>>,
+    running: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+    tick: Duration,
+}
+
+impl ThreadedCluster {
+    pub fn spawn(buddies: Vec<RaftBuddy>, tick: Duration) -> Self {
+        let running = Arc::new(AtomicBool::new(true));
+        let buddies: Vec<_> = buddies
+            .into_iter()
+            .map(|buddy| Arc::new(Mutex::new(buddy)))
+            .collect();
+
+        let threads = buddies
+            .iter()
+            .map(|buddy| {
+                let buddy = buddy.clone();
+                let running = running.clone();
+
+                thread::spawn(move || {
+                    while running.load(Ordering::Relaxed) {
+                        buddy.lock().unwrap().tick();
+                        thread::sleep(tick);
+                    }
+                })
+            })
+            .collect();
+
+        Self {
+            buddies,
+            running,
+            threads,
+            tick,
+        }
+    }
+
+    /// Panics if there's no buddy with that id.
+    pub fn buddy(&self, id: RaftId) -> MutexGuard<'_, RaftBuddy> {
+        self.buddies
+            .iter()
+            .map(|buddy| buddy.lock().unwrap())
+            .find(|buddy| buddy.id == id)
+            .unwrap_or_else(|| panic!("No buddy with id {}", *id))
+    }
+
+    pub fn leader(&self) -> Option<RaftId> {
+        self.buddies
+            .iter()
+            .map(|buddy| buddy.lock().unwrap())
+            .find(|buddy| buddy.role == Role::Leader)
+            .map(|buddy| buddy.id)
+    }
+
+    /// Check `condition` against every buddy once a tick until it holds or
+    /// `timeout` runs out, returning whether it ever held. Buddies are locked
+    /// while `condition` looks at them, so none of them tick meanwhile.
+    pub fn wait_until(&self, timeout: Duration, condition: impl Fn(&[&RaftBuddy]) -> bool) -> bool {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let guards: Vec<_> = self
+                .buddies
+                .iter()
+                .map(|buddy| buddy.lock().unwrap())
+                .collect();
+            let buddies: Vec<&RaftBuddy> = guards.iter().map(|guard| &**guard).collect();
+
+            if condition(&buddies) {
+                return true;
+            }
+
+            drop(guards);
+
+            if Instant::now() >= deadline {
+                return false;
+            }
+
+            thread::sleep(self.tick);
+        }
+    }
+
+    /// Stop every thread and hand the buddies back.
+    pub fn stop(mut self) -> Vec<RaftBuddy> {
+        self.halt().expect("Buddy thread panicked");
+
+        mem::take(&mut self.buddies)
+            .into_iter()
+            .map(|buddy| {
+                Arc::into_inner(buddy)
+                    .expect("Buddy threads have all been joined")
+                    .into_inner()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Join every thread, even if some of them panicked, and say whether any
+    /// did.
+    fn halt(&mut self) -> thread::Result<()> {
+        self.running.store(false, Ordering::Relaxed);
+
+        self.threads
+            .drain(..)
+            .map(JoinHandle::join)
+            .fold(Ok(()), Result::and)
+    }
+}
+
+impl Drop for ThreadedCluster {
+    /// Panicking here could be a panic during a panic, which aborts, so a
+    /// buddy thread that died only gets a mention.
+    fn drop(&mut self) {
+        if self.halt().is_err() {
+            eprintln!("A buddy thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_topology::Topology;
+
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn spawn_cluster(size: usize) -> ThreadedCluster {
+        let buddies: Vec<RaftBuddy> = Topology::in_memory(size).into();
+
+        ThreadedCluster::spawn(buddies, Duration::from_millis(1))
+    }
+
+    #[test]
+    fn buddies_can_be_sent_between_threads() {
+        fn assert_send<T: Send>() {}
+
+        assert_send::<RaftBuddy>();
+        assert_send::<Topology>();
+    }
+
+    #[test]
+    fn it_elects_a_leader() {
+        let cluster = spawn_cluster(5);
+
+        assert!(cluster.wait_until(TIMEOUT, |buddies| {
+            buddies.iter().any(|buddy| buddy.role == Role::Leader)
+        }));
+        assert!(cluster.leader().is_some());
+    }
+
+    #[test]
+    fn it_replicates_proposals_to_every_buddy() {
+        let cluster = spawn_cluster(3);
+
+        assert!(cluster.wait_until(TIMEOUT, |buddies| {
+            buddies.iter().any(|buddy| buddy.role == Role::Leader)
+        }));
+
+        let leader = cluster.leader().unwrap();
+        cluster.buddy(leader).propose("set x 42");
+
+        assert!(cluster.wait_until(TIMEOUT, |buddies| {
+            buddies
+                .iter()
+                .all(|buddy| buddy.state_machine.get("x") == Some(&"42".to_owned()))
+        }));
+
+        let buddies = cluster.stop();
+
+        assert_eq!(buddies.len(), 3);
+    }
+
+    #[test]
+    fn dropping_it_survives_a_buddy_thread_that_panicked() {
+        let mut cluster = spawn_cluster(3);
+
+        cluster
+            .threads
+            .push(thread::spawn(|| panic!("Buddy thread gave up")));
+        drop(cluster);
+    }
+}
@@ -1,15 +1,48 @@
+use crate::raft_auth::ClusterKeys;
+use crate::raft_channel::RaftChannel;
+use crate::raft_codec::{BinaryCodec, Codec};
 use crate::raft_id::RaftId;
 use crate::raft_log::RaftLog;
-use crate::raft_type_aliases::RcMutChannel;
-use std::cell::RefCell;
+use crate::raft_tcp::{TcpInbox, TcpPeer};
+use crate::raft_type_aliases::ArcMutChannel;
+#[cfg(unix)]
+use crate::raft_unix::{UnixInbox, UnixPeer};
 use std::cmp::Ordering;
-use std::{collections::BTreeMap, rc::Rc};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::SocketAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Where a buddy's inbox can be reached. Written as `host:port` for TCP or,
+/// on Unix, `unix:<path>` for a Unix domain socket.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum PeerAddress {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for PeerAddress {
+    type Err = std::net::AddrParseError;
+
+    fn from_str(address: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = address.strip_prefix("unix:") {
+            return Ok(PeerAddress::Unix(path.into()));
+        }
+
+        address.parse().map(PeerAddress::Tcp)
+    }
+}
 
 #[derive(Clone)]
-pub struct Topology(BTreeMap<RaftId, RcMutChannel>);
+pub struct Topology(BTreeMap<RaftId, ArcMutChannel>);
 
 impl std::ops::Deref for Topology {
-    type Target = BTreeMap<RaftId, RcMutChannel>;
+    type Target = BTreeMap<RaftId, ArcMutChannel>;
     fn deref(&self) -> &Self::Target {
         &self.0
     }
@@ -21,14 +54,88 @@ impl std::ops::DerefMut for Topology {
     }
 }
 
-impl FromIterator<(RaftId, RcMutChannel)> for Topology {
-    fn from_iter<I: IntoIterator<Item = (RaftId, RcMutChannel)>>(iter: I) -> Self {
+impl Topology {
+    /// Buddies `0..size`, each with an in-memory `RaftChannel` for an inbox.
+    pub fn in_memory(size: usize) -> Self {
+        (0..size)
+            .map(|id| {
+                (
+                    RaftId(id),
+                    Arc::new(Mutex::new(RaftChannel::default())) as ArcMutChannel,
+                )
+            })
+            .collect()
+    }
+}
+
+impl Topology {
+    /// Listen on our own address and connect to everyone else's, over
+    /// whichever transport each address asks for.
+    pub fn connect(id: RaftId, addresses: &BTreeMap<RaftId, PeerAddress>) -> io::Result<Self> {
+        Self::connect_with_codec(id, addresses, &BinaryCodec)
+    }
+
+    /// Same as `connect`, but encoding what we send with `codec`. Inboxes
+    /// read whatever codec each message says it was sent with.
+    pub fn connect_with_codec(
+        id: RaftId,
+        addresses: &BTreeMap<RaftId, PeerAddress>,
+        codec: &'static dyn Codec,
+    ) -> io::Result<Self> {
+        Self::connect_with_keys(id, addresses, codec, None)
+    }
+
+    /// Same as `connect_with_codec`, but with `keys`, everything we send is
+    /// signed and anything that isn't signed with one of them is refused.
+    pub fn connect_with_keys(
+        id: RaftId,
+        addresses: &BTreeMap<RaftId, PeerAddress>,
+        codec: &'static dyn Codec,
+        keys: Option<Arc<ClusterKeys>>,
+    ) -> io::Result<Self> {
+        addresses
+            .iter()
+            .map(|(&peer_id, address)| {
+                let channel = match (peer_id == id, address) {
+                    (true, PeerAddress::Tcp(addr)) => {
+                        Arc::new(Mutex::new(TcpInbox::bind_with_keys(addr, keys.clone())?))
+                            as ArcMutChannel
+                    }
+                    #[cfg(unix)]
+                    (true, PeerAddress::Unix(path)) => {
+                        Arc::new(Mutex::new(UnixInbox::bind_with_keys(path, keys.clone())?))
+                    }
+                    (false, PeerAddress::Tcp(addr)) => {
+                        let peer = TcpPeer::new(*addr).with_codec(codec);
+                        Arc::new(Mutex::new(match &keys {
+                            Some(keys) => peer.with_keys(keys.clone()),
+                            None => peer,
+                        }))
+                    }
+                    #[cfg(unix)]
+                    (false, PeerAddress::Unix(path)) => {
+                        let peer = UnixPeer::new(path).with_codec(codec);
+                        Arc::new(Mutex::new(match &keys {
+                            Some(keys) => peer.with_keys(keys.clone()),
+                            None => peer,
+                        }))
+                    }
+                };
+
+                Ok((peer_id, channel))
+            })
+            .collect()
+    }
+}
+
+impl FromIterator<(RaftId, ArcMutChannel)> for Topology {
+    fn from_iter<I: IntoIterator<Item = (RaftId, ArcMutChannel)>>(iter: I) -> Self {
         Topology(BTreeMap::from_iter(iter))
     }
 }
 
-impl From<BTreeMap<RaftId, RcMutChannel>> for Topology {
-    fn from(value: BTreeMap<RaftId, RcMutChannel>) -> Self {
+impl From<BTreeMap<RaftId, ArcMutChannel>> for Topology {
+    fn from(value: BTreeMap<RaftId, ArcMutChannel>) -> Self {
         Topology::from_iter(value)
     }
 }
@@ -0,0 +1,549 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::raft_buddy::{RaftBuddy, Role};
+use crate::raft_checksum::crc32;
+use crate::raft_encoding::{
+    decode_envelope, encode_envelope, encode_log, invalid_data, put_bool, put_bytes, put_u64,
+    put_usize, Reader,
+};
+use crate::raft_id::RaftId;
+use crate::raft_log::SnapshotBase;
+use crate::raft_message::Envelope;
+use crate::raft_state_machine::StateMachine;
+use crate::raft_storage::MemoryStorage;
+use crate::raft_temporal::RaftTimer;
+use crate::raft_topology::Topology;
+
+/// Bumped whenever the layout of a trace changes, so old fixtures are
+/// refused rather than misread.
+pub const TRACE_VERSION: u8 = 2;
+
+/// Something that happened to one buddy in a recorded run.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Event {
+    /// The buddy ticked, handling `delivered` in that order.
+    Tick {
+        id: RaftId,
+        delivered: Vec<Envelope>,
+        /// Its election timer, or its heartbeat if it was leader, ran out.
+        timed_out: bool,
+    },
+    Propose {
+        id: RaftId,
+        contents: String,
+    },
+    Crash(RaftId),
+    /// The buddy came back from its storage with a fresh election timeout.
+    Restart {
+        id: RaftId,
+        election_timeout: usize,
+    },
+}
+
+impl Event {
+    pub fn id(&self) -> RaftId {
+        match self {
+            Self::Tick { id, .. } | Self::Propose { id, .. } | Self::Restart { id, .. } => *id,
+            Self::Crash(id) => *id,
+        }
+    }
+}
+
+/// What's compared after each step of a replay. The log and state machine
+/// are only compared by checksum, which is plenty to say whether they match
+/// without keeping a copy of each for every step.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Checkpoint {
+    pub role: Role,
+    pub current_term: usize,
+    pub voted_for: Option<RaftId>,
+    pub commit_index: usize,
+    pub last_applied: usize,
+    pub base: SnapshotBase,
+    pub last_index: usize,
+    pub last_term: usize,
+    pub ticks_left: usize,
+    pub heartbeat_ticks_left: usize,
+    /// CRC-32 of the log as it would be persisted.
+    pub log_checksum: u32,
+    /// CRC-32 of a snapshot of the state machine.
+    pub state_checksum: u32,
+}
+
+impl Checkpoint {
+    pub fn of(buddy: &RaftBuddy) -> Self {
+        Self {
+            role: buddy.role.clone(),
+            current_term: buddy.current_term,
+            voted_for: buddy.voted_for,
+            commit_index: buddy.commit_index,
+            last_applied: buddy.last_applied,
+            base: buddy.log.base(),
+            last_index: buddy.log.last_index(),
+            last_term: buddy.log.last_term(),
+            ticks_left: buddy.timer.ticks_left,
+            heartbeat_ticks_left: buddy.heartbeat.ticks_left,
+            log_checksum: crc32(&encode_log(&buddy.log)),
+            state_checksum: crc32(&buddy.state_machine.snapshot()),
+        }
+    }
+}
+
+/// An event, and the buddy it happened to as it was afterwards.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Step {
+    pub event: Event,
+    pub checkpoint: Checkpoint,
+}
+
+/// Where a replay first went a different way from the recording.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Divergence {
+    /// The buddy's timer ran out in one and not the other.
+    Timer {
+        step: usize,
+        id: RaftId,
+        recorded: bool,
+    },
+    State {
+        step: usize,
+        id: RaftId,
+        recorded: Box<Checkpoint>,
+        replayed: Box<Checkpoint>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timer { step, id, recorded } => {
+                let ran_out = |yes: bool| if yes { "ran out" } else { "didn't run out" };
+
+                write!(
+                    f,
+                    "step {step}: buddy {}'s timer {} when recorded but {} on replay",
+                    **id,
+                    ran_out(*recorded),
+                    ran_out(!recorded)
+                )
+            }
+            Self::State {
+                step,
+                id,
+                recorded,
+                replayed,
+            } => write!(
+                f,
+                "step {step}: buddy {} was {recorded:?} when recorded but {replayed:?} on replay",
+                **id
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Divergence {}
+
+/// Everything that happened to a simulated cluster, one buddy at a time,
+/// in enough detail to play it out again exactly against fresh buddies.
+/// Messages are replayed as they were delivered rather than sent again, so
+/// whatever the network did to them comes along for free.
+///
+/// Traces are written with the same encoding as everything else: the
+/// version, each buddy's election timeout, then every step until the end.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Trace {
+    /// Buddies `0..election_timeouts.len()`, each with its timeout at the
+    /// start.
+    pub election_timeouts: Vec<usize>,
+    pub steps: Vec<Step>,
+}
+
+impl Trace {
+    pub fn new(election_timeouts: Vec<usize>) -> Self {
+        Self {
+            election_timeouts,
+            steps: vec![],
+        }
+    }
+
+    pub fn record(&mut self, event: Event, buddy: &RaftBuddy) {
+        self.steps.push(Step {
+            event,
+            checkpoint: Checkpoint::of(buddy),
+        });
+    }
+
+    /// Play every step out again against fresh buddies, checking each one
+    /// ends up where it did when recorded. Returns the buddies as they are
+    /// at the end.
+    pub fn replay(&self) -> Result<Vec<RaftBuddy>, Divergence> {
+        let topology = Topology::in_memory(self.election_timeouts.len());
+        let mut buddies: Vec<RaftBuddy> = topology.clone().into();
+        let storage: Vec<MemoryStorage> = buddies.iter().map(|_| Default::default()).collect();
+
+        for ((buddy, &timeout), memory) in buddies
+            .iter_mut()
+            .zip(&self.election_timeouts)
+            .zip(&storage)
+        {
+            buddy.storage = Box::new(memory.clone());
+            buddy.timer = RaftTimer::from(timeout);
+        }
+
+        for (step, Step { event, checkpoint }) in self.steps.iter().enumerate() {
+            let id = event.id();
+            let buddy = &mut buddies[*id];
+
+            match event {
+                Event::Tick {
+                    delivered,
+                    timed_out,
+                    ..
+                } => {
+                    let mut inbox = buddy.channel().lock().unwrap();
+                    delivered
+                        .iter()
+                        .for_each(|envelope| inbox.push(envelope.clone()));
+                    drop(inbox);
+
+                    if buddy.tick_timed_out() != *timed_out {
+                        return Err(Divergence::Timer {
+                            step,
+                            id,
+                            recorded: *timed_out,
+                        });
+                    }
+                }
+                Event::Propose { contents, .. } => {
+                    buddy.propose(contents.clone());
+                }
+                Event::Crash(_) => {}
+                Event::Restart {
+                    election_timeout, ..
+                } => {
+                    let memory = Box::new(storage[*id].clone());
+                    *buddy = RaftBuddy::restore(id, topology.clone(), memory)
+                        .expect("Memory storage can't fail to load");
+                    buddy.timer = RaftTimer::from(*election_timeout);
+                }
+            }
+
+            // What was sent only arrives if the trace says it did.
+            for channel in topology.values() {
+                channel.lock().unwrap().clear();
+            }
+
+            let replayed = Checkpoint::of(&buddies[*id]);
+
+            if replayed != *checkpoint {
+                return Err(Divergence::State {
+                    step,
+                    id,
+                    recorded: Box::new(checkpoint.clone()),
+                    replayed: Box::new(replayed),
+                });
+            }
+        }
+
+        Ok(buddies)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![TRACE_VERSION];
+
+        put_usize(&mut bytes, self.election_timeouts.len());
+        for &timeout in &self.election_timeouts {
+            put_usize(&mut bytes, timeout);
+        }
+
+        for step in &self.steps {
+            put_event(&mut bytes, &step.event);
+            put_checkpoint(&mut bytes, &step.checkpoint);
+        }
+
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader(bytes);
+
+        if reader.u8()? != TRACE_VERSION {
+            return Err(invalid_data("trace was written by another version"));
+        }
+
+        let election_timeouts = (0..reader.usize()?)
+            .map(|_| reader.usize())
+            .collect::<io::Result<_>>()?;
+
+        let mut steps = vec![];
+
+        while !reader.0.is_empty() {
+            steps.push(Step {
+                event: read_event(&mut reader)?,
+                checkpoint: read_checkpoint(&mut reader)?,
+            });
+        }
+
+        Ok(Self {
+            election_timeouts,
+            steps,
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.encode())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::decode(&fs::read(path)?)
+    }
+}
+
+fn put_event(bytes: &mut Vec<u8>, event: &Event) {
+    match event {
+        Event::Tick {
+            id,
+            delivered,
+            timed_out,
+        } => {
+            bytes.push(0);
+            put_usize(bytes, **id);
+            put_bool(bytes, *timed_out);
+            put_usize(bytes, delivered.len());
+
+            for envelope in delivered {
+                put_bytes(bytes, &encode_envelope(envelope));
+            }
+        }
+        Event::Propose { id, contents } => {
+            bytes.push(1);
+            put_usize(bytes, **id);
+            put_bytes(bytes, contents.as_bytes());
+        }
+        Event::Crash(id) => {
+            bytes.push(2);
+            put_usize(bytes, **id);
+        }
+        Event::Restart {
+            id,
+            election_timeout,
+        } => {
+            bytes.push(3);
+            put_usize(bytes, **id);
+            put_usize(bytes, *election_timeout);
+        }
+    }
+}
+
+fn read_event(reader: &mut Reader) -> io::Result<Event> {
+    let tag = reader.u8()?;
+    let id = RaftId(reader.usize()?);
+
+    Ok(match tag {
+        0 => {
+            let timed_out = reader.bool()?;
+            let delivered = (0..reader.usize()?)
+                .map(|_| decode_envelope(reader.bytes()?))
+                .collect::<io::Result<_>>()?;
+
+            Event::Tick {
+                id,
+                delivered,
+                timed_out,
+            }
+        }
+        1 => Event::Propose {
+            id,
+            contents: reader.string()?,
+        },
+        2 => Event::Crash(id),
+        3 => Event::Restart {
+            id,
+            election_timeout: reader.usize()?,
+        },
+        _ => return Err(invalid_data("unknown event")),
+    })
+}
+
+fn put_checkpoint(bytes: &mut Vec<u8>, checkpoint: &Checkpoint) {
+    bytes.push(match checkpoint.role {
+        Role::Follower => 0,
+        Role::Candidate => 1,
+        Role::Leader => 2,
+    });
+    put_usize(bytes, checkpoint.current_term);
+    put_bool(bytes, checkpoint.voted_for.is_some());
+    put_usize(bytes, checkpoint.voted_for.map_or(0, |id| *id));
+    put_usize(bytes, checkpoint.commit_index);
+    put_usize(bytes, checkpoint.last_applied);
+    put_usize(bytes, checkpoint.base.last_included_index);
+    put_usize(bytes, checkpoint.base.last_included_term);
+    put_usize(bytes, checkpoint.last_index);
+    put_usize(bytes, checkpoint.last_term);
+    put_usize(bytes, checkpoint.ticks_left);
+    put_usize(bytes, checkpoint.heartbeat_ticks_left);
+    put_u64(bytes, checkpoint.log_checksum.into());
+    put_u64(bytes, checkpoint.state_checksum.into());
+}
+
+fn read_checkpoint(reader: &mut Reader) -> io::Result<Checkpoint> {
+    let role = match reader.u8()? {
+        0 => Role::Follower,
+        1 => Role::Candidate,
+        2 => Role::Leader,
+        _ => return Err(invalid_data("unknown role")),
+    };
+    let current_term = reader.usize()?;
+    let voted = reader.bool()?;
+    let voted_for = RaftId(reader.usize()?);
+
+    Ok(Checkpoint {
+        role,
+        current_term,
+        voted_for: voted.then_some(voted_for),
+        commit_index: reader.usize()?,
+        last_applied: reader.usize()?,
+        base: SnapshotBase {
+            last_included_index: reader.usize()?,
+            last_included_term: reader.usize()?,
+        },
+        last_index: reader.usize()?,
+        last_term: reader.usize()?,
+        ticks_left: reader.usize()?,
+        heartbeat_ticks_left: reader.usize()?,
+        log_checksum: read_checksum(reader)?,
+        state_checksum: read_checksum(reader)?,
+    })
+}
+
+fn read_checksum(reader: &mut Reader) -> io::Result<u32> {
+    u32::try_from(reader.u64()?).map_err(|_| invalid_data("checksum is out of range"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_cluster::Cluster;
+    use crate::raft_network::Faults;
+    use crate::raft_scratch::ScratchDir;
+
+    const PINNED: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/fixtures/traces/crash_and_restart.trace"
+    );
+
+    /// Elections, commits, a crash and a restart, over a network that loses,
+    /// repeats, reorders and holds up messages.
+    fn recorded_run(seed: u64) -> Cluster {
+        let mut cluster = Cluster::new(3, seed).recording();
+        cluster.network.set_faults(Faults {
+            drop: 0.1,
+            duplicate: 0.1,
+            reorder: 0.1,
+            delay: 0.1,
+            max_delay: 5,
+        });
+
+        assert!(cluster.propose_and_commit("set a 1").is_some());
+
+        let follower = cluster
+            .buddies
+            .iter()
+            .map(|buddy| buddy.id)
+            .find(|&id| Some(id) != cluster.leader())
+            .unwrap();
+        cluster.crash(follower);
+        assert!(cluster.propose_and_commit("set b 2").is_some());
+        cluster.restart(follower);
+        cluster.run(50);
+
+        cluster
+    }
+
+    #[test]
+    fn a_recorded_run_replays_exactly() {
+        let cluster = recorded_run(3);
+        let trace = cluster.trace().unwrap();
+
+        let replayed = trace.replay().unwrap();
+
+        assert!(trace.steps.len() > 100);
+        for (buddy, replayed) in cluster.buddies.iter().zip(&replayed) {
+            assert_eq!(Checkpoint::of(replayed), Checkpoint::of(buddy));
+            assert_eq!(replayed.state_machine, buddy.state_machine);
+        }
+    }
+
+    #[test]
+    fn traces_survive_being_written_out() {
+        let trace = recorded_run(4).trace().unwrap().clone();
+        let dir = ScratchDir::new("round-trip");
+        let path = dir.join("round-trip.trace");
+
+        fs::create_dir_all(&dir).unwrap();
+        trace.save(&path).unwrap();
+
+        assert_eq!(Trace::load(&path).unwrap(), trace);
+
+        let mut bytes = trace.encode();
+        bytes[0] = TRACE_VERSION + 1;
+        assert!(Trace::decode(&bytes).is_err());
+        assert!(Trace::decode(&trace.encode()[..100]).is_err());
+    }
+
+    #[test]
+    fn a_replay_that_goes_another_way_says_where() {
+        let mut trace = recorded_run(3).trace().unwrap().clone();
+        trace.election_timeouts[1] += 1;
+
+        let first = trace
+            .steps
+            .iter()
+            .position(|step| step.event.id() == RaftId(1))
+            .unwrap();
+
+        match trace.replay() {
+            Err(Divergence::State { step, id, .. }) => assert_eq!((step, id), (first, RaftId(1))),
+            other => panic!("Expected the first step for buddy 1 to diverge, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn a_replay_notices_the_state_machine_going_another_way() {
+        let mut trace = recorded_run(3).trace().unwrap().clone();
+        let first = trace
+            .steps
+            .iter()
+            .position(|step| step.checkpoint.last_applied > 0)
+            .unwrap();
+        trace.steps[first].checkpoint.state_checksum ^= 1;
+
+        match trace.replay() {
+            Err(Divergence::State { step, .. }) => assert_eq!(step, first),
+            other => panic!("Expected step {first} to diverge, got {other:?}"),
+        }
+    }
+
+    /// Writes the pinned trace out again from `recorded_run(3)`. Run it with
+    /// `cargo test regenerate_pinned_trace -- --ignored` after changing
+    /// `TRACE_VERSION`, or anything else that changes how a run plays out.
+    #[test]
+    #[ignore]
+    fn regenerate_pinned_trace() {
+        recorded_run(3).trace().unwrap().save(PINNED).unwrap();
+    }
+
+    #[test]
+    fn pinned_traces_still_replay() {
+        let trace = Trace::decode(include_bytes!("../fixtures/traces/crash_and_restart.trace"));
+
+        let buddies = trace.unwrap().replay().unwrap();
+
+        assert!(buddies
+            .iter()
+            .all(|buddy| buddy.state_machine.get("b") == Some(&"2".to_owned())));
+    }
+}
@@ -1,7 +1,9 @@
-use crate::raft_channel::Channel;
+use crate::raft_channel::{Channel, RaftChannel};
 use crate::raft_log::RaftLog;
-use std::cell::RefCell;
+use crate::raft_message::Envelope;
 use std::cmp::Ordering;
-use std::{collections::BTreeMap, rc::Rc};
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Arc, Mutex};
 
-pub type RcMutChannel = Rc<RefCell<dyn Channel>>;
+pub type ArcMutChannel = Arc<Mutex<dyn Channel>>;
+pub type SharedQueue = Arc<Mutex<RaftChannel>>;
@@ -0,0 +1,215 @@
+use std::fs;
+use std::io;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::raft_auth::ClusterKeys;
+use crate::raft_channel::{Channel, Overflow, OverflowPolicy, RaftChannel};
+use crate::raft_connection::{Connect, Connection, DEFAULT_CAPACITY, WRITE_TIMEOUT};
+use crate::raft_frame::spawn_reader;
+use crate::raft_message::Envelope;
+use crate::raft_type_aliases::SharedQueue;
+
+/// Same as `TcpInbox`, but listening on a Unix domain socket, for clusters
+/// that all live on one host and would rather not hand out ports.
+#[derive(Debug)]
+pub struct UnixInbox {
+    path: PathBuf,
+    queue: SharedQueue,
+}
+
+impl UnixInbox {
+    /// A socket file left behind by an earlier run would stop us binding, so
+    /// it's cleared out first.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::bind_with_keys(path, None)
+    }
+
+    pub fn bind_with_keys(
+        path: impl AsRef<Path>,
+        keys: Option<Arc<ClusterKeys>>,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+
+        match fs::remove_file(&path) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+
+        let listener = UnixListener::bind(&path)?;
+        let queue = Arc::new(Mutex::new(RaftChannel::bounded(
+            DEFAULT_CAPACITY,
+            OverflowPolicy::default(),
+        )));
+
+        let accepted = queue.clone();
+        let from = path.display().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        spawn_reader(stream, from.clone(), accepted.clone(), keys.clone())
+                    }
+                    Err(error) => eprintln!("Could not accept connection on {from}: {error}"),
+                }
+            }
+        });
+
+        Ok(Self { path, queue })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// How many messages can wait to be popped, and what happens to any
+    /// more. A blocked message holds up the connection it came in on until
+    /// there's room for it.
+    pub fn with_capacity(self, capacity: usize, overflow: OverflowPolicy) -> Self {
+        {
+            let mut queue = self.queue.lock().unwrap();
+            queue.capacity = Some(capacity);
+            queue.overflow = overflow;
+        }
+
+        self
+    }
+}
+
+impl Channel for UnixInbox {
+    /// Anything we send ourselves skips the socket.
+    fn push(&mut self, envelope: Envelope) {
+        self.queue.lock().unwrap().push(envelope);
+    }
+
+    fn try_push(&mut self, envelope: Envelope) -> Result<(), Overflow> {
+        self.queue.lock().unwrap().try_push(envelope)
+    }
+
+    fn is_full(&mut self) -> bool {
+        self.queue.lock().unwrap().is_full()
+    }
+
+    fn pop(&mut self) -> Option<Envelope> {
+        self.queue.lock().unwrap().pop()
+    }
+
+    fn all_messages(&mut self) -> Vec<Envelope> {
+        self.queue.lock().unwrap().all_messages()
+    }
+}
+
+/// Same as `TcpPeer`, but connecting to a `UnixInbox`.
+pub type UnixPeer = Connection<PathBuf>;
+
+impl Connect for PathBuf {
+    type Stream = UnixStream;
+
+    fn connect(&self) -> io::Result<UnixStream> {
+        let stream = UnixStream::connect(self)?;
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raft_buddy::{RaftBuddy, Role};
+    use crate::raft_id::{ClusterId, RaftId};
+    use crate::raft_message::RaftMessage;
+    use crate::raft_tcp::tests::wait_for_message;
+    use crate::raft_temporal::RaftTimer;
+    use crate::raft_threaded::ThreadedCluster;
+    use crate::raft_topology::{PeerAddress, Topology};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("raft-{}-{name}.sock", std::process::id()))
+    }
+
+    fn vote(id: usize) -> Envelope {
+        Envelope {
+            from: RaftId(id),
+            to: RaftId(0),
+            term: 1,
+            cluster_id: ClusterId::default(),
+            message: RaftMessage::VoteForCandidate,
+        }
+    }
+
+    #[test]
+    fn it_delivers_messages_in_order() {
+        let mut inbox = UnixInbox::bind(socket_path("in-order")).unwrap();
+        let mut peer = UnixPeer::new(inbox.path());
+
+        peer.push(vote(1));
+        peer.push(vote(2));
+
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(1)));
+        assert_eq!(wait_for_message(&mut inbox), Some(vote(2)));
+    }
+
+    #[test]
+    fn it_binds_over_a_stale_socket_file() {
+        let path = socket_path("stale");
+        drop(UnixListener::bind(&path));
+
+        assert!(UnixInbox::bind(&path).is_ok());
+    }
+
+    #[test]
+    fn it_parses_addresses() {
+        assert_eq!(
+            "unix:/tmp/raft.sock".parse::<PeerAddress>().unwrap(),
+            PeerAddress::Unix("/tmp/raft.sock".into())
+        );
+        assert_eq!(
+            "127.0.0.1:7000".parse::<PeerAddress>().unwrap(),
+            PeerAddress::Tcp("127.0.0.1:7000".parse().unwrap())
+        );
+        assert!("nonsense".parse::<PeerAddress>().is_err());
+    }
+
+    #[test]
+    fn it_forms_a_cluster_mixing_unix_sockets_and_tcp() {
+        let tcp_port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let addresses = BTreeMap::from([
+            (RaftId(0), PeerAddress::Unix(socket_path("mixed-0"))),
+            (RaftId(1), PeerAddress::Unix(socket_path("mixed-1"))),
+            (RaftId(2), PeerAddress::Tcp(tcp_port)),
+        ]);
+
+        let buddies = addresses
+            .keys()
+            .map(|&id| RaftBuddy {
+                id,
+                topology: Topology::connect(id, &addresses).unwrap(),
+                timer: RaftTimer::from(100 + *id * 10),
+                ..Default::default()
+            })
+            .collect();
+
+        let cluster = ThreadedCluster::spawn(buddies, Duration::from_millis(1));
+
+        assert!(cluster.wait_until(Duration::from_secs(10), |buddies| {
+            buddies.iter().any(|buddy| buddy.role == Role::Leader)
+        }));
+
+        let leader = cluster.leader().unwrap();
+        cluster.buddy(leader).propose("set x 42");
+
+        assert!(cluster.wait_until(Duration::from_secs(10), |buddies| {
+            buddies
+                .iter()
+                .all(|buddy| buddy.state_machine.get("x") == Some(&"42".to_owned()))
+        }));
+    }
+}